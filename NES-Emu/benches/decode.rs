@@ -0,0 +1,79 @@
+// オペコードのデコードを以前の実装と比べる
+// cargo bench --bench decode
+//
+// baseline: CPU_OPS_CODESを線形探索してclone、名前から"*"を取り除いた文字列で命令を区別する
+// table: CPU_OPS_TABLEを添字で引いて、Instructionで区別する
+use std::hint::black_box;
+use std::time::Instant;
+
+use nes_emu::cpu::{Instruction, OpCode};
+use nes_emu::opscodes::{CPU_OPS_CODES, CPU_OPS_TABLE};
+
+const ROUNDS: usize = 1_000_000;
+
+// benches/emulation.rsのループと同じ命令の並び
+//   LDX #, LDA abs,X, ADC #, STA abs,X, INX, BNE, INC zp, JMP abs
+const PROGRAM: [u8; 8] = [0xA2, 0xBD, 0x69, 0x9D, 0xE8, 0xD0, 0xE6, 0x4C];
+
+// 以前のopscodes::callのmatchの腕の順番
+const NAMES: [&str; 76] = [
+  "ADC", "AND", "ASL", "BCC", "BCS", "BEQ", "BIT", "BMI", "BNE", "BPL", "BRK", "BVC", "BVS", "CLC",
+  "CLD", "CLI", "CLV", "CMP", "CPX", "CPY", "DEC", "DEX", "DEY", "EOR", "INC", "INX", "INY", "JMP",
+  "JSR", "LDA", "LDX", "LDY", "LSR", "NOP", "ORA", "PHA", "PHP", "PLA", "PLP", "ROL", "ROR", "RTI",
+  "RTS", "SBC", "SEC", "SED", "SEI", "STA", "STX", "STY", "TAX", "TAY", "TSX", "TXA", "TXS", "TYA",
+  "ANC", "SAX", "ARR", "ASR", "LXA", "SHA", "SBX", "DCP", "ISB", "JAM", "LAE", "LAX", "RLA", "RRA",
+  "SLO", "SRE", "SHX", "SHY", "ANE", "SHS",
+];
+
+fn baseline(code: u8) -> usize {
+  let op: OpCode = CPU_OPS_CODES
+    .iter()
+    .find(|op| op.code == code)
+    .cloned()
+    .unwrap();
+  let name = op.name.replace("*", "");
+  NAMES.iter().position(|n| *n == name).unwrap() + op.bytes as usize
+}
+
+fn table(code: u8) -> usize {
+  let op = &CPU_OPS_TABLE[code as usize];
+  let index = match op.instruction {
+    Instruction::LDX => 30,
+    Instruction::LDA => 29,
+    Instruction::ADC => 0,
+    Instruction::STA => 47,
+    Instruction::INX => 25,
+    Instruction::BNE => 8,
+    Instruction::INC => 24,
+    Instruction::JMP => 27,
+    _ => 0,
+  };
+  index + op.bytes as usize
+}
+
+fn measure(name: &str, decode: fn(u8) -> usize) {
+  let start = Instant::now();
+  let mut sum = 0;
+  for _ in 0..ROUNDS {
+    for code in PROGRAM {
+      sum += decode(black_box(code));
+    }
+  }
+  black_box(sum);
+  let elapsed = start.elapsed().as_secs_f64();
+  println!(
+    "{}: {:.1} ns/instruction",
+    name,
+    elapsed * 1e9 / (ROUNDS * PROGRAM.len()) as f64
+  );
+}
+
+fn main() {
+  // lazy_staticの初期化を測らないように
+  assert_eq!(baseline(0xA2), table(0xA2));
+  for code in PROGRAM {
+    assert_eq!(baseline(code), table(code), "{:02X}", code);
+  }
+  measure("baseline (linear search + name match)", baseline);
+  measure("table (256-entry table + enum match)", table);
+}
@@ -0,0 +1,63 @@
+// エミュレーションの速度を測る
+// cargo bench --bench emulation
+use std::time::Instant;
+
+use nes_emu::nes::Nes;
+use nes_emu::rom::{self, Rom};
+
+const FRAMES: usize = 600;
+const INSTRUCTIONS: usize = 5_000_000;
+// NTSCのフレームレート
+const REAL_TIME_FPS: f64 = 60.0988;
+
+// RAMを読み書きしながらループするだけのNROM
+//   $8000: LDX #$00
+//   $8002: LDA $0200,X
+//          ADC #$01
+//          STA $0200,X
+//          INX
+//          BNE $8002
+//          INC $10
+//          JMP $8000
+fn rom() -> Rom {
+  let program = [
+    0xA2, 0x00, 0xBD, 0x00, 0x02, 0x69, 0x01, 0x9D, 0x00, 0x02, 0xE8, 0xD0, 0xF5, 0xE6, 0x10, 0x4C,
+    0x00, 0x80,
+  ];
+  rom::nrom(&program, 0x8000, 0)
+}
+
+fn main() {
+  let mut nes = Nes::new(rom());
+  for _ in 0..60 {
+    nes.step_frame();
+  }
+
+  let start = Instant::now();
+  for _ in 0..INSTRUCTIONS {
+    nes.step_instruction();
+  }
+  let elapsed = start.elapsed().as_secs_f64();
+  nes.take_audio_samples();
+  println!(
+    "step_instruction: {} instructions in {:.3}s ({:.1} ns/instruction)",
+    INSTRUCTIONS,
+    elapsed,
+    elapsed * 1e9 / INSTRUCTIONS as f64
+  );
+
+  let start = Instant::now();
+  for _ in 0..FRAMES {
+    nes.step_frame();
+    nes.take_audio_samples();
+  }
+  let elapsed = start.elapsed().as_secs_f64();
+  let fps = FRAMES as f64 / elapsed;
+  println!(
+    "step_frame: {} frames in {:.3}s ({:.1} fps, {:.2}x real time)",
+    FRAMES,
+    elapsed,
+    fps,
+    fps / REAL_TIME_FPS
+  );
+}
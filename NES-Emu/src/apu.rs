@@ -1,53 +1,188 @@
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
 pub struct NesAPU {
   ch1_register: Ch1Register,
   ch2_register: Ch2Register,
   ch3_register: Ch3Register,
   ch4_register: Ch4Register,
 
-  ch1_device: AudioDevice<SquareWave>,
   ch1_sender: Sender<SquareNote>,
-
-  ch2_device: AudioDevice<SquareWave>,
   ch2_sender: Sender<SquareNote>,
-
-  ch3_device: AudioDevice<TriangleWave>,
   ch3_sender: Sender<TriangleNote>,
-
-  ch4_device: AudioDevice<NoiseWave>,
   ch4_sender: Sender<NoiseNote>,
+
+  // 最後に書き込まれた$4000-$4017の値 (ステートの復元用)
+  registers: [u8; 0x18],
+
+  // falseの場合はレジスタだけ更新して音は鳴らさない
+  enabled: bool,
+  // CPUのクロック(音の高さ)とフレームシーケンサの周期
+  region: Region,
+
+  // フレームシーケンサ ($4017)
+  frame_cycles: usize,
+  five_step_mode: bool,
+  irq_inhibit: bool,
+  frame_irq: bool,
 }
 
-const NES_CPU_CLOCK: f32 = 1_789_773.0; //1.78Hz
+// フレームシーケンサの1周のCPUサイクル数
+// 4ステップモードでは最後のステップでIRQを出す
+// Dendyのフレームシーケンサや周波数のテーブルはNTSCと同じ
+const FOUR_STEP_CYCLES: usize = 29830;
+const FIVE_STEP_CYCLES: usize = 37282;
+const FRAME_IRQ_CYCLE: usize = 29829;
+const PAL_FOUR_STEP_CYCLES: usize = 33254;
+const PAL_FIVE_STEP_CYCLES: usize = 41566;
+const PAL_FRAME_IRQ_CYCLE: usize = 33253;
 impl NesAPU {
-  pub fn new(sdl_context: &sdl2::Sdl) -> Self {
-    let (ch1_device, ch1_sender) = init_square(sdl_context);
-    let (ch2_device, ch2_sender) = init_square(sdl_context);
-    let (ch3_device, ch3_sender) = init_triangle(sdl_context);
-    let (ch4_device, ch4_sender) = init_noise(sdl_context);
-
-    NesAPU {
+  // 波形の生成はApuOutputが行う
+  // ApuOutputはオーディオスレッドに渡してもいいし、手元で回してもいい
+  pub fn new(sample_rate: f32) -> (Self, ApuOutput) {
+    let (ch1_sender, ch1_receiver) = channel::<SquareNote>();
+    let (ch2_sender, ch2_receiver) = channel::<SquareNote>();
+    let (ch3_sender, ch3_receiver) = channel::<TriangleNote>();
+    let (ch4_sender, ch4_receiver) = channel::<NoiseNote>();
+
+    let apu = NesAPU {
       ch1_register: Ch1Register::new(),
       ch3_register: Ch3Register::new(),
       ch2_register: Ch2Register::new(),
       ch4_register: Ch4Register::new(),
 
-      ch1_device: ch1_device,
       ch1_sender: ch1_sender,
-      ch2_device: ch2_device,
       ch2_sender: ch2_sender,
-      ch3_device: ch3_device,
       ch3_sender: ch3_sender,
-      ch4_device: ch4_device,
       ch4_sender: ch4_sender,
+
+      registers: [0; 0x18],
+      enabled: true,
+      region: Region::NTSC,
+
+      frame_cycles: 0,
+      five_step_mode: false,
+      irq_inhibit: false,
+      frame_irq: false,
+    };
+    let output = ApuOutput {
+      ch1: SquareWave::new(sample_rate, ch1_receiver),
+      ch2: SquareWave::new(sample_rate, ch2_receiver),
+      ch3: TriangleWave::new(sample_rate, ch3_receiver),
+      ch4: NoiseWave::new(sample_rate, ch4_receiver),
+    };
+    (apu, output)
+  }
+
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_bytes(&self.registers);
+    writer.write_usize(self.frame_cycles);
+    writer.write_bool(self.frame_irq);
+  }
+
+  // 書き込みをやり直せば各チャンネルのノートも送り直される
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    let mut registers = [0; 0x18];
+    reader.read_into(&mut registers)?;
+    for addr in 0x4000..=0x4007 {
+      let value = registers[(addr - 0x4000) as usize];
+      if addr < 0x4004 {
+        self.write_1ch(addr, value);
+      } else {
+        self.write_2ch(addr, value);
+      }
+    }
+    for addr in [0x4008, 0x400A, 0x400B] {
+      self.write_3ch(addr, registers[(addr - 0x4000) as usize]);
     }
+    for addr in [0x400C, 0x400E, 0x400F] {
+      self.write_4ch(addr, registers[(addr - 0x4000) as usize]);
+    }
+    self.registers = registers;
+    self.write_frame_counter(registers[0x17]);
+    self.frame_cycles = reader.read_usize()?;
+    self.frame_irq = reader.read_bool()?;
+    Ok(())
+  }
+
+  // CPUのサイクルに合わせて進める
+  pub fn tick(&mut self, cycles: u8) {
+    let (four_step_cycles, five_step_cycles, frame_irq_cycle) = match self.region {
+      Region::PAL => (
+        PAL_FOUR_STEP_CYCLES,
+        PAL_FIVE_STEP_CYCLES,
+        PAL_FRAME_IRQ_CYCLE,
+      ),
+      Region::NTSC | Region::DENDY => (FOUR_STEP_CYCLES, FIVE_STEP_CYCLES, FRAME_IRQ_CYCLE),
+    };
+    for _ in 0..cycles {
+      self.frame_cycles += 1;
+      if !self.five_step_mode && !self.irq_inhibit && self.frame_cycles >= frame_irq_cycle {
+        self.frame_irq = true;
+      }
+      let sequence_cycles = if self.five_step_mode {
+        five_step_cycles
+      } else {
+        four_step_cycles
+      };
+      if self.frame_cycles >= sequence_cycles {
+        self.frame_cycles = 0;
+      }
+    }
+  }
+
+  // $4017
+  // bit7: 5ステップモード, bit6: IRQ禁止
+  pub fn write_frame_counter(&mut self, value: u8) {
+    self.registers[0x17] = value;
+    self.five_step_mode = value & 0x80 != 0;
+    self.irq_inhibit = value & 0x40 != 0;
+    if self.irq_inhibit {
+      self.frame_irq = false;
+    }
+    self.frame_cycles = 0;
+  }
+
+  // $4015の読み込み
+  // bit6: フレームIRQ (読むと下りる)
+  // 長さカウンタとDMCは未実装なので0
+  pub fn read_status(&mut self) -> u8 {
+    let status = self.peek_status();
+    self.frame_irq = false;
+    status
+  }
+
+  // フラグを下ろさずに$4015の値を返す
+  pub fn peek_status(&self) -> u8 {
+    if self.frame_irq {
+      0x40
+    } else {
+      0
+    }
+  }
+
+  pub fn frame_irq(&self) -> bool {
+    self.frame_irq
+  }
+
+  pub fn set_region(&mut self, region: Region) {
+    self.region = region;
+  }
+
+  // 止めている間に送ったノートが溜まらないように送信もしない
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
   }
 
   pub fn write_1ch(&mut self, addr: u16, volume: u8) {
+    self.registers[(addr - 0x4000) as usize] = volume;
     self.ch1_register.write(addr, volume);
+    if !self.enabled {
+      return;
+    }
 
     let duty = match self.ch1_register.duty() {
       0b00 => 0.125,
@@ -58,20 +193,22 @@ impl NesAPU {
     };
 
     let volume = (self.ch1_register.volume() as f32) / 15.0;
-    let hz = NES_CPU_CLOCK / (16.0 * (self.ch1_register.hz() as f32 + 1.0));
-
-    self
-      .ch1_sender
-      .send(SquareNote {
-        hz: hz,
-        volume: volume,
-        duty: duty,
-      })
-      .unwrap();
+    let hz = self.region.cpu_clock() / (16.0 * (self.ch1_register.hz() as f32 + 1.0));
+
+    // ApuOutputが捨てられていても気にしない
+    let _ = self.ch1_sender.send(SquareNote {
+      hz: hz,
+      volume: volume,
+      duty: duty,
+    });
   }
 
   pub fn write_2ch(&mut self, addr: u16, value: u8) {
+    self.registers[(addr - 0x4000) as usize] = value;
     self.ch2_register.write(addr, value);
+    if !self.enabled {
+      return;
+    }
 
     let duty = match self.ch2_register.duty {
       0x00 => 0.125,
@@ -83,42 +220,48 @@ impl NesAPU {
 
     let volume = (self.ch2_register.volume as f32) / 15.0;
 
-    let hz = NES_CPU_CLOCK / (16.0 * (self.ch2_register.frequency as f32 + 1.0));
+    let hz = self.region.cpu_clock() / (16.0 * (self.ch2_register.frequency as f32 + 1.0));
 
-    self
-      .ch2_sender
-      .send(SquareNote {
-        hz: hz,
-        volume: volume,
-        duty: duty,
-      })
-      .unwrap();
+    let _ = self.ch2_sender.send(SquareNote {
+      hz: hz,
+      volume: volume,
+      duty: duty,
+    });
   }
 
   pub fn write_3ch(&mut self, addr: u16, value: u8) {
+    self.registers[(addr - 0x4000) as usize] = value;
     self.ch3_register.write(addr, value);
+    if !self.enabled {
+      return;
+    }
 
-    let hz = NES_CPU_CLOCK / (16.0 * (self.ch3_register.frequency as f32 + 1.0));
-    self.ch3_sender.send(TriangleNote { hz: hz }).unwrap();
+    let hz = self.region.cpu_clock() / (16.0 * (self.ch3_register.frequency as f32 + 1.0));
+    let _ = self.ch3_sender.send(TriangleNote { hz: hz });
   }
 
   pub fn write_4ch(&mut self, addr: u16, value: u8) {
+    self.registers[(addr - 0x4000) as usize] = value;
     self.ch4_register.write(addr, value);
+    if !self.enabled {
+      return;
+    }
 
-    let hz = NES_CPU_CLOCK / NOIZE_TABLE[self.ch4_register.frequency as usize] as f32;
+    let table = match self.region {
+      Region::PAL => &*NOIZE_TABLE_PAL,
+      Region::NTSC | Region::DENDY => &*NOIZE_TABLE,
+    };
+    let hz = self.region.cpu_clock() / table[self.ch4_register.frequency as usize] as f32;
     let is_long = match self.ch4_register.kind {
       NoiseKind::Long => true,
       _ => false,
     };
     let volume = (self.ch4_register.volume as f32) / 15.0;
-    self
-      .ch4_sender
-      .send(NoiseNote {
-        hz: hz,
-        is_long: is_long,
-        volume: volume,
-      })
-      .unwrap();
+    let _ = self.ch4_sender.send(NoiseNote {
+      hz: hz,
+      is_long: is_long,
+      volume: volume,
+    });
   }
 }
 
@@ -323,6 +466,23 @@ impl Ch4Register {
   }
 }
 
+// NesAPUから送られたノートを元に波形を生成する
+pub struct ApuOutput {
+  ch1: SquareWave,
+  ch2: SquareWave,
+  ch3: TriangleWave,
+  ch4: NoiseWave,
+}
+
+impl ApuOutput {
+  // 4チャンネルを足し合わせたモノラルのサンプルで埋める
+  pub fn fill(&mut self, out: &mut [f32]) {
+    for x in out.iter_mut() {
+      *x = self.ch1.sample() + self.ch2.sample() + self.ch3.sample() + self.ch4.sample();
+    }
+  }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct SquareNote {
   hz: f32,
@@ -337,40 +497,10 @@ struct SquareWave {
   note: SquareNote,
 }
 
-impl AudioCallback for SquareWave {
-  type Channel = f32;
-
-  fn callback(&mut self, out: &mut [f32]) {
-    for x in out.iter_mut() {
-      let res = self.receiver.recv_timeout(Duration::from_millis(0));
-      match res {
-        Ok(note) => self.note = note,
-        Err(_) => {}
-      }
-      *x = if self.phase <= self.note.duty {
-        self.note.volume
-      } else {
-        -self.note.volume
-      };
-      self.phase = (self.phase + self.note.hz / self.freq) % 1.0;
-    }
-  }
-}
-
-fn init_square(sdl_context: &sdl2::Sdl) -> (AudioDevice<SquareWave>, Sender<SquareNote>) {
-  let audio_subsystem = sdl_context.audio().unwrap();
-
-  let (sender, receiver) = channel::<SquareNote>();
-
-  let desired_spec = AudioSpecDesired {
-    freq: Some(44100),
-    channels: Some(1),
-    samples: None,
-  };
-
-  let device = audio_subsystem
-    .open_playback(None, &desired_spec, |spec| SquareWave {
-      freq: spec.freq as f32,
+impl SquareWave {
+  fn new(freq: f32, receiver: Receiver<SquareNote>) -> Self {
+    SquareWave {
+      freq: freq,
       phase: 0.0,
       receiver: receiver,
       note: SquareNote {
@@ -378,12 +508,23 @@ fn init_square(sdl_context: &sdl2::Sdl) -> (AudioDevice<SquareWave>, Sender<Squa
         volume: 0.0,
         duty: 0.0,
       },
-    })
-    .unwrap();
-
-  device.resume();
+    }
+  }
 
-  (device, sender)
+  fn sample(&mut self) -> f32 {
+    let res = self.receiver.recv_timeout(Duration::from_millis(0));
+    match res {
+      Ok(note) => self.note = note,
+      Err(_) => {}
+    }
+    let x = if self.phase <= self.note.duty {
+      self.note.volume
+    } else {
+      -self.note.volume
+    };
+    self.phase = (self.phase + self.note.hz / self.freq) % 1.0;
+    x
+  }
 }
 
 lazy_static! {
@@ -391,6 +532,10 @@ lazy_static! {
     0x0002, 0x0004, 0x0008, 0x0010, 0x0020, 0x0030, 0x0040, 0x0050, 0x0065, 0x007F, 0x00BE, 0x00FE,
     0x017D, 0x01FC, 0x03F9, 0x07F2,
   ];
+  pub static ref NOIZE_TABLE_PAL: Vec<u16> = vec![
+    0x0002, 0x0004, 0x0007, 0x000F, 0x001E, 0x002C, 0x003B, 0x004A, 0x005E, 0x0076, 0x00B1, 0x00EC,
+    0x0162, 0x01D8, 0x03B1, 0x0761,
+  ];
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -411,29 +556,42 @@ struct NoiseWave {
   note: NoiseNote,
 }
 
-impl AudioCallback for NoiseWave {
-  type Channel = f32;
+impl NoiseWave {
+  fn new(freq: f32, receiver: Receiver<NoiseNote>) -> Self {
+    NoiseWave {
+      freq: freq,
+      phase: 0.0,
+      receiver: receiver,
+      value: false,
+      long_random: NoiseRandom::long(),
+      short_random: NoiseRandom::short(),
+      note: NoiseNote {
+        hz: 0.0,
+        is_long: true,
+        volume: 0.0,
+      },
+    }
+  }
 
-  fn callback(&mut self, out: &mut [Self::Channel]) {
-    for x in out.iter_mut() {
-      let res = self.receiver.recv_timeout(Duration::from_millis(0));
-      match res {
-        Ok(note) => self.note = note,
-        Err(_) => {}
-      }
+  fn sample(&mut self) -> f32 {
+    let res = self.receiver.recv_timeout(Duration::from_millis(0));
+    match res {
+      Ok(note) => self.note = note,
+      Err(_) => {}
+    }
 
-      *x = if self.value { 0.0 } else { 1.0 } * self.note.volume;
+    let x = if self.value { 0.0 } else { 1.0 } * self.note.volume;
 
-      let last_phase = self.phase;
-      self.phase = (self.phase + self.note.hz / self.freq) % 1.0;
-      if last_phase > self.phase {
-        self.value = if self.note.is_long {
-          self.long_random.next()
-        } else {
-          self.short_random.next()
-        }
+    let last_phase = self.phase;
+    self.phase = (self.phase + self.note.hz / self.freq) % 1.0;
+    if last_phase > self.phase {
+      self.value = if self.note.is_long {
+        self.long_random.next()
+      } else {
+        self.short_random.next()
       }
     }
+    x
   }
 }
 
@@ -458,37 +616,6 @@ impl NoiseRandom {
   }
 }
 
-fn init_noise(sdl_context: &sdl2::Sdl) -> (AudioDevice<NoiseWave>, Sender<NoiseNote>) {
-  let audio_subsystem = sdl_context.audio().unwrap();
-
-  let (sender, receiver) = channel::<NoiseNote>();
-
-  let desired_spec = AudioSpecDesired {
-    freq: Some(44100),
-    channels: Some(1),
-    samples: None,
-  };
-
-  let device = audio_subsystem
-    .open_playback(None, &desired_spec, |spec| NoiseWave {
-      freq: spec.freq as f32,
-      phase: 0.0,
-      receiver: receiver,
-      value: false,
-      long_random: NoiseRandom::long(),
-      short_random: NoiseRandom::short(),
-      note: NoiseNote {
-        hz: 0.0,
-        is_long: true,
-        volume: 0.0,
-      },
-    })
-    .unwrap();
-
-  device.resume();
-  (device, sender)
-}
-
 #[derive(Debug, Clone, PartialEq)]
 struct TriangleNote {
   hz: f32,
@@ -500,46 +627,31 @@ struct TriangleWave {
   receiver: Receiver<TriangleNote>,
   note: TriangleNote,
 }
-impl AudioCallback for TriangleWave {
-  type Channel = f32;
-
-  fn callback(&mut self, out: &mut [Self::Channel]) {
-    for x in out.iter_mut() {
-      let res = self.receiver.recv_timeout(Duration::from_millis(0));
-      match res {
-        Ok(note) => self.note = note,
-        Err(_) => {}
-      }
-      *x = (if self.phase <= 0.5 {
-        self.phase
-      } else {
-        1.0 - self.phase
-      } - 0.25)
-        * 2.0;
-
-      self.phase = (self.phase + self.note.hz / self.freq) % 1.0;
-    }
-  }
-}
 
-fn init_triangle(sdl_context: &sdl2::Sdl) -> (AudioDevice<TriangleWave>, Sender<TriangleNote>) {
-  let audio_subsystem = sdl_context.audio().unwrap();
-
-  let (sender, receiver) = channel::<TriangleNote>();
-  let desired_spec = AudioSpecDesired {
-    freq: Some(44100),
-    channels: Some(1),
-    samples: None,
-  };
-  let device = audio_subsystem
-    .open_playback(None, &desired_spec, |spec| TriangleWave {
-      freq: spec.freq as f32,
+impl TriangleWave {
+  fn new(freq: f32, receiver: Receiver<TriangleNote>) -> Self {
+    TriangleWave {
+      freq: freq,
       phase: 0.0,
       receiver: receiver,
       note: TriangleNote { hz: 0.0 },
-    })
-    .unwrap();
+    }
+  }
 
-  device.resume();
-  (device, sender)
+  fn sample(&mut self) -> f32 {
+    let res = self.receiver.recv_timeout(Duration::from_millis(0));
+    match res {
+      Ok(note) => self.note = note,
+      Err(_) => {}
+    }
+    let x = (if self.phase <= 0.5 {
+      self.phase
+    } else {
+      1.0 - self.phase
+    } - 0.25)
+      * 2.0;
+
+    self.phase = (self.phase + self.note.hz / self.freq) % 1.0;
+    x
+  }
 }
@@ -1,15 +1,33 @@
+use bitflags::bitflags;
 use log::{error, trace, warn};
 
-use crate::{apu::NesAPU, joypad::Joypad, ppu::NesPPU, rom::Rom};
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::{apu::NesAPU, joypad::Joypad, mapper::Cartridge, ppu::NesPPU, rom::Rom};
+
+bitflags! {
+  // IRQを出している要因
+  // どれか1つでも立っていればIRQ線がアクティブになる
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub struct IrqSource: u8 {
+    // DMCチャンネルは未実装なのでDMCのIRQはまだない
+    const APU_FRAME = 0b0000_0001;
+    const MAPPER    = 0b0000_0100;
+  }
+}
 
 pub struct Bus<'call> {
   cpu_vram: [u8; 0x800],
-  prg_rom: Vec<u8>,
+  cartridge: Cartridge,
   ppu: NesPPU,
   apu: NesAPU,
   cycles: usize,
+  // vblankに入った回数
+  frame_count: usize,
   joypad1: Joypad,
   joypad2: Joypad,
+  irq_sources: IrqSource,
+  region: Region,
   gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
 }
 
@@ -18,48 +36,92 @@ impl<'a> Bus<'a> {
   where
     F: FnMut(&NesPPU, &mut Joypad) + 'call,
   {
-    let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+    let cartridge: Cartridge = rom.cartridge;
+    let ppu = NesPPU::new(cartridge.clone());
     Bus {
       cpu_vram: [0; 0x800],
-      prg_rom: rom.prg_rom,
+      cartridge: cartridge,
       ppu: ppu,
       apu: apu,
       joypad1: Joypad::new(),
       joypad2: Joypad::new(),
       cycles: 0,
+      frame_count: 0,
+      irq_sources: IrqSource::empty(),
+      region: Region::NTSC,
       gameloop_callback: Box::from(gameloop_callback),
     }
   }
 
-  fn read_prg_rom(&self, mut addr: u16) -> u8 {
-    addr -= 0x8000;
-    if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-      // mirror if needed
-      addr = addr % 0x4000;
-    }
-    self.prg_rom[addr as usize]
+  pub fn cycles(&self) -> usize {
+    self.cycles
   }
-  pub fn tick(&mut self, cycle: u8) {
-    self.cycles += cycle as usize;
 
-    let nmi_before = self.ppu.nmi_interrupt.is_some();
-    self.ppu.tick(cycle * 3);
-    let nmi_after = self.ppu.nmi_interrupt.is_some();
+  pub fn frame_count(&self) -> usize {
+    self.frame_count
+  }
 
-    if !nmi_before && nmi_after {
-      (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
-    }
+  pub fn ppu(&self) -> &NesPPU {
+    &self.ppu
   }
 
-  pub fn poll_nmi_status(&mut self) -> Option<i32> {
-    if self.ppu.clear_nmi_interrupt {
-      self.ppu.clear_nmi_interrupt = false;
-      self.ppu.nmi_interrupt = None;
-      return None;
-    }
-    let res = self.ppu.nmi_interrupt;
-    self.ppu.nmi_interrupt = None;
-    res
+  pub fn ppu_mut(&mut self) -> &mut NesPPU {
+    &mut self.ppu
+  }
+
+  pub fn region(&self) -> Region {
+    self.region
+  }
+
+  pub fn set_region(&mut self, region: Region) {
+    self.region = region;
+    self.ppu.set_region(region);
+    self.apu.set_region(region);
+  }
+
+  pub fn joypad1(&mut self) -> &mut Joypad {
+    &mut self.joypad1
+  }
+
+  pub fn joypad2(&mut self) -> &mut Joypad {
+    &mut self.joypad2
+  }
+
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_bytes(&self.cpu_vram);
+    writer.write_usize(self.cycles);
+    writer.write_usize(self.frame_count);
+    self.ppu.save_state(writer);
+    self.apu.save_state(writer);
+    self.joypad1.save_state(writer);
+    self.joypad2.save_state(writer);
+    self.cartridge.borrow().save_state(writer);
+  }
+
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    reader.read_into(&mut self.cpu_vram)?;
+    self.cycles = reader.read_usize()?;
+    self.frame_count = reader.read_usize()?;
+    self.ppu.load_state(reader)?;
+    self.apu.load_state(reader)?;
+    self.joypad1.load_state(reader)?;
+    self.joypad2.load_state(reader)?;
+    self.cartridge.borrow_mut().load_state(reader)?;
+    self.update_irq_sources();
+    Ok(())
+  }
+
+  fn update_irq_sources(&mut self) {
+    self
+      .irq_sources
+      .set(IrqSource::APU_FRAME, self.apu.frame_irq());
+    self
+      .irq_sources
+      .set(IrqSource::MAPPER, self.ppu.irq_pending());
+  }
+
+  pub fn irq_sources(&self) -> IrqSource {
+    self.irq_sources
   }
 }
 
@@ -68,12 +130,26 @@ const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
-const PRG_ROM: u16 = 0x8000;
-const PRG_ROM_END: u16 = 0xFFFF;
+// $4020-$FFFF はカートリッジ(マッパー)側
+const CARTRIDGE_SPACE: u16 = 0x4020;
+const CARTRIDGE_SPACE_END: u16 = 0xFFFF;
 
 pub trait Mem {
   fn mem_read(&mut self, addr: u16) -> u8;
   fn mem_write(&mut self, addr: u16, data: u8);
+  // 読み込みと同じ値を副作用なしで返す(トレースやデバッガ用)
+  fn mem_peek(&self, addr: u16) -> u8;
+
+  // CPUのメモリアクセス1回ごとに1サイクル進める
+  // メモリしか持たない場合は何もしない
+  fn tick(&mut self, _cycle: u8) {}
+  // NMIのエッジが来ていれば取り出す
+  fn poll_nmi_status(&mut self) -> Option<i32> {
+    None
+  }
+  fn poll_irq_status(&self) -> bool {
+    false
+  }
 }
 
 impl Mem for Bus<'_> {
@@ -103,9 +179,10 @@ impl Mem for Bus<'_> {
         let mirror_down_addr = addr & 0b0010_0000_0000_0111;
         self.mem_read(mirror_down_addr)
       }
+      0x4015 => self.apu.read_status(),
       0x4016 => self.joypad1.read(),
       0x4017 => self.joypad2.read(),
-      PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
+      CARTRIDGE_SPACE..=CARTRIDGE_SPACE_END => self.cartridge.borrow().read_prg(addr),
       _ => {
         warn!("Ignoreing mem access at {}", addr);
         0
@@ -113,6 +190,22 @@ impl Mem for Bus<'_> {
     }
   }
 
+  fn mem_peek(&self, addr: u16) -> u8 {
+    match addr {
+      RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize],
+      0x2002 => self.ppu.peek_status(),
+      0x2004 => self.ppu.read_oam_data(),
+      0x2007 => self.ppu.peek_data(),
+      0x2008..=PPU_REGISTERS_MIRRORS_END => self.mem_peek(addr & 0b0010_0000_0000_0111),
+      0x4015 => self.apu.peek_status(),
+      0x4016 => self.joypad1.peek(),
+      0x4017 => self.joypad2.peek(),
+      CARTRIDGE_SPACE..=CARTRIDGE_SPACE_END => self.cartridge.borrow().peek_prg(addr),
+      // 書き込み専用のレジスタなど
+      _ => 0,
+    }
+  }
+
   fn mem_write(&mut self, addr: u16, data: u8) {
     match addr {
       RAM..=RAM_MIRRORS_END => {
@@ -154,29 +247,78 @@ impl Mem for Bus<'_> {
         // $XX を書き込むと、256 バイトのデータが CPU ページ $XX00 ～ $XXFF から内部 PPU OAM にアップロードされます。
         // このページは通常、内部 RAM (通常は $0200 ～ $02FF) にありますが、カートリッジ RAM または ROM も使用できます。
 
+        // 転送中はCPUが止まる。書き込みの次のサイクルで止まり、
+        // 奇数サイクルだった場合はもう1サイクル待ってから1バイトごとに読み書きする
+        // 合計513か514サイクル
+        self.tick(1);
+        if self.cycles % 2 == 1 {
+          self.tick(1);
+        }
         let mut values: [u8; 256] = [0; 256];
-        for i in 0x00..0xFF {
+        for i in 0..values.len() {
           values[i] = self.mem_read((data as u16) << 8 | i as u16);
+          self.tick(2);
         }
         self.ppu.write_to_oam_dma(values);
-
-        // OMA DMAにはCPUクロックが513サイクルかかる
-        for _ in 0..513 {
-          self.ppu.tick(1);
-        }
       }
       0x4016 => {
+        // ストローブは両方のコントローラーに繋がっている
         self.joypad1.write(data);
+        self.joypad2.write(data);
       }
       0x4017 => {
-        self.joypad2.write(data);
+        self.apu.write_frame_counter(data);
       }
-      PRG_ROM..=PRG_ROM_END => {
-        warn!("Attempt to write to Cartridge ROM space");
+      CARTRIDGE_SPACE..=CARTRIDGE_SPACE_END => {
+        let mut cartridge = self.cartridge.borrow_mut();
+        cartridge.on_cpu_write(self.cycles);
+        cartridge.write_prg(addr, data);
       }
       _ => {
         error!("Ignoring mem write-access at {:X}", addr);
       }
     }
   }
+
+  fn tick(&mut self, cycle: u8) {
+    let cycles_before = self.cycles;
+    self.cycles += cycle as usize;
+
+    // PALはCPU1サイクルで3.2ドットなので、通算のサイクル数から端数を持ち越す
+    let (dots, per_cycles) = self.region.ppu_dots_per_cpu_cycle();
+    let ppu_dots = self.cycles * dots / per_cycles - cycles_before * dots / per_cycles;
+
+    let nmi_before = self.ppu.nmi_interrupt.is_some();
+    let scanline_before = self.ppu.scanline();
+    self.ppu.tick(ppu_dots as u8);
+    self.apu.tick(cycle);
+    let nmi_after = self.ppu.nmi_interrupt.is_some();
+    self.update_irq_sources();
+
+    // NMIが無効でもvblankに入ったらフレームの区切りとする
+    let vblank_scanline = self.region.vblank_scanline();
+    if scanline_before < vblank_scanline && self.ppu.scanline() >= vblank_scanline {
+      self.frame_count += 1;
+    }
+
+    if !nmi_before && nmi_after {
+      (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+    }
+  }
+
+  fn poll_nmi_status(&mut self) -> Option<i32> {
+    if self.ppu.clear_nmi_interrupt {
+      self.ppu.clear_nmi_interrupt = false;
+      self.ppu.nmi_interrupt = None;
+      return None;
+    }
+    let res = self.ppu.nmi_interrupt;
+    self.ppu.nmi_interrupt = None;
+    res
+  }
+
+  // IRQはレベルトリガなので要因が取り下げられるまで立ち続ける
+  fn poll_irq_status(&self) -> bool {
+    !self.irq_sources.is_empty()
+  }
 }
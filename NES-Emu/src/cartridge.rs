@@ -1,29 +1,110 @@
-use std::{fs::File, io::Read};
+use std::io;
+use std::path::{Path, PathBuf};
 
-use crate::rom::Rom;
+use crate::mapper::Cartridge;
+use crate::rom::{Rom, RomError};
 
-pub fn load_rom(path: &str) -> Rom {
-  let mut f = File::open(path).expect("no file found");
-  let metadata = std::fs::metadata(path).expect("unable to read metadata");
-  let mut buffer = vec![0; metadata.len() as usize];
-  f.read(&mut buffer).expect("buffer overflow");
-  let rom = Rom::new(&buffer).expect("load errpr");
-  rom
-}
+// 60fpsで約5秒ごとに.savへ書き出す
+const SAVE_INTERVAL_FRAMES: u32 = 300;
 
-#[allow(dead_code)]
-pub fn snake_rom() -> Rom {
-  load_rom("rom/snake.nes")
+pub fn load_rom(path: &str) -> Result<Rom, RomError> {
+  let buffer = std::fs::read(path)?;
+  Rom::new(&buffer)
 }
+
 #[allow(dead_code)]
-pub fn test_rom() -> Rom {
+pub fn test_rom() -> Result<Rom, RomError> {
   load_rom("rom/nestest.nes")
 }
-#[allow(dead_code)]
-pub fn alter_ego_rom() -> Rom {
-  load_rom("rom/Alter_Ego.nes")
+
+// バッテリーバックアップされたPRG_RAMを.savファイルと同期する
+// 起動時に読み込み、一定フレームごとと終了時に書き出す
+pub struct SaveFile {
+  path: PathBuf,
+  cartridge: Cartridge,
+  frames: u32,
 }
-#[allow(dead_code)]
-pub fn bomb_sweeper_rom() -> Rom {
-  load_rom("rom/BombSweeper.nes")
+
+impl SaveFile {
+  // ROMと同じ場所に拡張子.savで保存する
+  pub fn new(rom_path: &str, cartridge: Cartridge) -> Self {
+    SaveFile {
+      path: Path::new(rom_path).with_extension("sav"),
+      cartridge: cartridge,
+      frames: 0,
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  // .savが無ければ何もしない
+  pub fn load(&mut self) -> io::Result<()> {
+    match std::fs::read(&self.path) {
+      Ok(data) => {
+        self.cartridge.borrow_mut().prg_ram_mut().load(&data);
+        Ok(())
+      }
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+      Err(err) => Err(err),
+    }
+  }
+
+  pub fn on_frame(&mut self) -> io::Result<()> {
+    self.frames += 1;
+    if self.frames < SAVE_INTERVAL_FRAMES {
+      return Ok(());
+    }
+    self.frames = 0;
+    self.flush()
+  }
+
+  // 書き込み途中で落ちても前のセーブが壊れないように一時ファイルから置き換える
+  pub fn flush(&mut self) -> io::Result<()> {
+    let mut cartridge = self.cartridge.borrow_mut();
+    if !cartridge.prg_ram().is_dirty() {
+      return Ok(());
+    }
+    let tmp = self.path.with_extension("sav.tmp");
+    std::fs::write(&tmp, cartridge.prg_ram().data())?;
+    std::fs::rename(&tmp, &self.path)?;
+    cartridge.prg_ram_mut().mark_saved();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::rom;
+
+  fn battery_rom() -> Rom {
+    rom::nrom(&[], 0x8000, 0b0000_0010)
+  }
+
+  #[test]
+  fn test_save_file_round_trip() {
+    let dir = std::env::temp_dir().join(format!("nes_emu_save_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rom_path = dir.join("game.nes");
+    let rom_path = rom_path.to_str().unwrap();
+
+    let rom = battery_rom();
+    assert!(rom.battery);
+    let mut save = SaveFile::new(rom_path, rom.cartridge.clone());
+    save.load().unwrap();
+    rom.cartridge.borrow_mut().write_prg(0x6000, 0x12);
+    rom.cartridge.borrow_mut().write_prg(0x7FFF, 0x34);
+    save.flush().unwrap();
+    assert!(save.path().ends_with("game.sav"));
+
+    let rom = battery_rom();
+    let mut save = SaveFile::new(rom_path, rom.cartridge.clone());
+    save.load().unwrap();
+    assert_eq!(rom.cartridge.borrow().read_prg(0x6000), 0x12);
+    assert_eq!(rom.cartridge.borrow().read_prg(0x7FFF), 0x34);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
 }
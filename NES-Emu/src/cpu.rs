@@ -1,9 +1,8 @@
-use log::debug;
+use log::{debug, warn};
 
 use crate::bus::{Bus, Mem};
-use crate::opscodes::{call, CPU_OPS_CODES};
-
-pub static mut IN_TRACE: bool = false;
+use crate::opscodes::{call, CPU_OPS_TABLE};
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 #[derive(Debug, PartialEq, Clone)]
 #[allow(non_camel_case_types)]
@@ -32,10 +31,178 @@ pub enum CycleCalcMode {
   Branch,
 }
 
+// 命令の種類
+// 非公式命令も名前の"*"を除いたもので区別する
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum Instruction {
+  ADC,
+  AND,
+  ASL,
+  BCC,
+  BCS,
+  BEQ,
+  BIT,
+  BMI,
+  BNE,
+  BPL,
+  BRK,
+  BVC,
+  BVS,
+  CLC,
+  CLD,
+  CLI,
+  CLV,
+  CMP,
+  CPX,
+  CPY,
+  DEC,
+  DEX,
+  DEY,
+  EOR,
+  INC,
+  INX,
+  INY,
+  JMP,
+  JSR,
+  LDA,
+  LDX,
+  LDY,
+  LSR,
+  NOP,
+  ORA,
+  PHA,
+  PHP,
+  PLA,
+  PLP,
+  ROL,
+  ROR,
+  RTI,
+  RTS,
+  SBC,
+  SEC,
+  SED,
+  SEI,
+  STA,
+  STX,
+  STY,
+  TAX,
+  TAY,
+  TSX,
+  TXA,
+  TXS,
+  TYA,
+  ANC,
+  SAX,
+  ARR,
+  ASR,
+  LXA,
+  SHA,
+  SBX,
+  DCP,
+  ISB,
+  JAM,
+  LAE,
+  LAX,
+  RLA,
+  RRA,
+  SLO,
+  SRE,
+  SHX,
+  SHY,
+  ANE,
+  SHS,
+}
+
+impl Instruction {
+  fn from_name(name: &str) -> Self {
+    match name.trim_start_matches('*') {
+      "ADC" => Instruction::ADC,
+      "AND" => Instruction::AND,
+      "ASL" => Instruction::ASL,
+      "BCC" => Instruction::BCC,
+      "BCS" => Instruction::BCS,
+      "BEQ" => Instruction::BEQ,
+      "BIT" => Instruction::BIT,
+      "BMI" => Instruction::BMI,
+      "BNE" => Instruction::BNE,
+      "BPL" => Instruction::BPL,
+      "BRK" => Instruction::BRK,
+      "BVC" => Instruction::BVC,
+      "BVS" => Instruction::BVS,
+      "CLC" => Instruction::CLC,
+      "CLD" => Instruction::CLD,
+      "CLI" => Instruction::CLI,
+      "CLV" => Instruction::CLV,
+      "CMP" => Instruction::CMP,
+      "CPX" => Instruction::CPX,
+      "CPY" => Instruction::CPY,
+      "DEC" => Instruction::DEC,
+      "DEX" => Instruction::DEX,
+      "DEY" => Instruction::DEY,
+      "EOR" => Instruction::EOR,
+      "INC" => Instruction::INC,
+      "INX" => Instruction::INX,
+      "INY" => Instruction::INY,
+      "JMP" => Instruction::JMP,
+      "JSR" => Instruction::JSR,
+      "LDA" => Instruction::LDA,
+      "LDX" => Instruction::LDX,
+      "LDY" => Instruction::LDY,
+      "LSR" => Instruction::LSR,
+      "NOP" => Instruction::NOP,
+      "ORA" => Instruction::ORA,
+      "PHA" => Instruction::PHA,
+      "PHP" => Instruction::PHP,
+      "PLA" => Instruction::PLA,
+      "PLP" => Instruction::PLP,
+      "ROL" => Instruction::ROL,
+      "ROR" => Instruction::ROR,
+      "RTI" => Instruction::RTI,
+      "RTS" => Instruction::RTS,
+      "SBC" => Instruction::SBC,
+      "SEC" => Instruction::SEC,
+      "SED" => Instruction::SED,
+      "SEI" => Instruction::SEI,
+      "STA" => Instruction::STA,
+      "STX" => Instruction::STX,
+      "STY" => Instruction::STY,
+      "TAX" => Instruction::TAX,
+      "TAY" => Instruction::TAY,
+      "TSX" => Instruction::TSX,
+      "TXA" => Instruction::TXA,
+      "TXS" => Instruction::TXS,
+      "TYA" => Instruction::TYA,
+      "ANC" => Instruction::ANC,
+      "SAX" => Instruction::SAX,
+      "ARR" => Instruction::ARR,
+      "ASR" => Instruction::ASR,
+      "LXA" => Instruction::LXA,
+      "SHA" => Instruction::SHA,
+      "SBX" => Instruction::SBX,
+      "DCP" => Instruction::DCP,
+      "ISB" => Instruction::ISB,
+      "JAM" => Instruction::JAM,
+      "LAE" => Instruction::LAE,
+      "LAX" => Instruction::LAX,
+      "RLA" => Instruction::RLA,
+      "RRA" => Instruction::RRA,
+      "SLO" => Instruction::SLO,
+      "SRE" => Instruction::SRE,
+      "SHX" => Instruction::SHX,
+      "SHY" => Instruction::SHY,
+      "ANE" => Instruction::ANE,
+      "SHS" => Instruction::SHS,
+      _ => panic!("unknown instruction {}", name),
+    }
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct OpCode {
   pub code: u8,
-  pub name: String,
+  pub name: &'static str,
+  pub instruction: Instruction,
   pub bytes: u16,
   pub cycles: u8,
   pub cycle_calc_mode: CycleCalcMode,
@@ -44,7 +211,7 @@ pub struct OpCode {
 impl OpCode {
   pub fn new(
     code: u8,
-    name: &str,
+    name: &'static str,
     bytes: u16,
     cycles: u8,
     cycle_calc_mode: CycleCalcMode,
@@ -52,7 +219,8 @@ impl OpCode {
   ) -> Self {
     OpCode {
       code: code,
-      name: String::from(name),
+      name: name,
+      instruction: Instruction::from_name(name),
       bytes: bytes,
       cycles: cycles,
       cycle_calc_mode: cycle_calc_mode,
@@ -70,40 +238,57 @@ const FLAG_BREAK2: u8 = 1 << 5;
 const FLAG_OVERFLOW: u8 = 1 << 6;
 const FLAG_NEGATICE: u8 = 1 << 7;
 
-pub struct CPU<'a> {
+// ANE,LXAで使われる不定な定数 (チップや温度で変わる。よく見られる値にしておく)
+const ANE_MAGIC: u8 = 0xEE;
+const LXA_MAGIC: u8 = 0xEE;
+
+// Mだけ差し替えればROMやPPUなしでも動かせる(テスト用)
+pub struct CPU<M = Bus<'static>> {
   pub register_a: u8,
   pub register_x: u8,
   pub register_y: u8,
   pub status: u8,
   pub stack_pointer: u8,
   pub program_counter: u16,
-  pub bus: Bus<'a>,
+  pub bus: M,
 
-  add_cycle: u8,
+  // JAMを実行したらリセットされるまで止まる
+  jammed: bool,
+
+  // NMIのエッジを検出してからまだ処理していない
+  nmi_pending: bool,
+  // 各サイクルの始め(=前のサイクルの終わり)に割り込みを確認した結果
+  // 命令の最後のサイクルの分は使われないので、最後から2番目のサイクルまでの状態で割り込むかが決まる
+  nmi_poll: bool,
+  irq_poll: bool,
 }
 
-pub fn trace(cpu: &mut CPU) -> String {
-  let program_counter = cpu.program_counter - 1;
+// nestest.logと同じ形式
+// 命令を実行する直前に呼ぶこと
+pub fn trace(cpu: &CPU) -> String {
+  let program_counter = cpu.program_counter;
   let pc = format!("{:<04X}", program_counter);
-  let op = cpu.mem_read(program_counter);
-  let ops = cpu.find_ops(op).unwrap();
+  let op = cpu.mem_peek(program_counter);
+  let ops = cpu.find_ops(op);
   let mut args: Vec<u8> = vec![];
   for n in 1..ops.bytes {
-    let arg = cpu.mem_read(program_counter + n);
+    let arg = cpu.mem_peek(program_counter.wrapping_add(n));
     args.push(arg);
   }
   let bin = binary(op, &args);
   let asm = disasm(program_counter, &ops, &args);
   let memacc = memory_access(cpu, &ops, &args);
   let status = cpu2str(cpu);
-  unsafe { IN_TRACE = false };
 
   format!(
-    "{:<6}{:<9}{:<33}{}",
+    "{:<6}{:<9}{:<33}{} PPU:{:>3},{:>3} CYC:{}",
     pc,
     bin,
     vec![asm, memacc].join(" "),
-    status
+    status,
+    cpu.bus.ppu().scanline(),
+    cpu.bus.ppu().dot(),
+    cpu.bus.cycles()
   )
 }
 
@@ -176,65 +361,65 @@ fn address(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
   }
 }
 
-fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
+fn memory_access(cpu: &CPU, ops: &OpCode, args: &Vec<u8>) -> String {
   // ジャンプ系はログを変える
   if ops.name.starts_with("J") {
     if ops.addressing_mode == AddressingMode::Indirect {
       let hi = args[1] as u16;
       let lo = args[0] as u16;
       let addr = hi << 8 | lo;
-      let value = cpu.mem_read_u16(addr);
+      let value = peek_u16(cpu, addr);
       return format!("= {:04X}", value);
     }
     return format!("");
   }
   match ops.addressing_mode {
     AddressingMode::ZeroPage => {
-      let value = cpu.mem_read(args[0] as u16);
+      let value = peek(cpu, args[0] as u16);
       format!("= {:>02X}", value)
     }
     AddressingMode::ZeroPage_X => {
       let addr = args[0].wrapping_add(cpu.register_x) as u16;
-      let value = cpu.mem_read(addr);
+      let value = peek(cpu, addr);
       format!("@ {:<02X} = {:02X}", addr, value)
     }
     AddressingMode::ZeroPage_Y => {
       let addr = args[0].wrapping_add(cpu.register_y) as u16;
-      let value = cpu.mem_read(addr);
+      let value = peek(cpu, addr);
       format!("@ {:<02X} = {:02X}", addr, value)
     }
     AddressingMode::Absolute => {
       let hi = args[1] as u16;
       let lo = args[0] as u16;
       let addr = hi << 8 | lo;
-      let value = cpu.mem_read(addr);
+      let value = peek(cpu, addr);
       format!("= {:<02X}", value)
     }
     AddressingMode::Absolute_X => {
-      let base = cpu.mem_read_u16(cpu.program_counter);
+      let base = (args[1] as u16) << 8 | args[0] as u16;
       let addr = base.wrapping_add(cpu.register_x as u16);
-      let value = cpu.mem_read(addr);
+      let value = peek(cpu, addr);
       format!("@ {:<04X} = {:02X}", addr, value)
     }
     AddressingMode::Absolute_Y => {
-      let base = cpu.mem_read_u16(cpu.program_counter);
+      let base = (args[1] as u16) << 8 | args[0] as u16;
       let addr = base.wrapping_add(cpu.register_y as u16);
-      let value = cpu.mem_read(addr);
+      let value = peek(cpu, addr);
       format!("@ {:04X} = {:02X}", addr, value)
     }
     AddressingMode::Indirect_X => {
       let base = args[0];
       let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-      let addr = cpu.mem_read_u16(ptr as u16);
-      let value = cpu.mem_read(addr);
+      let addr = peek_u16(cpu, ptr as u16);
+      let value = peek(cpu, addr);
       format!("@ {:>02X} = {:04X} = {:02X}  ", ptr, addr, value)
     }
     AddressingMode::Indirect_Y => {
       // = 0400 @ 0400 = AA
       let base = args[0];
-      let deref_base = cpu.mem_read_u16(base as u16);
+      let deref_base = peek_u16(cpu, base as u16);
       let deref = deref_base.wrapping_add(cpu.register_y as u16);
-      let value = cpu.mem_read(deref);
+      let value = peek(cpu, deref);
       format!("= {:>04X} @ {:04X} = {:02X}", deref_base, deref, value)
     }
     AddressingMode::NoneAddressing => {
@@ -246,6 +431,21 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
   }
 }
 
+// nestest.logはAPUとI/Oレジスタを読まずにFFと表示している
+fn peek(cpu: &CPU, addr: u16) -> u8 {
+  match addr {
+    0x4000..=0x401F => 0xFF,
+    _ => cpu.mem_peek(addr),
+  }
+}
+
+// ゼロページのポインタとJMP ($xxFF)はページをまたがずに折り返す
+fn peek_u16(cpu: &CPU, addr: u16) -> u16 {
+  let lo = peek(cpu, addr) as u16;
+  let hi = peek(cpu, (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF)) as u16;
+  hi << 8 | lo
+}
+
 fn cpu2str(cpu: &CPU) -> String {
   format!(
     "A:{:<02X} X:{:02X} Y:{:<02X} P:{:02X} SP:{:02X}",
@@ -253,17 +453,20 @@ fn cpu2str(cpu: &CPU) -> String {
   )
 }
 
-impl Mem for CPU<'_> {
+impl<M: Mem> Mem for CPU<M> {
   fn mem_read(&mut self, addr: u16) -> u8 {
     self.bus.mem_read(addr)
   }
   fn mem_write(&mut self, addr: u16, data: u8) {
     self.bus.mem_write(addr, data)
   }
+  fn mem_peek(&self, addr: u16) -> u8 {
+    self.bus.mem_peek(addr)
+  }
 }
 
-impl<'a> CPU<'a> {
-  pub fn new(bus: Bus<'a>) -> CPU<'a> {
+impl<M: Mem> CPU<M> {
+  pub fn new(bus: M) -> CPU<M> {
     CPU {
       register_a: 0,
       register_x: 0,
@@ -272,12 +475,61 @@ impl<'a> CPU<'a> {
       stack_pointer: 0xFD,                  // Fixme
       program_counter: 0,
       bus: bus,
-      add_cycle: 0,
+      jammed: false,
+      nmi_pending: false,
+      nmi_poll: false,
+      irq_poll: false,
     }
   }
 
-  fn get_operand_address(&mut self, _mode: &AddressingMode) -> u16 {
-    match _mode {
+  // 命令実行中のメモリアクセス
+  // 1回のアクセスで1CPUサイクル分バスを進める
+  fn read(&mut self, addr: u16) -> u8 {
+    self.poll_interrupts();
+    let value = self.bus.mem_read(addr);
+    self.tick();
+    value
+  }
+
+  fn write(&mut self, addr: u16, data: u8) {
+    self.poll_interrupts();
+    self.bus.mem_write(addr, data);
+    self.tick();
+  }
+
+  fn poll_interrupts(&mut self) {
+    self.nmi_poll = self.nmi_pending;
+    self.irq_poll = self.bus.poll_irq_status() && self.status & FLAG_INTERRUPT == 0;
+  }
+
+  fn tick(&mut self) {
+    self.bus.tick(1);
+    if self.bus.poll_nmi_status().is_some() {
+      self.nmi_pending = true;
+    }
+  }
+
+  // 命令の引数の2バイトを読む
+  fn read_operand_u16(&mut self) -> u16 {
+    let lo = self.read(self.program_counter) as u16;
+    let hi = self.read(self.program_counter.wrapping_add(1)) as u16;
+    (hi << 8) | lo
+  }
+
+  // 読み込み命令用
+  // インデックスでページをまたいだ時だけダミーリードが入る
+  fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
+    self.operand_address(mode, false)
+  }
+
+  // 書き込み命令とリードモディファイライト命令用
+  // インデックス付きのアドレスでは常にダミーリードが入る
+  fn get_write_address(&mut self, mode: &AddressingMode) -> u16 {
+    self.operand_address(mode, true)
+  }
+
+  fn operand_address(&mut self, mode: &AddressingMode, write: bool) -> u16 {
+    match mode {
       AddressingMode::Implied => {
         panic!("Don't Ask Here Address of Implied");
       }
@@ -285,70 +537,74 @@ impl<'a> CPU<'a> {
         panic!("Don't Ask Here Address of Accumulator");
       }
       AddressingMode::Immediate => self.program_counter,
-      AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
-      AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
+      AddressingMode::ZeroPage => self.read(self.program_counter) as u16,
+      AddressingMode::Absolute => self.read_operand_u16(),
       AddressingMode::ZeroPage_X => {
-        let pos = self.mem_read(self.program_counter);
-        let addr = pos.wrapping_add(self.register_x) as u16;
-        addr
+        let pos = self.read(self.program_counter);
+        // インデックスを足している間に足す前のアドレスを読む
+        self.read(pos as u16);
+        pos.wrapping_add(self.register_x) as u16
       }
       AddressingMode::ZeroPage_Y => {
-        let pos = self.mem_read(self.program_counter);
-        let addr = pos.wrapping_add(self.register_y) as u16;
-        addr
+        let pos = self.read(self.program_counter);
+        self.read(pos as u16);
+        pos.wrapping_add(self.register_y) as u16
       }
       AddressingMode::Absolute_X => {
-        let base = self.mem_read_u16(self.program_counter);
-        let addr = base.wrapping_add(self.register_x as u16);
-        // (+1 if page crossed)
-        if base & 0xFF00 != base & 0xFF00 {
-          self.add_cycle += 1;
-        }
-        addr
+        let base = self.read_operand_u16();
+        self.indexed_address(base, self.register_x, write)
       }
       AddressingMode::Absolute_Y => {
-        let base = self.mem_read_u16(self.program_counter);
-        let addr = base.wrapping_add(self.register_y as u16);
-        // (+1 if page crossed)
-        if base & 0xFF00 != base & 0xFF00 {
-          self.add_cycle += 1;
-        }
-        addr
+        let base = self.read_operand_u16();
+        self.indexed_address(base, self.register_y, write)
       }
       AddressingMode::Indirect => {
-        let base = self.mem_read_u16(self.program_counter);
-        self.mem_read_u16(base as u16)
+        // 上位バイトはページをまたがずに同じページの先頭から読む(JMPのバグ)
+        // https://www.nesdev.org/obelisk-6502-guide/reference.html#JMP
+        let ptr = self.read_operand_u16();
+        let lo = self.read(ptr) as u16;
+        let hi = self.read((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF)) as u16;
+        (hi << 8) | lo
       }
       AddressingMode::Indirect_X => {
-        let base = self.mem_read(self.program_counter);
+        let base = self.read(self.program_counter);
+        self.read(base as u16);
 
-        let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-        self.mem_read_u16(ptr as u16)
+        let ptr = base.wrapping_add(self.register_x);
+        let lo = self.read(ptr as u16) as u16;
+        let hi = self.read(ptr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
       }
       AddressingMode::Indirect_Y => {
-        let base = self.mem_read(self.program_counter);
+        let base = self.read(self.program_counter);
 
-        let deref_base = self.mem_read_u16(base as u16);
-        let deref = deref_base.wrapping_add(self.register_y as u16);
-
-        // (+1 if page crossed)
-        if deref_base & 0xFF00 != deref & 0xFF00 {
-          self.add_cycle += 1;
-        }
-        deref
+        let lo = self.read(base as u16) as u16;
+        let hi = self.read(base.wrapping_add(1) as u16) as u16;
+        self.indexed_address((hi << 8) | lo, self.register_y, write)
       }
       AddressingMode::Relative => {
-        let base = self.mem_read(self.program_counter);
+        let base = self.read(self.program_counter);
         let base = base as i8;
         let addr = base as i32 + self.program_counter as i32;
         addr as u16
       }
       AddressingMode::NoneAddressing => {
-        panic!("_mode {:?} is not supported", _mode);
+        panic!("mode {:?} is not supported", mode);
       }
     }
   }
 
+  // 下位バイトにインデックスを足したアドレスを先に読み、
+  // ページをまたいでいたら上位バイトを直してからもう一度アクセスする
+  fn indexed_address(&mut self, base: u16, index: u8, write: bool) -> u16 {
+    let addr = base.wrapping_add(index as u16);
+    // (+1 if page crossed)
+    if write || base & 0xFF00 != addr & 0xFF00 {
+      self.read((base & 0xFF00) | (addr & 0x00FF));
+    }
+    addr
+  }
+
   // pub fn mem_read(&self, addr: u16) -> u8 {
   //   self.bus.mem_read(addr)
   // }
@@ -378,12 +634,6 @@ impl<'a> CPU<'a> {
     self.mem_write(pos + 1, hi);
   }
 
-  fn load_and_run(&mut self, program: Vec<u8>) {
-    self.load();
-    self.reset();
-    self.run()
-  }
-
   pub fn load(&mut self) {
     // プログラムメモリは読み込み専用になるのでロードはいらない
     // self.mem_write_u16(0xFFFC, 0x8000);
@@ -394,10 +644,22 @@ impl<'a> CPU<'a> {
     self.register_x = 0;
     self.register_y = 0;
     self.status = FLAG_INTERRUPT | FLAG_BREAK2;
-    self.stack_pointer = 0xFD;
-    self.program_counter = self.mem_read_u16(0xFFFC);
+    self.stack_pointer = 0x00;
+    self.jammed = false;
+    self.nmi_pending = false;
+
+    // 割り込みと同じ7サイクルのシーケンスだが、スタックには書き込まずSPだけ3つ減る
+    self.read(self.program_counter);
+    self.read(self.program_counter);
+    for _ in 0..3 {
+      self._dummy_stack_read();
+      self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    }
+    self.program_counter = self.read_vector(0xFFFC);
     // println!("PC: {:X}", self.program_counter);
     // self.program_counter = 0xC000;
+    self.nmi_poll = false;
+    self.irq_poll = false;
   }
 
   pub fn run(&mut self) {
@@ -406,79 +668,134 @@ impl<'a> CPU<'a> {
 
   pub fn run_with_callback<F>(&mut self, mut callback: F)
   where
-    F: FnMut(&mut CPU),
+    F: FnMut(&mut CPU<M>),
   {
     loop {
-      if let Some(_nmi) = self.bus.poll_nmi_status() {
-        self.interrupt_nmi();
-      }
-      let opscode = self.mem_read(self.program_counter);
-      self.program_counter += 1;
-
-      // println!("OPS: {:X}", opscode);
-
-      let op = self.find_ops(opscode);
-      match op {
-        Some(op) => {
-          // FIXME FOR TEST
-          // if op.name == "BRK" {
-          //   return;
-          // }
-          self.add_cycle = 0;
-          callback(self);
-          call(self, &op);
-
-          // TODO cycleの計算b
-          self.bus.tick(op.cycles + self.add_cycle);
-        }
-        _ => {
-          // panic!("no implemention {:02X}", opscode);
-        }
-      }
+      self.step_with_callback(&mut callback);
     }
   }
 
-  fn interrupt_nmi(&mut self) {
+  // 割り込みの処理と1命令の実行
+  pub fn step(&mut self) {
+    self.step_with_callback(|_| {});
+  }
+
+  pub fn step_with_callback<F>(&mut self, callback: F)
+  where
+    F: FnOnce(&mut CPU<M>),
+  {
+    if self.jammed {
+      // 止まっていても時間は進める
+      self.bus.tick(1);
+      return;
+    }
+    // 前の命令の最後から2番目のサイクルまでに来ていた割り込みを処理する
+    // NMIとIRQのどちらのベクタを使うかはinterruptの中で決まる
+    if self.nmi_poll || self.irq_poll {
+      self.interrupt(false);
+    }
+    callback(self);
+    let opscode = self.read(self.program_counter);
+    self.program_counter += 1;
+
+    // println!("OPS: {:X}", opscode);
+
+    let op = self.find_ops(opscode);
+    // 引数の無い命令も次のバイトを読んで捨てる
+    if op.addressing_mode == AddressingMode::Implied
+      || op.addressing_mode == AddressingMode::Accumulator
+    {
+      self.read(self.program_counter);
+    }
+    call(self, op);
+  }
+
+  pub fn is_jammed(&self) -> bool {
+    self.jammed
+  }
+
+  // NMI, IRQ, BRK共通のシーケンス
+  // ステータスを積む時点でNMIが来ていれば、IRQやBRKでもNMIのベクタに化ける(ハイジャック)
+  fn interrupt(&mut self, brk: bool) {
+    if !brk {
+      // 命令の代わりに2回読んで捨てる
+      self.read(self.program_counter);
+      self.read(self.program_counter);
+    }
     self._push_u16(self.program_counter);
-    let mut status = self.status;
-    status = status & !FLAG_BREAK;
-    status = status | FLAG_BREAK2;
 
+    let vector = if self.nmi_pending {
+      self.nmi_pending = false;
+      0xFFFA
+    } else {
+      0xFFFE
+    };
+    let mut status = self.status | FLAG_BREAK2;
+    status = if brk {
+      status | FLAG_BREAK
+    } else {
+      status & !FLAG_BREAK
+    };
     self._push(status);
     self.status |= FLAG_INTERRUPT;
 
-    self.bus.tick(2);
-    self.program_counter = self.mem_read_u16(0xFFFA);
-    // println!("**interrupt nmi**");
+    self.program_counter = self.read_vector(vector);
+    // 割り込み処理の最初の1命令は必ず実行される
+    self.nmi_poll = false;
+    self.irq_poll = false;
   }
 
-  fn find_ops(&self, opscode: u8) -> Option<OpCode> {
-    for op in CPU_OPS_CODES.iter() {
-      if op.code == opscode {
-        return Some(op.clone());
-      }
-    }
-    return None;
+  fn read_vector(&mut self, vector: u16) -> u16 {
+    let lo = self.read(vector) as u16;
+    let hi = self.read(vector + 1) as u16;
+    (hi << 8) | lo
+  }
+
+  fn find_ops(&self, opscode: u8) -> &'static OpCode {
+    &CPU_OPS_TABLE[opscode as usize]
   }
 
   pub fn anc(&mut self, mode: &AddressingMode) {
-    todo!("anc")
+    // AND #{imm} の後、ネガティブフラグをキャリーにコピーする
+    self.and(mode);
+    self._set_carry(self.register_a & 0x80 != 0);
   }
 
   pub fn asr(&mut self, mode: &AddressingMode) {
-    todo!("asr")
+    // = ALR
+    // AND #{imm} + LSR A
+    self.and(mode);
+    self.register_a = self._shift_right(self.register_a, 0);
+    self.update_zero_and_negative_flags(self.register_a);
   }
 
   pub fn arr(&mut self, mode: &AddressingMode) {
-    todo!("arr")
+    // AND #{imm} + ROR A
+    // キャリーとオーバーフローはADCの途中結果のようになる
+    // C = bit6, V = bit6 xor bit5
+    self.and(mode);
+    let carry = self.status & FLAG_CARRY;
+    self.register_a = (self.register_a >> 1) | (carry << 7);
+    self.update_zero_and_negative_flags(self.register_a);
+    let bit6 = self.register_a & 0x40 != 0;
+    let bit5 = self.register_a & 0x20 != 0;
+    self._set_carry(bit6);
+    self._set_overflow(bit6 != bit5);
   }
 
   pub fn lxa(&mut self, mode: &AddressingMode) {
-    todo!("lxa")
+    // (A OR CONST) AND #{imm} into A and X
+    let addr = self.get_operand_address(mode);
+    let value = self.read(addr);
+    self.register_a = (self.register_a | LXA_MAGIC) & value;
+    self.register_x = self.register_a;
+    self.update_zero_and_negative_flags(self.register_a);
   }
 
   pub fn sha(&mut self, mode: &AddressingMode) {
-    todo!("sha")
+    // = AHX
+    // A&X&(H+1) into {adr}
+    self._store_high_and(mode, self.register_y, self.register_a & self.register_x);
   }
 
   pub fn sbx(&mut self, mode: &AddressingMode) {
@@ -489,116 +806,123 @@ impl<'a> CPU<'a> {
 
     // AND X をアキュムレータに登録し、結果を X レジスタに格納します。 X レジスタからバイトを減算します (ボローなし)。 ステータスフラグ：N、Z、C
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-    let (v, overflow) = (self.register_a & self.register_x).overflowing_sub(value);
-    self.register_x = v;
-    self.update_zero_and_negative_flags(v);
-    self.status = if overflow {
-      self.status & !FLAG_OVERFLOW
-    } else {
-      self.status | FLAG_OVERFLOW
-    };
-    todo!("sbx")
+    let value = self.read(addr);
+    self.register_x = self._compare(self.register_a & self.register_x, value);
   }
 
-  pub fn jam(&mut self, mode: &AddressingMode) {
+  pub fn jam(&mut self, _mode: &AddressingMode) {
     // Stop porogram counter (processor lock up).
+    // リセットされるまで何もしない
     self.program_counter -= 1;
-    panic!("CALL JAM operation.");
+    self.jammed = true;
+    warn!("CPU jammed at {:04X}", self.program_counter);
   }
 
   pub fn lae(&mut self, mode: &AddressingMode) {
+    // = LAS
     // stores {adr}&S into A, X and S
 
     // AND memory with stack pointer,transfer result to accu-mlator,X register and stack pointer.
     // Status flags: N,Z
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-    let s = self._pop();
-    self.register_a = value & s;
-    self.register_x = self.register_a;
-    self._push(self.register_a);
-    self.update_zero_and_negative_flags(self.register_a);
-    todo!("lae")
+    let value = self.read(addr) & self.stack_pointer;
+    self.register_a = value;
+    self.register_x = value;
+    self.stack_pointer = value;
+    self.update_zero_and_negative_flags(value);
   }
 
   pub fn shx(&mut self, mode: &AddressingMode) {
-    // M = 3D X AND HIGH(arg) + 1
-    let addr = self.get_operand_address(mode);
-    let h = ((addr & 0xFF00) >> 8) as u8;
-    self.mem_write(addr, (self.register_x & h).wrapping_add(1));
-    todo!("shx")
+    // X&(H+1) into {adr}
+    self._store_high_and(mode, self.register_y, self.register_x);
   }
 
   pub fn shy(&mut self, mode: &AddressingMode) {
-    // Y&H into {adr}
+    // Y&(H+1) into {adr}
     // AND Y register with the high byte of the target address of argment
     // +1. Store the result memory
-    let addr = self.get_operand_address(mode);
-    let h = ((addr & 0xFF00) >> 8) as u8;
-    self.mem_write(addr, (self.register_y & h).wrapping_add(1));
-    todo!("shy")
+    self._store_high_and(mode, self.register_x, self.register_y);
   }
 
   pub fn ane(&mut self, mode: &AddressingMode) {
-    // TXA + AND #{imm}
-    self.txa(mode);
-    self.and(mode);
-    todo!("ane")
+    // = XAA
+    // (A OR CONST) AND X AND #{imm} into A
+    let addr = self.get_operand_address(mode);
+    let value = self.read(addr);
+    self.register_a = (self.register_a | ANE_MAGIC) & self.register_x & value;
+    self.update_zero_and_negative_flags(self.register_a);
   }
 
   pub fn shs(&mut self, mode: &AddressingMode) {
+    // = TAS
     // stores A&X into S and A&X&H into {adr}
     // アキュムレータとXレジスタとAND演算し、結果をスタックポインタに格納する。
-    // 次にスタックポインタと引数1のターゲットアドレスの上位バイトをAND演算し、結果をメモリに格納する。
-    self._push(self.register_a & self.register_x);
-    let addr = self.get_operand_address(mode);
-    let h = ((addr & 0xFF00) >> 8) as u8;
-    self.mem_write(addr, self.register_a & self.register_x & h);
-    todo!("shs")
+    // 次にスタックポインタと引数1のターゲットアドレスの上位バイト+1をAND演算し、結果をメモリに格納する。
+    self.stack_pointer = self.register_a & self.register_x;
+    self._store_high_and(mode, self.register_y, self.stack_pointer);
   }
 
   pub fn rra(&mut self, mode: &AddressingMode) {
-    self.ror(mode);
-    self.adc(mode);
-    todo!("rra")
+    // ROR + ADC
+    let carry = self.status & FLAG_CARRY;
+    let value = self._modify(mode, |cpu, value| cpu._shift_right(value, carry));
+    self._add(value);
   }
 
   pub fn sre(&mut self, mode: &AddressingMode) {
-    self.lsr(mode);
-    self.eor(mode);
-    todo!("sre")
+    // LSR + EOR
+    let value = self._modify(mode, |cpu, value| cpu._shift_right(value, 0));
+    self.register_a ^= value;
+    self.update_zero_and_negative_flags(self.register_a);
   }
 
   pub fn rla(&mut self, mode: &AddressingMode) {
-    self.rol(mode);
-    self.and(mode);
-    todo!("rla")
+    // ROL + AND
+    let carry = self.status & FLAG_CARRY;
+    let value = self._modify(mode, |cpu, value| cpu._shift_left(value, carry));
+    self.register_a &= value;
+    self.update_zero_and_negative_flags(self.register_a);
   }
 
   pub fn slo(&mut self, mode: &AddressingMode) {
-    self.asl(mode);
-    self.ora(mode);
-    todo!("slo")
+    // ASL + ORA
+    let value = self._modify(mode, |cpu, value| cpu._shift_left(value, 0));
+    self.register_a |= value;
+    self.update_zero_and_negative_flags(self.register_a);
   }
 
   pub fn isb(&mut self, mode: &AddressingMode) {
     // = ISC
-    self.inc(mode);
-    self.sbc(mode);
-    todo!("isb")
+    // INC + SBC
+    let value = self._modify(mode, |_, value| value.wrapping_add(1));
+    self._add(!value);
   }
 
   pub fn dcp(&mut self, mode: &AddressingMode) {
-    self.dec(mode);
-    self.cmp(mode);
-    todo!("dcp")
+    // DEC + CMP
+    let value = self._modify(mode, |_, value| value.wrapping_sub(1));
+    self._compare(self.register_a, value);
   }
 
   pub fn sax(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    self.mem_write(addr, self.register_a & self.register_x);
-    todo!("sax")
+    // A&X into {adr} (フラグは変わらない)
+    let addr = self.get_write_address(mode);
+    self.write(addr, self.register_a & self.register_x);
+  }
+
+  // SHA,SHX,SHY,SHS用
+  // 値とアドレスの上位バイト+1のANDを書き込む
+  // インデックスでページをまたいだ場合は、書き込み先の上位バイトがその値に化ける
+  fn _store_high_and(&mut self, mode: &AddressingMode, index: u8, value: u8) {
+    let addr = self.get_write_address(mode);
+    let base = addr.wrapping_sub(index as u16);
+    let value = value & ((base >> 8) as u8).wrapping_add(1);
+    let addr = if base & 0xFF00 != addr & 0xFF00 {
+      ((value as u16) << 8) | (addr & 0x00FF)
+    } else {
+      addr
+    };
+    self.write(addr, value);
   }
 
   pub fn lax(&mut self, mode: &AddressingMode) {
@@ -634,20 +958,21 @@ impl<'a> CPU<'a> {
   }
 
   pub fn sty(&mut self, _mode: &AddressingMode) {
-    let addr = self.get_operand_address(_mode);
-    self.mem_write(addr, self.register_y);
+    let addr = self.get_write_address(_mode);
+    self.write(addr, self.register_y);
   }
   pub fn stx(&mut self, _mode: &AddressingMode) {
-    let addr = self.get_operand_address(_mode);
-    self.mem_write(addr, self.register_x);
+    let addr = self.get_write_address(_mode);
+    self.write(addr, self.register_x);
   }
   // レジスタaの値をメモリに書き込む
   pub fn sta(&mut self, _mode: &AddressingMode) {
-    let addr = self.get_operand_address(_mode);
-    self.mem_write(addr, self.register_a);
+    let addr = self.get_write_address(_mode);
+    self.write(addr, self.register_a);
   }
 
   pub fn rti(&mut self, _mode: &AddressingMode) {
+    self._dummy_stack_read();
     // 引用 https://bugzmanov.github.io/nes_ebook/chapter_3_3.html
     // フラグ管理をする
     self.status = self._pop() & !FLAG_BREAK | FLAG_BREAK2;
@@ -655,6 +980,7 @@ impl<'a> CPU<'a> {
   }
 
   pub fn plp(&mut self, _mode: &AddressingMode) {
+    self._dummy_stack_read();
     // 引用 https://bugzmanov.github.io/nes_ebook/chapter_3_3.html
     // フラグ管理をする
     self.status = self._pop() & !FLAG_BREAK | FLAG_BREAK2;
@@ -667,6 +993,7 @@ impl<'a> CPU<'a> {
   }
 
   pub fn pla(&mut self, _mode: &AddressingMode) {
+    self._dummy_stack_read();
     self.register_a = self._pop();
     self.update_zero_and_negative_flags(self.register_a);
   }
@@ -677,18 +1004,23 @@ impl<'a> CPU<'a> {
 
   pub fn nop(&mut self, _mode: &AddressingMode) {
     // 何もしない
+    // 引数のある非公式のNOPは読み込みだけする
+    if _mode != &AddressingMode::Implied {
+      let addr = self.get_operand_address(_mode);
+      self.read(addr);
+    }
   }
 
   pub fn ldy(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
 
     self.register_y = value;
     self.update_zero_and_negative_flags(value);
   }
   pub fn ldx(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
 
     self.register_x = value;
     self.update_zero_and_negative_flags(value);
@@ -697,35 +1029,46 @@ impl<'a> CPU<'a> {
   // レジスタaに値をコピーする
   pub fn lda(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
 
     self.register_a = value;
     self.update_zero_and_negative_flags(value);
   }
 
   pub fn rts(&mut self, _mode: &AddressingMode) {
-    let value = self._pop_u16() + 1;
-    self.program_counter = value;
+    self._dummy_stack_read();
+    let value = self._pop_u16();
+    // 戻り先の1つ前を読んでからインクリメントする
+    self.read(value);
+    self.program_counter = value.wrapping_add(1);
   }
 
   pub fn jsr(&mut self, _mode: &AddressingMode) {
-    let addr = self.get_operand_address(_mode);
+    // 下位バイトを読んだ後、戻り先を積んでから上位バイトを読む
+    let lo = self.read(self.program_counter) as u16;
+    self._dummy_stack_read();
     self._push_u16(self.program_counter + 2 - 1);
-    self.program_counter = addr;
+    let hi = self.read(self.program_counter + 1) as u16;
+    self.program_counter = (hi << 8) | lo;
     // 後で+2されるので整合性のため-2する
-    self.program_counter -= 2;
+    self.program_counter = self.program_counter.wrapping_sub(2);
+  }
+
+  // スタックから取り出す前の空読み
+  fn _dummy_stack_read(&mut self) {
+    self.read(0x0100 + self.stack_pointer as u16);
   }
 
   pub fn _push(&mut self, value: u8) {
     let addr = 0x0100 + self.stack_pointer as u16;
-    self.mem_write(addr, value);
+    self.write(addr, value);
     self.stack_pointer = self.stack_pointer.wrapping_sub(1);
   }
 
   pub fn _pop(&mut self) -> u8 {
     self.stack_pointer = self.stack_pointer.wrapping_add(1);
     let addr = 0x0100 + self.stack_pointer as u16;
-    self.mem_read(addr)
+    self.read(addr)
   }
 
   pub fn _push_u16(&mut self, value: u16) {
@@ -743,8 +1086,12 @@ impl<'a> CPU<'a> {
   // like SBC
   pub fn adc(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
+    self._add(value);
+  }
 
+  // SBCは1の補数を足すのと同じ
+  fn _add(&mut self, value: u8) {
     // n = register_a + value + carry
     let carry = self.status & FLAG_CARRY;
     let (rhs, carry_flag) = value.overflowing_add(carry);
@@ -758,16 +1105,8 @@ impl<'a> CPU<'a> {
 
     self.register_a = n;
 
-    self.status = if carry_flag || carry_flag2 {
-      self.status | FLAG_CARRY
-    } else {
-      self.status & (!FLAG_CARRY)
-    };
-    self.status = if overflow {
-      self.status | FLAG_OVERFLOW
-    } else {
-      self.status & (!FLAG_OVERFLOW)
-    };
+    self._set_carry(carry_flag || carry_flag2);
+    self._set_overflow(overflow);
 
     self.update_zero_and_negative_flags(self.register_a);
   }
@@ -776,7 +1115,7 @@ impl<'a> CPU<'a> {
   // like EOR,ORA
   pub fn and(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
 
     self.register_a &= value;
     self.update_zero_and_negative_flags(self.register_a);
@@ -785,22 +1124,11 @@ impl<'a> CPU<'a> {
   // 算術左シフト
   // like LSR,ROL,ROR
   pub fn asl(&mut self, _mode: &AddressingMode) {
-    let (value, carry) = if _mode == &AddressingMode::Accumulator {
-      let (value, carry) = self.register_a.overflowing_mul(2);
-      self.register_a = value;
-      (value, carry)
+    let value = if _mode == &AddressingMode::Accumulator {
+      self.register_a = self._shift_left(self.register_a, 0);
+      self.register_a
     } else {
-      let addr = self.get_operand_address(_mode);
-      let value = self.mem_read(addr);
-      let (value, carry) = value.overflowing_mul(2);
-      self.mem_write(addr, value);
-      (value, carry)
-    };
-
-    self.status = if carry {
-      self.status | FLAG_CARRY
-    } else {
-      self.status & (!FLAG_CARRY)
+      self._modify(_mode, |cpu, value| cpu._shift_left(value, 0))
     };
     self.update_zero_and_negative_flags(value);
   }
@@ -835,7 +1163,7 @@ impl<'a> CPU<'a> {
   // オーバーフローフラグとネガティブフラグを立てる
   pub fn bit(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
     let result = value & self.register_a;
     self.status = if result == 0 {
       self.status | FLAG_ZERO
@@ -848,27 +1176,25 @@ impl<'a> CPU<'a> {
 
   fn _branch(&mut self, mode: &AddressingMode, flag: u8, nonzero: bool) {
     let addr = self.get_operand_address(mode);
-    if nonzero {
-      if self.status & flag != 0 {
-        // (+1 if branch succeeds
-        //  +2 if to a new page)
-        self.add_cycle += 1;
-        if self.program_counter & 0xFF00 != addr & 0xFF00 {
-          self.add_cycle += 2;
-        }
-        self.program_counter = addr;
-      }
+    if (self.status & flag != 0) != nonzero {
+      return;
+    }
+    // (+1 if branch succeeds
+    //  +2 if to a new page)
+    // 分岐する時は次の命令を読んで捨て、ページをまたぐ時は
+    // 上位バイトを直す前のアドレスをもう一度読む
+    let next = self.program_counter.wrapping_add(1);
+    let target = addr.wrapping_add(1);
+    let (nmi_poll, irq_poll) = (self.nmi_poll, self.irq_poll);
+    self.read(next);
+    if next & 0xFF00 != target & 0xFF00 {
+      self.read((next & 0xFF00) | (target & 0x00FF));
     } else {
-      if self.status & flag == 0 {
-        // (+1 if branch succeeds
-        //  +2 if to a new page)
-        self.add_cycle += 1;
-        if self.program_counter & 0xFF00 != addr & 0xFF00 {
-          self.add_cycle += 2;
-        }
-        self.program_counter = addr;
-      }
+      // ページをまたがない分岐では最後のサイクルで割り込みを確認しない
+      self.nmi_poll = nmi_poll;
+      self.irq_poll = irq_poll;
     }
+    self.program_counter = addr;
   }
 
   // ネガティブフラグが立っていたら分岐
@@ -906,26 +1232,23 @@ impl<'a> CPU<'a> {
 
   pub fn _cmp(&mut self, target: u8, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
-
-    if target >= value {
-      self.sec(&AddressingMode::Implied);
-    } else {
-      self.clc(&AddressingMode::Implied);
-    }
+    let value = self.read(addr);
+    self._compare(target, value);
+  }
 
-    let (value, _) = target.overflowing_sub(value);
+  // 比較してフラグを立て、差を返す
+  fn _compare(&mut self, target: u8, value: u8) -> u8 {
+    self._set_carry(target >= value);
+    let value = target.wrapping_sub(value);
     self.update_zero_and_negative_flags(value);
+    value
   }
 
   // break
   pub fn brk(&mut self, _mode: &AddressingMode) {
-    // TODO!
-    self._push_u16(self.program_counter);
-    self._push(self.status);
-
-    self.program_counter = self.mem_read_u16(0xFFFE);
-    self.status |= FLAG_BREAK;
+    // BRKの次の1バイトは読み飛ばされる
+    self.program_counter = self.program_counter.wrapping_add(1);
+    self.interrupt(true);
   }
 
   // オーバーフローフラグがクリアなら分岐
@@ -955,11 +1278,7 @@ impl<'a> CPU<'a> {
 
   // デクリメントメモリ
   pub fn dec(&mut self, _mode: &AddressingMode) {
-    let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
-    let value = value.wrapping_sub(1);
-
-    self.mem_write(addr, value);
+    let value = self._modify(_mode, |_, value| value.wrapping_sub(1));
     self.update_zero_and_negative_flags(value);
   }
 
@@ -984,7 +1303,7 @@ impl<'a> CPU<'a> {
   // like AND,ORA
   pub fn eor(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
 
     self.register_a ^= value;
     self.update_zero_and_negative_flags(self.register_a);
@@ -992,11 +1311,7 @@ impl<'a> CPU<'a> {
 
   // デクリメントメモリ
   pub fn inc(&mut self, _mode: &AddressingMode) {
-    let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
-    let (value, _) = value.overflowing_add(1);
-
-    self.mem_write(addr, value);
+    let value = self._modify(_mode, |_, value| value.wrapping_add(1));
     self.update_zero_and_negative_flags(value);
   }
 
@@ -1020,7 +1335,7 @@ impl<'a> CPU<'a> {
     let addr = self.get_operand_address(_mode);
     self.program_counter = addr;
     // 引数の関係上あとで+2するので整合性のため-2する
-    self.program_counter -= 2;
+    self.program_counter = self.program_counter.wrapping_sub(2);
     // この後プログラムカウンターはインクリメントしない。
     // 元の6502はターゲットアドレスを正しくフェッチしていません
     // 間接ベクトルがページ境界に該当する場合
@@ -1034,23 +1349,11 @@ impl<'a> CPU<'a> {
   // 算術右シフト
   // like ASR,ROL,ROR
   pub fn lsr(&mut self, _mode: &AddressingMode) {
-    let (value, carry) = if _mode == &AddressingMode::Accumulator {
-      let carry = (self.register_a & 0x01) != 0; // 最下位ビットが立っているか
-      self.register_a /= 2;
-      (self.register_a, carry)
+    let value = if _mode == &AddressingMode::Accumulator {
+      self.register_a = self._shift_right(self.register_a, 0);
+      self.register_a
     } else {
-      let addr = self.get_operand_address(_mode);
-      let value = self.mem_read(addr);
-      let carry = (value & 0x01) != 0;
-      let value = value / 2;
-      self.mem_write(addr, value);
-      (value, carry)
-    };
-
-    self.status = if carry {
-      self.status | FLAG_CARRY
-    } else {
-      self.status & (!FLAG_CARRY)
+      self._modify(_mode, |cpu, value| cpu._shift_right(value, 0))
     };
     self.update_zero_and_negative_flags(value);
   }
@@ -1059,7 +1362,7 @@ impl<'a> CPU<'a> {
   // like AND,EOR
   pub fn ora(&mut self, _mode: &AddressingMode) {
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
+    let value = self.read(addr);
 
     self.register_a |= value;
     self.update_zero_and_negative_flags(self.register_a);
@@ -1068,24 +1371,12 @@ impl<'a> CPU<'a> {
   // 算術左シフト(キャリーによる補完あり)
   // like ASL,LSR,ROR
   pub fn rol(&mut self, _mode: &AddressingMode) {
-    let (value, carry) = if _mode == &AddressingMode::Accumulator {
-      let (value, carry) = self.register_a.overflowing_mul(2);
-      let value = value | (self.status & FLAG_CARRY);
-      self.register_a = value;
-      (value, carry)
-    } else {
-      let addr = self.get_operand_address(_mode);
-      let value = self.mem_read(addr);
-      let (value, carry) = value.overflowing_mul(2);
-      let value = value | (self.status & FLAG_CARRY);
-      self.mem_write(addr, value);
-      (value, carry)
-    };
-
-    self.status = if carry {
-      self.status | FLAG_CARRY
+    let carry = self.status & FLAG_CARRY;
+    let value = if _mode == &AddressingMode::Accumulator {
+      self.register_a = self._shift_left(self.register_a, carry);
+      self.register_a
     } else {
-      self.status & (!FLAG_CARRY)
+      self._modify(_mode, |cpu, value| cpu._shift_left(value, carry))
     };
     self.update_zero_and_negative_flags(value);
   }
@@ -1093,57 +1384,66 @@ impl<'a> CPU<'a> {
   // 算術右シフト(キャリーによる補完あり)
   // like ASR,ASL,ROL
   pub fn ror(&mut self, _mode: &AddressingMode) {
-    let (value, carry) = if _mode == &AddressingMode::Accumulator {
-      let carry = (self.register_a & 0x01) != 0; // 最下位ビットが立っているか
-      self.register_a = (self.register_a / 2) | ((self.status & FLAG_CARRY) << 7);
-      (self.register_a, carry)
-    } else {
-      let addr = self.get_operand_address(_mode);
-      let value = self.mem_read(addr);
-      let carry = (value & 0x01) != 0;
-      let value = (value / 2) | ((self.status & FLAG_CARRY) << 7);
-      self.mem_write(addr, value);
-      (value, carry)
-    };
-
-    self.status = if carry {
-      self.status | FLAG_CARRY
+    let carry = self.status & FLAG_CARRY;
+    let value = if _mode == &AddressingMode::Accumulator {
+      self.register_a = self._shift_right(self.register_a, carry);
+      self.register_a
     } else {
-      self.status & (!FLAG_CARRY)
+      self._modify(_mode, |cpu, value| cpu._shift_right(value, carry))
     };
     self.update_zero_and_negative_flags(value);
   }
 
+  // 左シフトして追い出されたビットをキャリーに入れる
+  fn _shift_left(&mut self, value: u8, carry: u8) -> u8 {
+    self._set_carry(value & 0x80 != 0);
+    (value << 1) | carry
+  }
+
+  // 右シフトして追い出されたビットをキャリーに入れる
+  fn _shift_right(&mut self, value: u8, carry: u8) -> u8 {
+    self._set_carry(value & 0x01 != 0);
+    (value >> 1) | (carry << 7)
+  }
+
+  // リードモディファイライト
+  // 読んだ値をfで書き換えて書き込み、書き込んだ値を返す
+  fn _modify<F>(&mut self, mode: &AddressingMode, f: F) -> u8
+  where
+    F: FnOnce(&mut Self, u8) -> u8,
+  {
+    let addr = self.get_write_address(mode);
+    let value = self.read(addr);
+    // 書き換える前の値を一度書き戻す
+    self.write(addr, value);
+    let value = f(self, value);
+    self.write(addr, value);
+    value
+  }
+
   // レジスタaとメモリの値の差をレジスタaに書き込む
   // like ADC
   pub fn sbc(&mut self, _mode: &AddressingMode) {
-    // A-M-(1-C)
+    // A-M-(1-C) = A+!M+C
     let addr = self.get_operand_address(_mode);
-    let value = self.mem_read(addr);
-
-    let carry = self.status & FLAG_CARRY;
-    let (v1, carry_flag) = self.register_a.overflowing_sub(value);
-    let (n, carry_flag2) = v1.overflowing_sub(1 - carry);
-
-    // 違う符号同士の差で符号が変わることはないはずなので
-    let overflow =
-      ((self.register_a & 0x80) != (value & 0x80)) && ((self.register_a & 0x80) != (n & 0x80));
-
-    self.register_a = n;
+    let value = self.read(addr);
+    self._add(!value);
+  }
 
-    // キャリーがない場合にフラグが立つ
-    self.status = if !carry_flag && !carry_flag2 {
+  fn _set_carry(&mut self, carry: bool) {
+    self.status = if carry {
       self.status | FLAG_CARRY
     } else {
       self.status & (!FLAG_CARRY)
     };
+  }
+
+  fn _set_overflow(&mut self, overflow: bool) {
     self.status = if overflow {
       self.status | FLAG_OVERFLOW
     } else {
       self.status & (!FLAG_OVERFLOW)
     };
-
-    self.update_zero_and_negative_flags(self.register_a);
   }
 
   // ゼロフラグとネガティブフラグのつけ外し
@@ -1164,6 +1464,37 @@ impl<'a> CPU<'a> {
   }
 }
 
+// ステートにはバスの中身(PPU, APU, カートリッジ)も含める
+impl CPU<Bus<'_>> {
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_u8(self.register_a);
+    writer.write_u8(self.register_x);
+    writer.write_u8(self.register_y);
+    writer.write_u8(self.status);
+    writer.write_u8(self.stack_pointer);
+    writer.write_u16(self.program_counter);
+    writer.write_bool(self.jammed);
+    writer.write_bool(self.nmi_pending);
+    writer.write_bool(self.nmi_poll);
+    writer.write_bool(self.irq_poll);
+    self.bus.save_state(writer);
+  }
+
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.register_a = reader.read_u8()?;
+    self.register_x = reader.read_u8()?;
+    self.register_y = reader.read_u8()?;
+    self.status = reader.read_u8()?;
+    self.stack_pointer = reader.read_u8()?;
+    self.program_counter = reader.read_u16()?;
+    self.jammed = reader.read_bool()?;
+    self.nmi_pending = reader.read_bool()?;
+    self.nmi_poll = reader.read_bool()?;
+    self.irq_poll = reader.read_bool()?;
+    self.bus.load_state(reader)
+  }
+}
+
 //===================================================================
 // テストコード
 //===================================================================
@@ -1171,91 +1502,459 @@ impl<'a> CPU<'a> {
 mod test {
 
   use super::*;
-  use crate::bus::Bus;
+  use crate::apu::NesAPU;
+  use crate::bus::{Bus, IrqSource};
   use crate::cartridge::test_rom;
-  use crate::ppu::NesPPU;
-
-  /*
-   #[test]
-   fn test_format_trace() {
-     let mut bus = Bus::new(test_rom(), move |ppu: &NesPPU| {});
-     bus.mem_write(100, 0xa2);
-     bus.mem_write(101, 0x01);
-     bus.mem_write(102, 0xca);
-     bus.mem_write(103, 0x88);
-     bus.mem_write(104, 0x00);
-
-     let mut cpu = CPU::new(bus);
-     cpu.program_counter = 0x64;
-     cpu.register_a = 1;
-     cpu.register_x = 2;
-     cpu.register_y = 3;
-     let mut result: Vec<String> = vec![];
-     cpu.run_with_callback(|cpu| {
-       result.push(trace(cpu));
-     });
-     assert_eq!(
-       "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
-       result[0]
-     );
-     assert_eq!(
-       "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
-       result[1]
-     );
-     assert_eq!(
-       "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
-       result[2]
-     );
-   }
-
-   #[test]
-   fn test_format_mem_access() {
-     let mut bus = Bus::new(test_rom(), move |ppu: &NesPPU| {});
-     // ORA ($33), Y
-     bus.mem_write(100, 0x11);
-     bus.mem_write(101, 0x33);
-
-     // data
-     bus.mem_write(0x33, 0x00);
-     bus.mem_write(0x34, 0x04);
-
-     // target cell
-     bus.mem_write(0x400, 0xAA);
-
-     let mut cpu = CPU::new(bus);
-     cpu.program_counter = 0x64;
-     cpu.register_y = 0;
-     let mut result: Vec<String> = vec![];
-     cpu.run_with_callback(|cpu| {
-       result.push(trace(cpu));
-     });
-     assert_eq!(
-       "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
-       result[0]
-     );
-   }
-  */
-  /*
-  fn run<F>(program: Vec<u8>, f: F) -> CPU
+  use crate::joypad::JoypadButton;
+  use crate::rom;
+
+  // RAMのaddrにプログラムを置いたCPU
+  // NMIのベクタは$0400, IRQとBRKのベクタは$0300
+  fn cpu_with_program(addr: u16, program: &[u8]) -> CPU {
+    let mut prg = vec![0; 0x4000];
+    prg[0x3FFB] = 0x04;
+    prg[0x3FFF] = 0x03;
+    let (apu, _) = NesAPU::new(44100.0);
+    let mut cpu = CPU::new(Bus::new(rom::nrom(&prg, 0x0000, 0), apu, |_, _| {}));
+    for (i, value) in program.iter().enumerate() {
+      cpu.mem_write(addr + i as u16, *value);
+    }
+    cpu.program_counter = addr;
+    cpu
+  }
+
+  fn step_cycles(cpu: &mut CPU) -> usize {
+    let before = cpu.bus.cycles();
+    cpu.step();
+    cpu.bus.cycles() - before
+  }
+
+  #[test]
+  fn test_ops_table() {
+    for (code, op) in CPU_OPS_TABLE.iter().enumerate() {
+      assert_eq!(op.code as usize, code);
+    }
+    assert_eq!(CPU_OPS_TABLE[0xEB].instruction, Instruction::SBC);
+    assert_eq!(CPU_OPS_TABLE[0xEB].name, "*SBC");
+  }
+
+  #[test]
+  fn test_cycles_match_opcode_table() {
+    // 引数は全て$0010を指すようにして、ページをまたがないようにする
+    let skip = |op: &OpCode| op.cycle_calc_mode == CycleCalcMode::Branch || op.name == "*JAM";
+    for op in CPU_OPS_TABLE.iter().filter(|op| !skip(op)) {
+      let mut cpu = cpu_with_program(0x0200, &[op.code, 0x10, 0x00]);
+      assert_eq!(
+        step_cycles(&mut cpu),
+        op.cycles as usize,
+        "{} {:02X}",
+        op.name,
+        op.code
+      );
+    }
+  }
+
+  #[test]
+  fn test_page_cross_cycles() {
+    // LDA $02FF,X
+    let mut cpu = cpu_with_program(0x0200, &[0xBD, 0xFF, 0x02]);
+    cpu.register_x = 1;
+    assert_eq!(step_cycles(&mut cpu), 5);
+
+    // STA $0200,X はまたがなくても5サイクル
+    let mut cpu = cpu_with_program(0x0200, &[0x9D, 0x00, 0x02]);
+    assert_eq!(step_cycles(&mut cpu), 5);
+
+    // LDA ($10),Y
+    let mut cpu = cpu_with_program(0x0200, &[0xB1, 0x10]);
+    cpu.mem_write(0x10, 0xFF);
+    cpu.mem_write(0x11, 0x02);
+    cpu.register_y = 1;
+    assert_eq!(step_cycles(&mut cpu), 6);
+  }
+
+  #[test]
+  fn test_branch_cycles() {
+    // BNE: 分岐しない / 同じページ / ページをまたぐ
+    let mut cpu = cpu_with_program(0x0200, &[0xD0, 0x10]);
+    cpu.status |= FLAG_ZERO;
+    assert_eq!(step_cycles(&mut cpu), 2);
+
+    let mut cpu = cpu_with_program(0x0200, &[0xD0, 0x10]);
+    assert_eq!(step_cycles(&mut cpu), 3);
+    assert_eq!(cpu.program_counter, 0x0212);
+
+    let mut cpu = cpu_with_program(0x02F0, &[0xD0, 0x20]);
+    assert_eq!(step_cycles(&mut cpu), 4);
+    assert_eq!(cpu.program_counter, 0x0312);
+  }
+
+  #[test]
+  fn test_oam_dma_cycles() {
+    // STA $4014
+    let mut cpu = cpu_with_program(0x0200, &[0x8D, 0x14, 0x40]);
+    let cycles = step_cycles(&mut cpu);
+    assert!(cycles == 4 + 513 || cycles == 4 + 514, "{}", cycles);
+  }
+
+  #[test]
+  fn test_unofficial_immediate() {
+    // ANC #$81
+    let mut cpu = cpu_with_program(0x0200, &[0x0B, 0x81]);
+    cpu.register_a = 0xF0;
+    cpu.step();
+    assert_eq!(cpu.register_a, 0x80);
+    assert_eq!(
+      cpu.status & (FLAG_CARRY | FLAG_NEGATICE),
+      FLAG_CARRY | FLAG_NEGATICE
+    );
+
+    // ALR #$03
+    let mut cpu = cpu_with_program(0x0200, &[0x4B, 0x03]);
+    cpu.register_a = 0xFF;
+    cpu.step();
+    assert_eq!(cpu.register_a, 0x01);
+    assert_eq!(cpu.status & FLAG_CARRY, FLAG_CARRY);
+
+    // ARR #$FF (C=1)
+    let mut cpu = cpu_with_program(0x0200, &[0x6B, 0xFF]);
+    cpu.register_a = 0x40;
+    cpu.status |= FLAG_CARRY;
+    cpu.step();
+    assert_eq!(cpu.register_a, 0xA0);
+    assert_eq!(cpu.status & (FLAG_CARRY | FLAG_OVERFLOW), FLAG_OVERFLOW);
+
+    // SBX #$02
+    let mut cpu = cpu_with_program(0x0200, &[0xCB, 0x02]);
+    cpu.register_a = 0x0F;
+    cpu.register_x = 0x05;
+    cpu.step();
+    assert_eq!(cpu.register_x, 0x03);
+    assert_eq!(cpu.status & FLAG_CARRY, FLAG_CARRY);
+
+    // ANE #$FF
+    let mut cpu = cpu_with_program(0x0200, &[0x8B, 0xFF]);
+    cpu.register_a = 0x00;
+    cpu.register_x = 0x3C;
+    cpu.step();
+    assert_eq!(cpu.register_a, ANE_MAGIC & 0x3C);
+  }
+
+  #[test]
+  fn test_unofficial_read_modify_write() {
+    // SLO $10
+    let mut cpu = cpu_with_program(0x0200, &[0x07, 0x10]);
+    cpu.mem_write(0x10, 0x81);
+    cpu.register_a = 0x01;
+    cpu.step();
+    assert_eq!(cpu.mem_read(0x10), 0x02);
+    assert_eq!(cpu.register_a, 0x03);
+    assert_eq!(cpu.status & FLAG_CARRY, FLAG_CARRY);
+
+    // DCP $10
+    let mut cpu = cpu_with_program(0x0200, &[0xC7, 0x10]);
+    cpu.mem_write(0x10, 0x43);
+    cpu.register_a = 0x42;
+    cpu.step();
+    assert_eq!(cpu.mem_read(0x10), 0x42);
+    assert_eq!(
+      cpu.status & (FLAG_CARRY | FLAG_ZERO),
+      FLAG_CARRY | FLAG_ZERO
+    );
+
+    // ISB $10 (C=1)
+    let mut cpu = cpu_with_program(0x0200, &[0xE7, 0x10]);
+    cpu.mem_write(0x10, 0x0F);
+    cpu.register_a = 0x20;
+    cpu.status |= FLAG_CARRY;
+    cpu.step();
+    assert_eq!(cpu.mem_read(0x10), 0x10);
+    assert_eq!(cpu.register_a, 0x10);
+
+    // SAX $10
+    let mut cpu = cpu_with_program(0x0200, &[0x87, 0x10]);
+    cpu.register_a = 0xF0;
+    cpu.register_x = 0x3C;
+    cpu.step();
+    assert_eq!(cpu.mem_read(0x10), 0x30);
+  }
+
+  #[test]
+  fn test_shx_high_byte() {
+    // SHX $0300,Y
+    let mut cpu = cpu_with_program(0x0200, &[0x9E, 0x00, 0x03]);
+    cpu.register_x = 0xFF;
+    cpu.register_y = 0x10;
+    cpu.step();
+    assert_eq!(cpu.mem_read(0x0310), 0x04);
+
+    // ページをまたぐと書き込み先の上位バイトが書き込む値(X&$02)に化ける
+    // SHX $01F0,Y → $0220 ではなく $0020
+    let mut cpu = cpu_with_program(0x0400, &[0x9E, 0xF0, 0x01]);
+    cpu.mem_write(0x0020, 0xAA);
+    cpu.mem_write(0x0220, 0xAA);
+    cpu.register_x = 0x05;
+    cpu.register_y = 0x30;
+    cpu.step();
+    assert_eq!(cpu.mem_read(0x0020), 0x00);
+    assert_eq!(cpu.mem_read(0x0220), 0xAA);
+  }
+
+  #[test]
+  fn test_jam_halts_until_reset() {
+    let mut cpu = cpu_with_program(0x0200, &[0x02, 0xE8]);
+    cpu.step();
+    assert!(cpu.is_jammed());
+    for _ in 0..10 {
+      assert_eq!(step_cycles(&mut cpu), 1);
+    }
+    assert_eq!(cpu.program_counter, 0x0200);
+    assert_eq!(cpu.register_x, 0);
+
+    cpu.reset();
+    assert!(!cpu.is_jammed());
+  }
+
+  // $0200: JMP $0200 でフレームIRQが立つまで回す
+  // IRQハンドラ($0300)は LDA $4015 / INX / RTI
+  fn cpu_with_frame_irq() -> CPU {
+    let mut cpu = cpu_with_program(0x0200, &[0x4C, 0x00, 0x02]);
+    for (i, value) in [0xAD, 0x15, 0x40, 0xE8, 0x40].iter().enumerate() {
+      cpu.mem_write(0x0300 + i as u16, *value);
+    }
+    while !cpu.bus.irq_sources().contains(IrqSource::APU_FRAME) {
+      cpu.step();
+    }
+    cpu
+  }
+
+  #[test]
+  fn test_frame_irq() {
+    let mut cpu = cpu_with_frame_irq();
+    // Iフラグが立っている間は割り込まない
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0200);
+
+    // CLIの直後の1命令は割り込まれない
+    cpu.mem_write(0x0210, 0x58);
+    cpu.mem_write(0x0211, 0x4C);
+    cpu.mem_write(0x0212, 0x11);
+    cpu.mem_write(0x0213, 0x02);
+    cpu.program_counter = 0x0210;
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0211);
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0211);
+
+    // 割り込んでハンドラの最初の命令($4015を読んでIRQを下ろす)まで進む
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0303);
+    assert_eq!(cpu.register_a, 0x40);
+    assert!(cpu.bus.irq_sources().is_empty());
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.register_x, 1);
+    assert_eq!(cpu.program_counter, 0x0211);
+  }
+
+  #[test]
+  fn test_irq_after_sei() {
+    // SEIの直後は割り込まれ、積まれたステータスはIが立っている
+    let mut cpu = cpu_with_frame_irq();
+    cpu.mem_write(0x0210, 0x78);
+    cpu.program_counter = 0x0210;
+    cpu.status &= !FLAG_INTERRUPT;
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0211);
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0303);
+    let status = cpu.mem_read(0x0100 + cpu.stack_pointer.wrapping_add(1) as u16);
+    assert_eq!(status & (FLAG_INTERRUPT | FLAG_BREAK), FLAG_INTERRUPT);
+  }
+
+  #[test]
+  fn test_brk() {
+    let mut cpu = cpu_with_program(0x0200, &[0x00, 0xFF]);
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0300);
+    // BRKの次の1バイトを飛ばした所に戻る
+    assert_eq!(cpu.mem_read(0x01FD), 0x02);
+    assert_eq!(cpu.mem_read(0x01FC), 0x02);
+    assert_eq!(cpu.mem_read(0x01FB) & FLAG_BREAK, FLAG_BREAK);
+  }
+
+  #[test]
+  fn test_nmi_hijacks_brk() {
+    let mut cpu = cpu_with_program(0x0200, &[0x00, 0xFF]);
+    cpu.nmi_pending = true;
+    cpu.step();
+    assert_eq!(cpu.program_counter, 0x0400);
+    assert!(!cpu.nmi_pending);
+    // Bフラグは立ったまま
+    assert_eq!(cpu.mem_read(0x01FB) & FLAG_BREAK, FLAG_BREAK);
+  }
+
+  #[test]
+  fn test_jsr_rts_cycles() {
+    // JSR $0300 / RTS
+    let mut cpu = cpu_with_program(0x0200, &[0x20, 0x00, 0x03]);
+    cpu.mem_write(0x0300, 0x60);
+    assert_eq!(step_cycles(&mut cpu), 6);
+    assert_eq!(cpu.program_counter, 0x0300);
+    assert_eq!(step_cycles(&mut cpu), 6);
+    assert_eq!(cpu.program_counter, 0x0203);
+  }
+
+  #[test]
+  fn test_trace_has_no_side_effects() {
+    // LDA $4016 / LDA $4016
+    let mut cpu = cpu_with_program(0x0200, &[0xAD, 0x16, 0x40, 0xAD, 0x16, 0x40]);
+    cpu.mem_write(0x4016, 1);
+    cpu.mem_write(0x4016, 0);
+    cpu.bus.joypad1().set_buttons(JoypadButton::BUTTON_A);
+
+    // トレースで読んでもコントローラーのシフトレジスタは進まない
+    trace(&cpu);
+    trace(&cpu);
+    cpu.step();
+    assert_eq!(cpu.register_a, 1);
+    trace(&cpu);
+    cpu.step();
+    assert_eq!(cpu.register_a, 0);
+  }
+
+  // nestest.nesはリポジトリに含まれていないので、置いてから
+  // cargo test -- --ignored で実行する
+  #[test]
+  #[ignore = "requires rom/nestest.nes"]
+  fn test_nestest() {
+    let rom = test_rom().expect("rom/nestest.nes is required");
+    let log = std::fs::read_to_string("nestest.log").unwrap();
+
+    let (apu, _) = NesAPU::new(44100.0);
+    let mut cpu = CPU::new(Bus::new(rom, apu, |_, _| {}));
+    cpu.reset();
+    // 自動テストモードは$C000から始まる
+    cpu.program_counter = 0xC000;
+
+    for (i, expected) in log.lines().enumerate() {
+      let mut actual = String::new();
+      cpu.step_with_callback(|cpu| actual = trace(cpu));
+      assert_eq!(
+        actual,
+        expected.trim_end(),
+        "nestest.log diverged at line {}",
+        i + 1
+      );
+    }
+  }
+
+  #[test]
+  fn test_format_trace() {
+    let mut cpu = cpu_with_program(0x64, &[0xa2, 0x01, 0xca, 0x88, 0x00]);
+    cpu.register_a = 1;
+    cpu.register_x = 2;
+    cpu.register_y = 3;
+    let mut result: Vec<String> = vec![];
+    for _ in 0..3 {
+      cpu.step_with_callback(|cpu| {
+        result.push(trace(cpu));
+      });
+    }
+    assert_eq!(
+      "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
+      result[0]
+    );
+    assert_eq!(
+      "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  6 CYC:2",
+      result[1]
+    );
+    assert_eq!(
+      "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 12 CYC:4",
+      result[2]
+    );
+  }
+
+  #[test]
+  fn test_format_mem_access() {
+    // ORA ($33), Y
+    let mut cpu = cpu_with_program(0x64, &[0x11, 0x33]);
+
+    // data
+    cpu.mem_write(0x33, 0x00);
+    cpu.mem_write(0x34, 0x04);
+
+    // target cell
+    cpu.mem_write(0x400, 0xAA);
+
+    cpu.register_y = 0;
+    assert_eq!(
+      "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+      trace(&cpu)
+    );
+  }
+
+  // ROMもPPUも持たない64KBのRAMだけのバス
+  // アクセスごとに進んだサイクルだけ数える
+  struct TestMem {
+    memory: Vec<u8>,
+    cycles: usize,
+  }
+
+  impl TestMem {
+    fn new() -> Self {
+      TestMem {
+        memory: vec![0; 0x10000],
+        cycles: 0,
+      }
+    }
+  }
+
+  impl Mem for TestMem {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+      self.memory[addr as usize]
+    }
+    fn mem_write(&mut self, addr: u16, data: u8) {
+      self.memory[addr as usize] = data;
+    }
+    fn mem_peek(&self, addr: u16) -> u8 {
+      self.memory[addr as usize]
+    }
+    fn tick(&mut self, cycle: u8) {
+      self.cycles += cycle as usize;
+    }
+  }
+
+  // $8000にプログラムを置いてリセットし、fで準備してからBRK(0x00)まで実行する
+  // BRKは割り込みに入らず、読んだところで止める
+  fn run<F>(program: Vec<u8>, f: F) -> CPU<TestMem>
   where
-  F: Fn(&mut CPU),
+    F: Fn(&mut CPU<TestMem>),
   {
-    let mut cpu = CPU::new(Bus::new(Rom::empty(), move |&NesPPU| {}));
-    cpu.load();
+    let mut mem = TestMem::new();
+    mem.memory[0x8000..0x8000 + program.len()].copy_from_slice(&program);
+    mem.memory[0xFFFD] = 0x80;
+    let mut cpu = CPU::new(mem);
     cpu.reset();
+    // フラグは全て下りた、スタックが空の状態から始める
+    cpu.status = 0;
+    cpu.stack_pointer = 0xFF;
 
     f(&mut cpu);
-    cpu.run();
-
-    cpu
+    for _ in 0..1000 {
+      if cpu.mem_peek(cpu.program_counter) == 0x00 {
+        cpu.program_counter += 1;
+        return cpu;
+      }
+      cpu.step();
+    }
+    panic!("program did not reach BRK");
   }
-  */
 
-  fn assert_status(cpu: &CPU, flag: u8) {
+  fn assert_status<M: Mem>(cpu: &CPU<M>, flag: u8) {
     assert_eq!(cpu.status, flag);
   }
 
-  /*
   // LDA
   #[test]
   // LDAを呼ぶテスト
@@ -1280,7 +1979,7 @@ mod test {
   #[test]
   // ネガティブフラグが正常に立つかのテスト
   fn test_0xa9_lda_negative_flag() {
-    let cpu = run(vec![0xa, 0x80, 0x00], |_| {});
+    let cpu = run(vec![0xa9, 0x80, 0x00], |_| {});
     assert_status(&cpu, FLAG_NEGATICE);
   }
 
@@ -1366,7 +2065,7 @@ mod test {
     let cpu = run(vec![0x85, 0x10, 0x00], |cpu| {
       cpu.register_a = 0xAF;
     });
-    assert_eq!(cpu.mem_read(0x10), 0xAF);
+    assert_eq!(cpu.mem_peek(0x10), 0xAF);
   }
 
   #[test]
@@ -1555,14 +2254,14 @@ mod test {
     let cpu = run(vec![0x06, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x03);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x03 * 2);
+    assert_eq!(cpu.mem_peek(0x0001), 0x03 * 2);
   }
   #[test]
   fn test_asl_zero_page_carry() {
     let cpu = run(vec![0x06, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x83);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x03 * 2);
+    assert_eq!(cpu.mem_peek(0x0001), 0x03 * 2);
     assert_status(&cpu, FLAG_CARRY);
   }
   #[test]
@@ -1578,7 +2277,7 @@ mod test {
     let cpu = run(vec![0x46, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x02);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x02 / 2);
+    assert_eq!(cpu.mem_peek(0x0001), 0x02 / 2);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -1594,7 +2293,7 @@ mod test {
     let cpu = run(vec![0x46, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x03);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x01);
+    assert_eq!(cpu.mem_peek(0x0001), 0x01);
     assert_status(&cpu, FLAG_CARRY);
   }
   #[test]
@@ -1602,7 +2301,7 @@ mod test {
     let cpu = run(vec![0x46, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x01);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x00);
+    assert_eq!(cpu.mem_peek(0x0001), 0x00);
     assert_status(&cpu, FLAG_CARRY | FLAG_ZERO);
   }
   #[test]
@@ -1618,7 +2317,7 @@ mod test {
     let cpu = run(vec![0x26, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x03);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x06);
+    assert_eq!(cpu.mem_peek(0x0001), 0x06);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -1636,7 +2335,7 @@ mod test {
       cpu.mem_write(0x0001, 0x03);
       cpu.status = FLAG_CARRY;
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x07);
+    assert_eq!(cpu.mem_peek(0x0001), 0x07);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -1654,7 +2353,7 @@ mod test {
       cpu.mem_write(0x0001, 0x00);
       cpu.status = FLAG_CARRY;
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x01);
+    assert_eq!(cpu.mem_peek(0x0001), 0x01);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -1670,7 +2369,7 @@ mod test {
     let cpu = run(vec![0x66, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x02);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x02 / 2);
+    assert_eq!(cpu.mem_peek(0x0001), 0x02 / 2);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -1686,7 +2385,7 @@ mod test {
     let cpu = run(vec![0x66, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x0001, 0x03);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x01);
+    assert_eq!(cpu.mem_peek(0x0001), 0x01);
     assert_status(&cpu, FLAG_CARRY);
   }
   #[test]
@@ -1704,7 +2403,7 @@ mod test {
       cpu.mem_write(0x0001, 0x02);
       cpu.status = FLAG_CARRY;
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x81);
+    assert_eq!(cpu.mem_peek(0x0001), 0x81);
     assert_status(&cpu, FLAG_NEGATICE);
   }
   #[test]
@@ -1722,7 +2421,7 @@ mod test {
       cpu.mem_write(0x0001, 0x00);
       cpu.status = FLAG_CARRY;
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x80);
+    assert_eq!(cpu.mem_peek(0x0001), 0x80);
     assert_status(&cpu, FLAG_NEGATICE);
   }
   #[test]
@@ -1991,7 +2690,7 @@ mod test {
     let cpu = run(vec![0xC6, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x01, 0x05);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x04);
+    assert_eq!(cpu.mem_peek(0x0001), 0x04);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -1999,7 +2698,7 @@ mod test {
     let cpu = run(vec![0xC6, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x01, 0x00);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0xFF);
+    assert_eq!(cpu.mem_peek(0x0001), 0xFF);
     assert_status(&cpu, FLAG_NEGATICE);
   }
   #[test]
@@ -2040,7 +2739,7 @@ mod test {
     let cpu = run(vec![0xE6, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x01, 0x05);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x06);
+    assert_eq!(cpu.mem_peek(0x0001), 0x06);
     assert_status(&cpu, 0);
   }
   #[test]
@@ -2048,7 +2747,7 @@ mod test {
     let cpu = run(vec![0xE6, 0x01, 0x00], |cpu| {
       cpu.mem_write(0x01, 0x7F);
     });
-    assert_eq!(cpu.mem_read(0x0001), 0x80);
+    assert_eq!(cpu.mem_peek(0x0001), 0x80);
     assert_status(&cpu, FLAG_NEGATICE);
   }
   #[test]
@@ -2099,7 +2798,6 @@ mod test {
     let cpu = run(vec![0x6C, 0x30, 0x40, 0x00], |cpu| {
       cpu.mem_write(0x4030, 0x80);
       cpu.mem_write(0x4031, 0x00);
-      println!("{:?}", cpu.program_counter);
       cpu.mem_write(0x8000, 0xE8);
       cpu.mem_write(0x8001, 0x00);
     });
@@ -2108,6 +2806,58 @@ mod test {
     assert_status(&cpu, 0);
   }
   #[test]
+  fn test_jmp_indirect_page_wrap() {
+    // JMP ($40FF) は上位バイトを$4100ではなく$4000から読む
+    let cpu = run(vec![0x6C, 0xFF, 0x40], |cpu| {
+      cpu.mem_write(0x40FF, 0x00);
+      cpu.mem_write(0x4000, 0x90);
+      cpu.mem_write(0x4100, 0x50);
+    });
+    assert_eq!(cpu.program_counter, 0x9001);
+  }
+  #[test]
+  fn test_zero_page_x_wrap() {
+    // LDA $F0,X はゼロページ内で折り返す
+    let cpu = run(vec![0xb5, 0xF0, 0x00], |cpu| {
+      cpu.register_x = 0x20;
+      cpu.mem_write(0x0010, 0x42);
+      cpu.mem_write(0x0110, 0x99);
+    });
+    assert_eq!(cpu.register_a, 0x42);
+  }
+  #[test]
+  fn test_indirect_x_pointer_wrap() {
+    // LDA ($FE,X) X=1 でポインタは$FFと$00
+    let cpu = run(vec![0xa1, 0xFE, 0x00], |cpu| {
+      cpu.register_x = 0x01;
+      cpu.mem_write(0x00FF, 0x34);
+      cpu.mem_write(0x0000, 0x12);
+      cpu.mem_write(0x0100, 0x56);
+      cpu.mem_write(0x1234, 0x77);
+    });
+    assert_eq!(cpu.register_a, 0x77);
+  }
+  #[test]
+  fn test_indirect_y_pointer_wrap() {
+    // LDA ($FF),Y のポインタの上位バイトは$00から読む
+    let cpu = run(vec![0xb1, 0xFF, 0x00], |cpu| {
+      cpu.register_y = 0x01;
+      cpu.mem_write(0x00FF, 0x33);
+      cpu.mem_write(0x0000, 0x12);
+      cpu.mem_write(0x1234, 0x88);
+    });
+    assert_eq!(cpu.register_a, 0x88);
+  }
+  #[test]
+  fn test_test_mem_cycles() {
+    // LDA $12FF,X (ページまたぎ) + BRKの手前まで
+    let cpu = run(vec![0xbd, 0xFF, 0x12, 0x00], |cpu| {
+      cpu.register_x = 0x01;
+      cpu.bus.cycles = 0;
+    });
+    assert_eq!(cpu.bus.cycles, 5);
+  }
+  #[test]
   fn test_jsr() {
     let cpu = run(vec![0x20, 0x30, 0x40, 0x00], |cpu| {
       cpu.mem_write(0x4030, 0xE8);
@@ -2116,14 +2866,17 @@ mod test {
     assert_eq!(cpu.register_x, 0x01);
     assert_status(&cpu, 0);
     assert_eq!(cpu.program_counter, 0x4032);
-    assert_eq!(cpu.mem_read_u16(0x01FE), 0x8003);
+    // JSRは次の命令の1つ手前のアドレスを積む
+    assert_eq!(cpu.mem_peek(0x01FE), 0x02);
+    assert_eq!(cpu.mem_peek(0x01FF), 0x80);
   }
 
   #[test]
   fn test_rts() {
     let cpu = run(vec![0x60, 0x00], |cpu| {
+      // RTSは積まれたアドレス+1に戻る
       cpu.mem_write(0x01FF, 0x05);
-      cpu.mem_write(0x01FE, 0x06);
+      cpu.mem_write(0x01FE, 0x05);
 
       cpu.mem_write(0x0506, 0xe8);
       cpu.mem_write(0x0507, 0x00);
@@ -2171,7 +2924,7 @@ mod test {
     assert_eq!(cpu.program_counter, 0x8002);
     assert_eq!(cpu.register_a, 0x07);
     assert_eq!(cpu.stack_pointer, 0xFE);
-    assert_eq!(cpu.mem_read(0x01FF), 0x07);
+    assert_eq!(cpu.mem_peek(0x01FF), 0x07);
   }
 
   #[test]
@@ -2214,7 +2967,11 @@ mod test {
     });
     assert_eq!(cpu.program_counter, 0x8002);
     assert_eq!(cpu.stack_pointer, 0xFE);
-    assert_eq!(cpu.mem_read(0x01FF), FLAG_NEGATICE | FLAG_OVERFLOW);
+    // 積まれる値はBフラグとビット5が立つ
+    assert_eq!(
+      cpu.mem_peek(0x01FF),
+      FLAG_NEGATICE | FLAG_OVERFLOW | FLAG_BREAK | FLAG_BREAK2
+    );
   }
   #[test]
   fn test_plp() {
@@ -2223,7 +2980,8 @@ mod test {
       cpu.stack_pointer = 0xFE;
     });
     assert_eq!(cpu.program_counter, 0x8002);
-    assert_status(&cpu, FLAG_CARRY | FLAG_ZERO);
+    // ビット5は常に1
+    assert_status(&cpu, FLAG_CARRY | FLAG_ZERO | FLAG_BREAK2);
     assert_eq!(cpu.stack_pointer, 0xFF);
   }
   #[test]
@@ -2232,7 +2990,7 @@ mod test {
       cpu.status = FLAG_OVERFLOW | FLAG_CARRY;
     });
     assert_eq!(cpu.program_counter, 0x8005);
-    assert_status(&cpu, FLAG_OVERFLOW | FLAG_CARRY);
+    assert_status(&cpu, FLAG_OVERFLOW | FLAG_CARRY | FLAG_BREAK2);
     assert_eq!(cpu.stack_pointer, 0xFF);
   }
 
@@ -2241,14 +2999,14 @@ mod test {
     let cpu = run(vec![0x86, 0x10, 0x00], |cpu| {
       cpu.register_x = 0xBA;
     });
-    assert_eq!(cpu.mem_read(0x10), 0xBA);
+    assert_eq!(cpu.mem_peek(0x10), 0xBA);
   }
   #[test]
   fn test_sty() {
     let cpu = run(vec![0x84, 0x10, 0x00], |cpu| {
       cpu.register_y = 0xBA;
     });
-    assert_eq!(cpu.mem_read(0x10), 0xBA);
+    assert_eq!(cpu.mem_peek(0x10), 0xBA);
   }
   #[test]
   fn test_txa() {
@@ -2292,5 +3050,4 @@ mod test {
     });
     assert_eq!(cpu.stack_pointer, 0x80);
   }
-   */
 }
@@ -0,0 +1,4 @@
+// SDLを使うmainバイナリ側の処理
+pub mod args;
+pub mod audio;
+pub mod slots;
@@ -0,0 +1,249 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use nes_emu::region::Region;
+
+pub const USAGE: &str = "\
+Usage: main [OPTIONS] <ROM>
+
+Options:
+  -s, --scale <N>           window scale factor (default: 2)
+  -r, --region <REGION>     auto, ntsc, pal or dendy (default: auto = ROM header)
+      --no-audio            disable sound output
+      --no-sprite-limit     show more than 8 sprites per line (less flicker)
+  -p, --palette <FILE>      load a .pal palette, can be given more than once
+                            (F9 cycles through them and the built-in palettes)
+  -t, --trace <FILE>        write a CPU trace log to FILE
+  -l, --load-state <FILE>   start from a save state
+      --rewind-interval <N> take a rewind snapshot every N frames (default: 2)
+      --rewind-memory <MB>  memory for the rewind buffer, 0 disables (default: 64)
+  -h, --help                print this help";
+
+// mainバイナリのコマンドライン引数
+#[derive(Debug, PartialEq)]
+pub struct Args {
+  pub rom_path: String,
+  pub scale: u32,
+  // Noneの場合はROMヘッダーに従う
+  pub region: Option<Region>,
+  pub audio: bool,
+  pub sprite_limit: bool,
+  // 最初のものを起動時に使う
+  pub palettes: Vec<PathBuf>,
+  pub trace_log: Option<PathBuf>,
+  pub load_state: Option<PathBuf>,
+  pub rewind_interval: usize,
+  // MB単位
+  pub rewind_memory: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArgsError {
+  Help,
+  MissingRom,
+  MissingValue(String),
+  InvalidValue { option: String, value: String },
+  UnknownOption(String),
+  UnexpectedArgument(String),
+}
+
+impl fmt::Display for ArgsError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ArgsError::Help => write!(f, "{}", USAGE),
+      ArgsError::MissingRom => write!(f, "no ROM file given\n\n{}", USAGE),
+      ArgsError::MissingValue(option) => write!(f, "{} requires a value", option),
+      ArgsError::InvalidValue { option, value } => {
+        write!(f, "invalid value for {}: {}", option, value)
+      }
+      ArgsError::UnknownOption(option) => write!(f, "unknown option: {}\n\n{}", option, USAGE),
+      ArgsError::UnexpectedArgument(arg) => write!(f, "unexpected argument: {}", arg),
+    }
+  }
+}
+
+impl std::error::Error for ArgsError {}
+
+impl Args {
+  // 先頭のプログラム名は除いて渡す
+  pub fn parse<I>(args: I) -> Result<Args, ArgsError>
+  where
+    I: IntoIterator<Item = String>,
+  {
+    let mut rom_path = None;
+    let mut scale = 2;
+    let mut region = None;
+    let mut audio = true;
+    let mut sprite_limit = true;
+    let mut palettes = vec![];
+    let mut trace_log = None;
+    let mut load_state = None;
+    let mut rewind_interval = 2;
+    let mut rewind_memory = 64;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      // --scale=3 の形式も受け付ける
+      let (option, inline_value) = match arg.split_once('=') {
+        Some((option, value)) if arg.starts_with("--") => (option.to_string(), Some(value)),
+        _ => (arg.clone(), None),
+      };
+      let mut value = || match inline_value {
+        Some(value) => Ok(value.to_string()),
+        None => args
+          .next()
+          .ok_or_else(|| ArgsError::MissingValue(option.clone())),
+      };
+
+      match option.as_str() {
+        "-h" | "--help" => return Err(ArgsError::Help),
+        "-s" | "--scale" => {
+          let value = value()?;
+          scale = match value.parse::<u32>() {
+            Ok(scale) if scale > 0 => scale,
+            _ => return Err(invalid(&option, value)),
+          };
+        }
+        "-r" | "--region" => {
+          let value = value()?;
+          region = match value.to_ascii_lowercase().as_str() {
+            "auto" => None,
+            "ntsc" => Some(Region::NTSC),
+            "pal" => Some(Region::PAL),
+            "dendy" => Some(Region::DENDY),
+            _ => return Err(invalid(&option, value)),
+          };
+        }
+        "--no-audio" => audio = false,
+        "--no-sprite-limit" => sprite_limit = false,
+        "-p" | "--palette" => palettes.push(PathBuf::from(value()?)),
+        "-t" | "--trace" => trace_log = Some(PathBuf::from(value()?)),
+        "-l" | "--load-state" => load_state = Some(PathBuf::from(value()?)),
+        "--rewind-interval" => {
+          let value = value()?;
+          rewind_interval = match value.parse::<usize>() {
+            Ok(interval) if interval > 0 => interval,
+            _ => return Err(invalid(&option, value)),
+          };
+        }
+        "--rewind-memory" => {
+          let value = value()?;
+          rewind_memory = match value.parse::<usize>() {
+            Ok(memory) => memory,
+            _ => return Err(invalid(&option, value)),
+          };
+        }
+        _ if option.starts_with('-') && option.len() > 1 => {
+          return Err(ArgsError::UnknownOption(option))
+        }
+        _ => {
+          if rom_path.is_some() {
+            return Err(ArgsError::UnexpectedArgument(arg));
+          }
+          rom_path = Some(arg);
+        }
+      }
+    }
+
+    Ok(Args {
+      rom_path: rom_path.ok_or(ArgsError::MissingRom)?,
+      scale: scale,
+      region: region,
+      audio: audio,
+      sprite_limit: sprite_limit,
+      palettes: palettes,
+      trace_log: trace_log,
+      load_state: load_state,
+      rewind_interval: rewind_interval,
+      rewind_memory: rewind_memory,
+    })
+  }
+}
+
+fn invalid(option: &str, value: String) -> ArgsError {
+  ArgsError::InvalidValue {
+    option: option.to_string(),
+    value: value,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+    Args::parse(args.iter().map(|arg| arg.to_string()))
+  }
+
+  #[test]
+  fn test_defaults() {
+    let args = parse(&["game.nes"]).unwrap();
+    assert_eq!(args.rom_path, "game.nes");
+    assert_eq!(args.scale, 2);
+    assert_eq!(args.region, None);
+    assert!(args.audio);
+    assert!(args.sprite_limit);
+    assert!(args.palettes.is_empty());
+    assert_eq!(args.trace_log, None);
+    assert_eq!(args.load_state, None);
+    assert_eq!(args.rewind_interval, 2);
+    assert_eq!(args.rewind_memory, 64);
+  }
+
+  #[test]
+  fn test_options() {
+    let args = parse(&[
+      "--scale=3",
+      "-r",
+      "PAL",
+      "--no-audio",
+      "--no-sprite-limit",
+      "-p",
+      "a.pal",
+      "--palette=b.pal",
+      "game.nes",
+      "-t",
+      "trace.log",
+      "--load-state",
+      "game.state",
+      "--rewind-interval",
+      "1",
+      "--rewind-memory=0",
+    ])
+    .unwrap();
+    assert_eq!(args.rom_path, "game.nes");
+    assert_eq!(args.scale, 3);
+    assert_eq!(args.region, Some(Region::PAL));
+    assert!(!args.audio);
+    assert!(!args.sprite_limit);
+    assert_eq!(
+      args.palettes,
+      vec![PathBuf::from("a.pal"), PathBuf::from("b.pal")]
+    );
+    assert_eq!(args.trace_log, Some(PathBuf::from("trace.log")));
+    assert_eq!(args.load_state, Some(PathBuf::from("game.state")));
+    assert_eq!(args.rewind_interval, 1);
+    assert_eq!(args.rewind_memory, 0);
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!(parse(&[]), Err(ArgsError::MissingRom));
+    assert_eq!(
+      parse(&["game.nes", "--scale"]),
+      Err(ArgsError::MissingValue("--scale".to_string()))
+    );
+    assert!(matches!(
+      parse(&["game.nes", "--scale", "0"]),
+      Err(ArgsError::InvalidValue { .. })
+    ));
+    assert_eq!(
+      parse(&["game.nes", "--fast"]),
+      Err(ArgsError::UnknownOption("--fast".to_string()))
+    );
+    assert_eq!(
+      parse(&["a.nes", "b.nes"]),
+      Err(ArgsError::UnexpectedArgument("b.nes".to_string()))
+    );
+  }
+}
@@ -0,0 +1,32 @@
+use nes_emu::nes::SAMPLE_RATE;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+// 遅れが溜まらないように、これ以上キューに入っていたら捨てる(約0.1秒)
+const MAX_QUEUED_SAMPLES: u32 = SAMPLE_RATE as u32 / 10;
+
+pub fn open(sdl_context: &sdl2::Sdl) -> AudioQueue<f32> {
+  let audio_subsystem = sdl_context.audio().unwrap();
+
+  let desired_spec = AudioSpecDesired {
+    freq: Some(SAMPLE_RATE as i32),
+    channels: Some(1),
+    samples: None,
+  };
+
+  let queue = audio_subsystem
+    .open_queue::<f32, _>(None, &desired_spec)
+    .unwrap();
+
+  queue.resume();
+  queue
+}
+
+pub fn queue(queue: &AudioQueue<f32>, samples: &[f32]) {
+  let queued = queue.size() / std::mem::size_of::<f32>() as u32;
+  if queued > MAX_QUEUED_SAMPLES {
+    return;
+  }
+  if let Err(err) = queue.queue_audio(samples) {
+    log::warn!("failed to queue audio: {}", err);
+  }
+}
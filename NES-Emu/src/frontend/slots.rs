@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+use nes_emu::nes::Nes;
+use nes_emu::savestate::StateError;
+
+// 0-9のステートスロット
+// ROMと同じ場所に拡張子.ss0~.ss9で保存する
+pub struct StateSlots {
+  rom_path: PathBuf,
+  current: u8,
+}
+
+impl StateSlots {
+  pub fn new(rom_path: &str) -> Self {
+    StateSlots {
+      rom_path: PathBuf::from(rom_path),
+      current: 0,
+    }
+  }
+
+  pub fn current(&self) -> u8 {
+    self.current
+  }
+
+  pub fn select(&mut self, slot: u8) {
+    assert!(slot < 10);
+    self.current = slot;
+  }
+
+  pub fn path(&self) -> PathBuf {
+    self.rom_path.with_extension(format!("ss{}", self.current))
+  }
+
+  pub fn save(&self, nes: &Nes) -> Result<PathBuf, StateError> {
+    let path = self.path();
+    std::fs::write(&path, nes.save_state())?;
+    Ok(path)
+  }
+
+  pub fn load(&self, nes: &mut Nes) -> Result<PathBuf, StateError> {
+    let path = self.path();
+    read_state(&path, nes)?;
+    Ok(path)
+  }
+}
+
+pub fn read_state(path: &Path, nes: &mut Nes) -> Result<(), StateError> {
+  let data = std::fs::read(path)?;
+  nes.load_state(&data)
+}
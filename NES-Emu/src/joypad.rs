@@ -1,6 +1,6 @@
 use bitflags::bitflags;
 
-use crate::cpu::IN_TRACE;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 bitflags! {
   #[derive(Clone, Copy)]
@@ -16,6 +16,13 @@ bitflags! {
   }
 }
 
+// コントローラーの端子 (1P: $4016, 2P: $4017)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Player {
+  ONE,
+  TWO,
+}
+
 pub struct Joypad {
   strobe: bool,
   button_index: u8,
@@ -38,19 +45,38 @@ impl Joypad {
   }
 
   pub fn read(&mut self) -> u8 {
-    if self.button_index > 7 {
-      return 1;
-    }
-    let response = (self.button_status.bits() & (1 << self.button_index)) >> self.button_index;
+    let response = self.peek();
     if !self.strobe && self.button_index <= 7 {
-      if !unsafe { IN_TRACE } {
-        self.button_index += 1;
-      }
+      self.button_index += 1;
     }
     response
   }
 
+  // 読み込み位置を進めずに次に読まれる値を返す
+  pub fn peek(&self) -> u8 {
+    if self.button_index > 7 {
+      return 1;
+    }
+    (self.button_status.bits() & (1 << self.button_index)) >> self.button_index
+  }
+
   pub fn set_button_pressed_status(&mut self, button: JoypadButton, value: bool) {
     self.button_status.set(button, value)
   }
+
+  pub fn set_buttons(&mut self, buttons: JoypadButton) {
+    self.button_status = buttons;
+  }
+
+  // ボタンの状態は入力なので含めない
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_bool(self.strobe);
+    writer.write_u8(self.button_index);
+  }
+
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.strobe = reader.read_bool()?;
+    self.button_index = reader.read_u8()?;
+    Ok(())
+  }
 }
@@ -0,0 +1,22 @@
+// エミュレータのコア部分
+// SDLには依存しないので、ヘッドレスのテストやツールからも使える
+#[macro_use]
+extern crate lazy_static;
+
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod frame;
+pub mod joypad;
+pub mod mapper;
+pub mod nes;
+pub mod opscodes;
+pub mod palette;
+pub mod ppu;
+pub mod region;
+pub mod render;
+pub mod rewind;
+pub mod rom;
+pub mod savestate;
+pub mod testrom;
@@ -1,54 +1,58 @@
 extern crate sdl2;
 
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
 
-use crate::bus::Mem;
-use crate::cpu::{trace, CPU};
-use apu::NesAPU;
-use bus::Bus;
-use cartridge::bomb_sweeper_rom;
-use cartridge::{alter_ego_rom, test_rom};
-use frame::show_tile;
-use frame::Frame;
-use joypad::Joypad;
+use frontend::args::{Args, ArgsError};
+use frontend::audio;
+use frontend::slots::{self, StateSlots};
 use log::trace;
-use ppu::NesPPU;
+use nes_emu::bus::Mem;
+use nes_emu::cartridge::{load_rom, SaveFile};
+use nes_emu::cpu::{trace, CPU};
+use nes_emu::joypad::{JoypadButton, Player};
+use nes_emu::nes::Nes;
+use nes_emu::palette::{NtscParams, Palette};
+use nes_emu::rewind::Rewind;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
 
-#[macro_use]
-extern crate lazy_static;
-
-mod apu;
-mod bus;
-mod cartridge;
-mod cpu;
-mod frame;
-mod joypad;
-mod opscodes;
-mod palette;
-mod ppu;
-mod render;
-mod rom;
+mod frontend;
 
 fn main() {
   env_logger::init();
 
+  let args = match Args::parse(std::env::args().skip(1)) {
+    Ok(args) => args,
+    Err(ArgsError::Help) => {
+      println!("{}", ArgsError::Help);
+      std::process::exit(0);
+    }
+    Err(err) => {
+      eprintln!("{}", err);
+      std::process::exit(2);
+    }
+  };
+
   // init sdl2
   let sdl_context = sdl2::init().unwrap();
   let video_subsystem = sdl_context.video().unwrap();
   let window = video_subsystem
-    .window("Nes Emurator", (256.0 * 2.0) as u32, (240.0 * 2.0) as u32)
+    .window("Nes Emurator", 256 * args.scale, 240 * args.scale)
     .position_centered()
     .build()
     .unwrap();
 
   let mut canvas = window.into_canvas().present_vsync().build().unwrap();
   let mut event_pump = sdl_context.event_pump().unwrap();
-  canvas.set_scale(2.0, 2.0).unwrap();
+  canvas
+    .set_scale(args.scale as f32, args.scale as f32)
+    .unwrap();
 
   let creator = canvas.texture_creator();
   let mut texture = creator
@@ -56,58 +60,226 @@ fn main() {
     .unwrap();
 
   // put CHR_ROM
-  // let rom = bomb_sweeper_rom();
-  let rom = alter_ego_rom();
-  let apu = NesAPU::new(&sdl_context);
-  let mut frame = Frame::new();
+  let rom_path = args.rom_path.as_str();
+  let rom = match load_rom(rom_path) {
+    Ok(rom) => rom,
+    Err(err) => {
+      eprintln!("{}", err);
+      std::process::exit(1);
+    }
+  };
+  let mut save_file = if rom.battery {
+    let mut save_file = SaveFile::new(rom_path, rom.cartridge.clone());
+    if let Err(err) = save_file.load() {
+      eprintln!("failed to load {}: {}", save_file.path().display(), err);
+    }
+    Some(save_file)
+  } else {
+    None
+  };
+  let mut trace_log = match &args.trace_log {
+    Some(path) => match File::create(path) {
+      Ok(file) => Some(BufWriter::new(file)),
+      Err(err) => {
+        eprintln!("failed to create {}: {}", path.display(), err);
+        std::process::exit(1);
+      }
+    },
+    None => None,
+  };
+  let audio_queue = if args.audio {
+    Some(audio::open(&sdl_context))
+  } else {
+    None
+  };
 
   let mut key_map = HashMap::new();
-  key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
-  key_map.insert(Keycode::Up, joypad::JoypadButton::UP);
-  key_map.insert(Keycode::Right, joypad::JoypadButton::RIGHT);
-  key_map.insert(Keycode::Left, joypad::JoypadButton::LEFT);
-  key_map.insert(Keycode::Space, joypad::JoypadButton::SELECT);
-  key_map.insert(Keycode::Return, joypad::JoypadButton::START);
-  key_map.insert(Keycode::A, joypad::JoypadButton::BUTTON_A);
-  key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
-
-  let bus = Bus::new(rom, apu, move |ppu: &NesPPU, joypad1: &mut Joypad| {
-    // println!("***GAME LOOP***");
-    render::render(ppu, &mut frame);
-    texture.update(None, &frame.data, 256 * 3).unwrap();
+  key_map.insert(Keycode::Down, JoypadButton::DOWN);
+  key_map.insert(Keycode::Up, JoypadButton::UP);
+  key_map.insert(Keycode::Right, JoypadButton::RIGHT);
+  key_map.insert(Keycode::Left, JoypadButton::LEFT);
+  key_map.insert(Keycode::Space, JoypadButton::SELECT);
+  key_map.insert(Keycode::Return, JoypadButton::START);
+  key_map.insert(Keycode::A, JoypadButton::BUTTON_A);
+  key_map.insert(Keycode::S, JoypadButton::BUTTON_B);
 
-    canvas.copy(&texture, None, None).unwrap();
+  // 0~9: スロット選択, F5: セーブ, F7: ロード
+  let slot_keys = [
+    Keycode::Num0,
+    Keycode::Num1,
+    Keycode::Num2,
+    Keycode::Num3,
+    Keycode::Num4,
+    Keycode::Num5,
+    Keycode::Num6,
+    Keycode::Num7,
+    Keycode::Num8,
+    Keycode::Num9,
+  ];
+  let mut slots = StateSlots::new(rom_path);
 
+  let mut nes = Nes::new(rom);
+  nes.set_sprite_limit(args.sprite_limit);
+  if let Some(region) = args.region {
+    nes.set_region(region);
+  }
+
+  // F9で切り替えるパレット (指定したファイル、組み込み、NTSCの順)
+  let mut palettes = vec![];
+  for path in &args.palettes {
+    match Palette::load(path) {
+      Ok(palette) => palettes.push((path.display().to_string(), palette)),
+      Err(err) => {
+        eprintln!("failed to load {}: {}", path.display(), err);
+        std::process::exit(1);
+      }
+    }
+  }
+  palettes.push(("built-in".to_string(), Palette::default()));
+  palettes.push(("ntsc".to_string(), Palette::ntsc(&NtscParams::default())));
+  let mut palette_index = 0;
+  nes.set_palette(palettes[palette_index].1.clone());
+  if let Some(path) = &args.load_state {
+    if let Err(err) = slots::read_state(path, &mut nes) {
+      eprintln!("failed to load {}: {}", path.display(), err);
+      std::process::exit(1);
+    }
+  }
+
+  // Backspaceを押している間は巻き戻す
+  let mut rewind = if args.rewind_memory > 0 {
+    Some(Rewind::new(
+      args.rewind_interval,
+      args.rewind_memory * 1024 * 1024,
+    ))
+  } else {
+    None
+  };
+  let mut rewinding = false;
+
+  // vsyncだけだとモニタのリフレッシュレートで回ってしまうので、地域のフレームレートに合わせて待つ
+  let frame_duration = Duration::from_secs_f64(1.0 / nes.region().frame_rate());
+  let mut next_frame = Instant::now();
+
+  let mut buttons = JoypadButton::empty();
+  'running: loop {
+    match (rewind.as_mut(), rewinding) {
+      (Some(rewind), true) => {
+        rewind.rewind(&mut nes);
+      }
+      (rewind, _) => {
+        match trace_log.as_mut() {
+          Some(trace_log) => nes.step_frame_with_callback(|cpu| {
+            if let Err(err) = writeln!(trace_log, "{}", trace(cpu)) {
+              eprintln!("failed to write trace log: {}", err);
+              std::process::exit(1);
+            }
+          }),
+          None => nes.step_frame_with_callback(|cpu| trace!("{}", trace(cpu))),
+        };
+        if let Some(rewind) = rewind {
+          rewind.on_frame(&nes);
+        }
+      }
+    }
+    texture.update(None, &nes.frame().data, 256 * 3).unwrap();
+    canvas.copy(&texture, None, None).unwrap();
     canvas.present();
+
+    let samples = nes.take_audio_samples();
+    if let Some(audio_queue) = &audio_queue {
+      audio::queue(audio_queue, &samples);
+    }
+    if let Some(save_file) = save_file.as_mut() {
+      if let Err(err) = save_file.on_frame() {
+        eprintln!("failed to save {}: {}", save_file.path().display(), err);
+      }
+    }
+
     for event in event_pump.poll_iter() {
       match event {
         Event::Quit { .. }
         | Event::KeyDown {
           keycode: Some(Keycode::Escape),
           ..
-        } => std::process::exit(0),
+        } => break 'running,
 
-        Event::KeyDown { keycode, .. } => {
-          if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-            joypad1.set_button_pressed_status(*key, true);
+        Event::KeyDown {
+          keycode: Some(Keycode::F5),
+          repeat: false,
+          ..
+        } => match slots.save(&nes) {
+          Ok(path) => println!("saved state to {}", path.display()),
+          Err(err) => eprintln!("failed to save state: {}", err),
+        },
+        Event::KeyDown {
+          keycode: Some(Keycode::F7),
+          repeat: false,
+          ..
+        } => match slots.load(&mut nes) {
+          Ok(path) => println!("loaded state from {}", path.display()),
+          Err(err) => eprintln!("failed to load state: {}", err),
+        },
+        Event::KeyDown {
+          keycode: Some(Keycode::F9),
+          repeat: false,
+          ..
+        } => {
+          palette_index = (palette_index + 1) % palettes.len();
+          let (name, palette) = &palettes[palette_index];
+          nes.set_palette(palette.clone());
+          println!("palette {}", name);
+        }
+        Event::KeyDown {
+          keycode: Some(Keycode::Backspace),
+          ..
+        } => rewinding = true,
+        Event::KeyUp {
+          keycode: Some(Keycode::Backspace),
+          ..
+        } => rewinding = false,
+        Event::KeyDown {
+          keycode: Some(keycode),
+          ..
+        } => {
+          if let Some(key) = key_map.get(&keycode) {
+            buttons.insert(*key);
+          } else if let Some(slot) = slot_keys.iter().position(|k| *k == keycode) {
+            slots.select(slot as u8);
+            println!("state slot {}", slots.current());
           }
         }
-        Event::KeyUp { keycode, .. } => {
-          if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-            joypad1.set_button_pressed_status(*key, false);
+        Event::KeyUp {
+          keycode: Some(keycode),
+          ..
+        } => {
+          if let Some(key) = key_map.get(&keycode) {
+            buttons.remove(*key);
           }
         }
         _ => { /* do nothing */ }
       }
     }
-  });
+    nes.set_buttons(Player::ONE, buttons);
 
-  let mut cpu = CPU::new(bus);
+    next_frame += frame_duration;
+    let now = Instant::now();
+    if next_frame > now {
+      std::thread::sleep(next_frame - now);
+    } else {
+      // 遅れた分は取り戻さない
+      next_frame = now;
+    }
+  }
 
-  cpu.reset();
-  cpu.run_with_callback(move |cpu| {
-    trace!("{}", trace(cpu));
-  });
+  if let Some(save_file) = save_file.as_mut() {
+    if let Err(err) = save_file.flush() {
+      eprintln!("failed to save {}: {}", save_file.path().display(), err);
+    }
+  }
+  if let Some(trace_log) = trace_log.as_mut() {
+    let _ = trace_log.flush();
+  }
   /*
   let mut screen_state = [0 as u8; 32 * 3 * 32];
   let mut rng = rand::thread_rng();
@@ -183,7 +355,7 @@ fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
   let mut frame_idx = 0;
   let mut update = false;
   for i in 0x0200..0x600 {
-    let color_idx = cpu.mem_read(i as u16);
+    let color_idx = cpu.mem_peek(i as u16);
     let (b1, b2, b3) = color(color_idx).rgb();
     if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
       frame[frame_idx] = b1;
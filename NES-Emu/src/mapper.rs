@@ -0,0 +1,199 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::mapper::axrom::Axrom;
+use crate::mapper::cnrom::Cnrom;
+use crate::mapper::gxrom::Gxrom;
+use crate::mapper::mmc1::Mmc1;
+use crate::mapper::mmc3::Mmc3;
+use crate::mapper::nrom::Nrom;
+use crate::mapper::uxrom::Uxrom;
+use crate::rom::{Mirroring, RomError};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+mod axrom;
+mod cnrom;
+mod gxrom;
+mod mmc1;
+mod mmc3;
+mod nrom;
+mod uxrom;
+
+// カートリッジ側の回路
+// CPU: $4020-$FFFF, PPU: $0000-$1FFF へのアクセスは全てここを経由する
+pub trait Mapper {
+  fn read_prg(&self, addr: u16) -> u8;
+  // 副作用なしで読む(トレースやデバッガ用)
+  // 読み込みで状態が変わるマッパーだけ上書きする
+  fn peek_prg(&self, addr: u16) -> u8 {
+    self.read_prg(addr)
+  }
+  fn write_prg(&mut self, addr: u16, data: u8);
+  fn read_chr(&self, addr: u16) -> u8;
+  fn write_chr(&mut self, addr: u16, data: u8);
+  // 実行中にマッパーが切り替えることがある
+  fn mirroring(&self) -> Mirroring;
+  // $6000-$7FFF (バッテリーバックアップされている場合は.savに保存する)
+  fn prg_ram(&self) -> &PrgRam;
+  fn prg_ram_mut(&mut self) -> &mut PrgRam;
+  // バンクなどのレジスタとPRG_RAM, CHR_RAM (ROMは含めない)
+  fn save_state(&self, writer: &mut StateWriter);
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+
+  // PPUがアドレスバスに出したアドレスと、そのときのPPUのサイクル数(MMC3のA12検出用)
+  // パターンテーブルのフェッチと$2006/$2007でのアクセスで呼ばれる
+  fn on_ppu_fetch(&mut self, _addr: u16, _cycles: usize) {}
+  // 書き込みの直前に、そのときのCPUのサイクル数を伝える(MMC1の連続書き込みの検出用)
+  fn on_cpu_write(&mut self, _cycles: usize) {}
+  // CPUのIRQ線
+  fn irq_pending(&self) -> bool {
+    false
+  }
+}
+
+// BusとPPUの両方から参照される
+pub type Cartridge = Rc<RefCell<Box<dyn Mapper>>>;
+
+// カートリッジ上のWRAM
+// サイズ0の場合はオープンバス扱いで0を返す
+pub struct PrgRam {
+  data: Vec<u8>,
+  dirty: bool,
+}
+
+impl PrgRam {
+  pub fn new(size: usize) -> Self {
+    PrgRam {
+      data: vec![0; size],
+      dirty: false,
+    }
+  }
+
+  pub fn read(&self, addr: u16) -> u8 {
+    if self.data.is_empty() {
+      return 0;
+    }
+    self.data[(addr - 0x6000) as usize % self.data.len()]
+  }
+
+  pub fn write(&mut self, addr: u16, data: u8) {
+    if self.data.is_empty() {
+      return;
+    }
+    let len = self.data.len();
+    let index = (addr - 0x6000) as usize % len;
+    if self.data[index] != data {
+      self.data[index] = data;
+      self.dirty = true;
+    }
+  }
+
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
+
+  // .savから復元する (サイズが違う場合は入る分だけ)
+  pub fn load(&mut self, data: &[u8]) {
+    let len = self.data.len().min(data.len());
+    self.data[..len].copy_from_slice(&data[..len]);
+    self.dirty = false;
+  }
+
+  // 前回保存してから書き込みがあったか
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  pub fn mark_saved(&mut self) {
+    self.dirty = false;
+  }
+
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_bytes(&self.data);
+  }
+
+  // ステートを読み込んだら.savにも反映させる
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    reader.read_into(&mut self.data)?;
+    self.dirty = true;
+    Ok(())
+  }
+}
+
+// マッパーが切り替えるPRG_ROM, CHR_ROMの最小単位 (未対応のマッパーはNone)
+// ROMのサイズはこの倍数でないといけない
+pub fn bank_sizes(mapper: u16) -> Option<(usize, usize)> {
+  match mapper {
+    0 | 2 | 3 => Some((0x4000, 0x2000)),
+    1 => Some((0x4000, 0x1000)),
+    // 最後の2つの8KBバンクが固定なので、PRG_ROMは16KB単位
+    4 => Some((0x4000, 0x0400)),
+    7 | 66 => Some((0x8000, 0x2000)),
+    _ => None,
+  }
+}
+
+pub fn new_mapper(
+  mapper: u16,
+  submapper: u8,
+  prg_rom: Vec<u8>,
+  chr_rom: Vec<u8>,
+  chr_ram: bool,
+  screen_mirroring: Mirroring,
+  prg_ram_size: usize,
+) -> Result<Box<dyn Mapper>, RomError> {
+  match mapper {
+    0 => Ok(Box::new(Nrom::new(
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      screen_mirroring,
+      prg_ram_size,
+    ))),
+    1 => Ok(Box::new(Mmc1::new(prg_rom, chr_rom, chr_ram, prg_ram_size))),
+    // ディスクリートロジックの基板はROMの出力とCPUの書き込みがぶつかる(バスコンフリクト)
+    // NES2.0のサブマッパー 1: バスコンフリクト無し, 2: 有り
+    2 => Ok(Box::new(Uxrom::new(
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      screen_mirroring,
+      submapper != 1,
+      prg_ram_size,
+    ))),
+    3 => Ok(Box::new(Cnrom::new(
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      screen_mirroring,
+      submapper != 1,
+      prg_ram_size,
+    ))),
+    4 => Ok(Box::new(Mmc3::new(
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      screen_mirroring,
+      prg_ram_size,
+    ))),
+    // AOROMにはバスコンフリクトがない
+    7 => Ok(Box::new(Axrom::new(
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      submapper == 2,
+      prg_ram_size,
+    ))),
+    66 => Ok(Box::new(Gxrom::new(
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      screen_mirroring,
+      true,
+      prg_ram_size,
+    ))),
+    _ => Err(RomError::UnsupportedMapper {
+      mapper: mapper,
+      submapper: submapper,
+    }),
+  }
+}
@@ -0,0 +1,136 @@
+use log::warn;
+
+use crate::mapper::{Mapper, PrgRam};
+use crate::rom::Mirroring;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+// Mapper 7
+// see: https://www.nesdev.org/wiki/AxROM
+pub struct Axrom {
+  prg_rom: Vec<u8>,
+  chr: Vec<u8>,
+  chr_ram: bool,
+  prg_ram: PrgRam,
+  bus_conflicts: bool,
+
+  // bit0-2: PRGバンク, bit4: 1画面ミラーリングのページ
+  bank: u8,
+}
+
+impl Axrom {
+  pub fn new(
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    bus_conflicts: bool,
+    prg_ram_size: usize,
+  ) -> Self {
+    Axrom {
+      prg_rom: prg_rom,
+      chr: chr,
+      chr_ram: chr_ram,
+      prg_ram: PrgRam::new(prg_ram_size),
+      bus_conflicts: bus_conflicts,
+      bank: 0,
+    }
+  }
+}
+
+impl Mapper for Axrom {
+  fn read_prg(&self, addr: u16) -> u8 {
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.read(addr),
+      0x8000..=0xFFFF => {
+        let count = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let bank = (self.bank & 0b111) as usize % count;
+        self.prg_rom[(bank * PRG_BANK_SIZE + (addr - 0x8000) as usize) % self.prg_rom.len()]
+      }
+      _ => {
+        warn!("Ignoreing mem access at {:X}", addr);
+        0
+      }
+    }
+  }
+
+  fn write_prg(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.write(addr, data),
+      0x8000..=0xFFFF => {
+        self.bank = if self.bus_conflicts {
+          data & self.read_prg(addr)
+        } else {
+          data
+        };
+      }
+      _ => {
+        warn!("Ignoring mem write-access at {:X}", addr);
+      }
+    }
+  }
+
+  fn read_chr(&self, addr: u16) -> u8 {
+    self.chr[addr as usize]
+  }
+
+  fn write_chr(&mut self, addr: u16, data: u8) {
+    if self.chr_ram {
+      self.chr[addr as usize] = data;
+    } else {
+      warn!("Attempt to write to CHR_ROM {:04X}", addr);
+    }
+  }
+
+  fn mirroring(&self) -> Mirroring {
+    if self.bank & 0b1_0000 == 0 {
+      Mirroring::SINGLE_SCREEN_LOWER
+    } else {
+      Mirroring::SINGLE_SCREEN_UPPER
+    }
+  }
+
+  fn prg_ram(&self) -> &PrgRam {
+    &self.prg_ram
+  }
+
+  fn prg_ram_mut(&mut self) -> &mut PrgRam {
+    &mut self.prg_ram
+  }
+
+  fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_u8(self.bank);
+    self.prg_ram.save_state(writer);
+    if self.chr_ram {
+      writer.write_bytes(&self.chr);
+    }
+  }
+
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.bank = reader.read_u8()?;
+    self.prg_ram.load_state(reader)?;
+    if self.chr_ram {
+      reader.read_into(&mut self.chr)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_bank_and_single_screen() {
+    let mut prg_rom = vec![0xFF; 4 * PRG_BANK_SIZE];
+    for bank in 0..4 {
+      prg_rom[bank * PRG_BANK_SIZE] = bank as u8;
+    }
+    let mut mapper = Axrom::new(prg_rom, vec![0; 0x2000], true, false, 0);
+    assert_eq!(mapper.mirroring(), Mirroring::SINGLE_SCREEN_LOWER);
+
+    mapper.write_prg(0x8000, 0b1_0010);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    assert_eq!(mapper.mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+  }
+}
@@ -0,0 +1,124 @@
+use log::warn;
+
+use crate::mapper::{Mapper, PrgRam};
+use crate::rom::Mirroring;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// Mapper 66
+// see: https://www.nesdev.org/wiki/GxROM
+pub struct Gxrom {
+  prg_rom: Vec<u8>,
+  chr: Vec<u8>,
+  chr_ram: bool,
+  prg_ram: PrgRam,
+  mirroring: Mirroring,
+  bus_conflicts: bool,
+
+  // bit4-5: PRGバンク, bit0-1: CHRバンク
+  bank: u8,
+}
+
+impl Gxrom {
+  pub fn new(
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    prg_ram_size: usize,
+  ) -> Self {
+    Gxrom {
+      prg_rom: prg_rom,
+      chr: chr,
+      chr_ram: chr_ram,
+      prg_ram: PrgRam::new(prg_ram_size),
+      mirroring: mirroring,
+      bus_conflicts: bus_conflicts,
+      bank: 0,
+    }
+  }
+
+  fn chr_offset(&self, addr: u16) -> usize {
+    let count = (self.chr.len() / CHR_BANK_SIZE).max(1);
+    let bank = (self.bank & 0b11) as usize % count;
+    bank * CHR_BANK_SIZE + addr as usize
+  }
+}
+
+impl Mapper for Gxrom {
+  fn read_prg(&self, addr: u16) -> u8 {
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.read(addr),
+      0x8000..=0xFFFF => {
+        let count = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let bank = ((self.bank >> 4) & 0b11) as usize % count;
+        self.prg_rom[(bank * PRG_BANK_SIZE + (addr - 0x8000) as usize) % self.prg_rom.len()]
+      }
+      _ => {
+        warn!("Ignoreing mem access at {:X}", addr);
+        0
+      }
+    }
+  }
+
+  fn write_prg(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.write(addr, data),
+      0x8000..=0xFFFF => {
+        self.bank = if self.bus_conflicts {
+          data & self.read_prg(addr)
+        } else {
+          data
+        };
+      }
+      _ => {
+        warn!("Ignoring mem write-access at {:X}", addr);
+      }
+    }
+  }
+
+  fn read_chr(&self, addr: u16) -> u8 {
+    self.chr[self.chr_offset(addr)]
+  }
+
+  fn write_chr(&mut self, addr: u16, data: u8) {
+    if self.chr_ram {
+      let offset = self.chr_offset(addr);
+      self.chr[offset] = data;
+    } else {
+      warn!("Attempt to write to CHR_ROM {:04X}", addr);
+    }
+  }
+
+  fn mirroring(&self) -> Mirroring {
+    self.mirroring
+  }
+
+  fn prg_ram(&self) -> &PrgRam {
+    &self.prg_ram
+  }
+
+  fn prg_ram_mut(&mut self) -> &mut PrgRam {
+    &mut self.prg_ram
+  }
+
+  fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_u8(self.bank);
+    self.prg_ram.save_state(writer);
+    if self.chr_ram {
+      writer.write_bytes(&self.chr);
+    }
+  }
+
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.bank = reader.read_u8()?;
+    self.prg_ram.load_state(reader)?;
+    if self.chr_ram {
+      reader.read_into(&mut self.chr)?;
+    }
+    Ok(())
+  }
+}
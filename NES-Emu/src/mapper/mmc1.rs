@@ -0,0 +1,359 @@
+use log::{debug, warn};
+
+use crate::mapper::{Mapper, PrgRam};
+use crate::rom::Mirroring;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// Mapper 1
+// see: https://www.nesdev.org/wiki/MMC1
+pub struct Mmc1 {
+  prg_rom: Vec<u8>,
+  chr: Vec<u8>,
+  chr_ram: bool,
+  prg_ram: PrgRam,
+
+  // 5bitのシリアルシフトレジスタ
+  shift_register: u8,
+  shift_count: u8,
+  // 連続したサイクルの書き込みは2回目が無視される(INCなどの読み書き命令で起きる)
+  cpu_cycles: usize,
+  last_write_cycles: Option<usize>,
+
+  control: u8,   // $8000-$9FFF
+  chr_bank0: u8, // $A000-$BFFF
+  chr_bank1: u8, // $C000-$DFFF
+  prg_bank: u8,  // $E000-$FFFF
+}
+
+impl Mmc1 {
+  pub fn new(prg_rom: Vec<u8>, chr: Vec<u8>, chr_ram: bool, prg_ram_size: usize) -> Self {
+    Mmc1 {
+      prg_rom: prg_rom,
+      chr: chr,
+      chr_ram: chr_ram,
+      prg_ram: PrgRam::new(prg_ram_size),
+      shift_register: 0,
+      shift_count: 0,
+      cpu_cycles: 0,
+      last_write_cycles: None,
+      // 電源投入時は最後のバンクが$C000に固定される
+      control: 0x0C,
+      chr_bank0: 0,
+      chr_bank1: 0,
+      prg_bank: 0,
+    }
+  }
+
+  fn write_register(&mut self, addr: u16, value: u8) {
+    debug!("MMC1 REG {:04X} => {:02X}", addr, value);
+    match addr {
+      0x8000..=0x9FFF => self.control = value,
+      0xA000..=0xBFFF => self.chr_bank0 = value,
+      0xC000..=0xDFFF => self.chr_bank1 = value,
+      0xE000..=0xFFFF => self.prg_bank = value,
+      _ => panic!("can't be"),
+    }
+  }
+
+  fn prg_ram_enabled(&self) -> bool {
+    self.prg_bank & 0b1_0000 == 0
+  }
+
+  fn prg_bank_count(&self) -> usize {
+    (self.prg_rom.len() / PRG_BANK_SIZE).min(16).max(1)
+  }
+
+  fn prg_outer_bank(&self) -> usize {
+    // 512KBのSUROMはCHRバンクのbit4で256KB単位の外側バンクを選ぶ
+    if self.prg_rom.len() > 0x40000 {
+      ((self.chr_bank0 as usize) >> 4 & 1) * 16
+    } else {
+      0
+    }
+  }
+
+  fn prg_offset(&self, addr: u16) -> usize {
+    let count = self.prg_bank_count();
+    let bank = (self.prg_bank & 0b1111) as usize % count;
+    let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+
+    let bank = match (self.control >> 2) & 0b11 {
+      // 32KB単位で切り替え(下位bitは無視)
+      0 | 1 => (bank & !1) + slot,
+      // $8000を最初のバンクに固定して$C000を切り替え
+      2 => {
+        if slot == 0 {
+          0
+        } else {
+          bank
+        }
+      }
+      // $C000を最後のバンクに固定して$8000を切り替え
+      3 => {
+        if slot == 0 {
+          bank
+        } else {
+          count - 1
+        }
+      }
+      _ => panic!("can't be"),
+    };
+    let bank = self.prg_outer_bank() + bank % count;
+    (bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))) % self.prg_rom.len()
+  }
+
+  fn chr_offset(&self, addr: u16) -> usize {
+    let slot = addr as usize / CHR_BANK_SIZE;
+    let bank = if self.control & 0b1_0000 == 0 {
+      // 8KB単位で切り替え(下位bitは無視)
+      (self.chr_bank0 as usize & !1) + slot
+    } else if slot == 0 {
+      self.chr_bank0 as usize
+    } else {
+      self.chr_bank1 as usize
+    };
+    (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.chr.len()
+  }
+}
+
+impl Mapper for Mmc1 {
+  fn read_prg(&self, addr: u16) -> u8 {
+    match addr {
+      0x6000..=0x7FFF => {
+        if self.prg_ram_enabled() {
+          self.prg_ram.read(addr)
+        } else {
+          0
+        }
+      }
+      0x8000..=0xFFFF => self.prg_rom[self.prg_offset(addr)],
+      _ => {
+        warn!("Ignoreing mem access at {:X}", addr);
+        0
+      }
+    }
+  }
+
+  fn write_prg(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x6000..=0x7FFF => {
+        if self.prg_ram_enabled() {
+          self.prg_ram.write(addr, data);
+        }
+      }
+      0x8000..=0xFFFF => {
+        let consecutive = self.last_write_cycles == Some(self.cpu_cycles.wrapping_sub(1));
+        self.last_write_cycles = Some(self.cpu_cycles);
+        if consecutive {
+          debug!(
+            "MMC1 ignored consecutive write {:04X} => {:02X}",
+            addr, data
+          );
+          return;
+        }
+        if data & 0b1000_0000 != 0 {
+          // bit7が立っていたらシフトレジスタをリセット
+          self.shift_register = 0;
+          self.shift_count = 0;
+          self.control |= 0x0C;
+          return;
+        }
+        // LSBから順に5回書き込まれる
+        self.shift_register |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+          let value = self.shift_register;
+          self.write_register(addr, value);
+          self.shift_register = 0;
+          self.shift_count = 0;
+        }
+      }
+      _ => {
+        warn!("Ignoring mem write-access at {:X}", addr);
+      }
+    }
+  }
+
+  fn read_chr(&self, addr: u16) -> u8 {
+    self.chr[self.chr_offset(addr)]
+  }
+
+  fn write_chr(&mut self, addr: u16, data: u8) {
+    if self.chr_ram {
+      let offset = self.chr_offset(addr);
+      self.chr[offset] = data;
+    } else {
+      warn!("Attempt to write to CHR_ROM {:04X}", addr);
+    }
+  }
+
+  fn on_cpu_write(&mut self, cycles: usize) {
+    self.cpu_cycles = cycles;
+  }
+
+  fn mirroring(&self) -> Mirroring {
+    match self.control & 0b11 {
+      0 => Mirroring::SINGLE_SCREEN_LOWER,
+      1 => Mirroring::SINGLE_SCREEN_UPPER,
+      2 => Mirroring::VERTICAL,
+      3 => Mirroring::HORIZONTAL,
+      _ => panic!("can't be"),
+    }
+  }
+
+  fn prg_ram(&self) -> &PrgRam {
+    &self.prg_ram
+  }
+
+  fn prg_ram_mut(&mut self) -> &mut PrgRam {
+    &mut self.prg_ram
+  }
+
+  fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_u8(self.shift_register);
+    writer.write_u8(self.shift_count);
+    writer.write_bool(self.last_write_cycles.is_some());
+    writer.write_usize(self.last_write_cycles.unwrap_or(0));
+    writer.write_u8(self.control);
+    writer.write_u8(self.chr_bank0);
+    writer.write_u8(self.chr_bank1);
+    writer.write_u8(self.prg_bank);
+    self.prg_ram.save_state(writer);
+    if self.chr_ram {
+      writer.write_bytes(&self.chr);
+    }
+  }
+
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.shift_register = reader.read_u8()?;
+    self.shift_count = reader.read_u8()?;
+    let has_last_write = reader.read_bool()?;
+    let last_write_cycles = reader.read_usize()?;
+    self.last_write_cycles = if has_last_write {
+      Some(last_write_cycles)
+    } else {
+      None
+    };
+    self.control = reader.read_u8()?;
+    self.chr_bank0 = reader.read_u8()?;
+    self.chr_bank1 = reader.read_u8()?;
+    self.prg_bank = reader.read_u8()?;
+    self.prg_ram.load_state(reader)?;
+    if self.chr_ram {
+      reader.read_into(&mut self.chr)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn write_serial(mapper: &mut Mmc1, addr: u16, value: u8) {
+    for i in 0..5 {
+      mapper.write_prg(addr, (value >> i) & 1);
+    }
+  }
+
+  fn mmc1() -> Mmc1 {
+    // 8 x 16KBのPRG_ROM, 各バンクの先頭にバンク番号を入れておく
+    let mut prg_rom = vec![0; 8 * PRG_BANK_SIZE];
+    for bank in 0..8 {
+      prg_rom[bank * PRG_BANK_SIZE] = bank as u8;
+    }
+    let mut chr = vec![0; 8 * CHR_BANK_SIZE];
+    for bank in 0..8 {
+      chr[bank * CHR_BANK_SIZE] = bank as u8;
+    }
+    Mmc1::new(prg_rom, chr, false, 0x2000)
+  }
+
+  #[test]
+  fn test_power_on_fixes_last_bank() {
+    let mapper = mmc1();
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    assert_eq!(mapper.read_prg(0xC000), 7);
+  }
+
+  #[test]
+  fn test_prg_bank_switch() {
+    let mut mapper = mmc1();
+    write_serial(&mut mapper, 0xE000, 3);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xC000), 7);
+
+    // $8000固定モード
+    write_serial(&mut mapper, 0x8000, 0b0_1000);
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    assert_eq!(mapper.read_prg(0xC000), 3);
+
+    // 32KBモード
+    write_serial(&mut mapper, 0x8000, 0b0_0000);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    assert_eq!(mapper.read_prg(0xC000), 3);
+  }
+
+  #[test]
+  fn test_reset_shift_register() {
+    let mut mapper = mmc1();
+    write_serial(&mut mapper, 0x8000, 0b0_0010);
+    assert_eq!(mapper.mirroring(), Mirroring::VERTICAL);
+
+    mapper.write_prg(0x8000, 1);
+    mapper.write_prg(0x8000, 0x80);
+    write_serial(&mut mapper, 0xE000, 5);
+    assert_eq!(mapper.read_prg(0x8000), 5);
+  }
+
+  #[test]
+  fn test_ignore_consecutive_writes() {
+    let mut mapper = mmc1();
+    mapper.on_cpu_write(100);
+    mapper.write_prg(0xE000, 1);
+
+    // INC $8000 ($8000 = $80): 元の値を書き戻してリセット、次のサイクルの$81は無視される
+    mapper.on_cpu_write(200);
+    mapper.write_prg(0x8000, 0x80);
+    mapper.on_cpu_write(201);
+    mapper.write_prg(0x8000, 0x81);
+    assert_eq!(mapper.shift_count, 0);
+
+    // 1サイクル空いていれば受け付ける
+    mapper.on_cpu_write(203);
+    mapper.write_prg(0xE000, 1);
+    assert_eq!(mapper.shift_count, 1);
+  }
+
+  #[test]
+  fn test_chr_bank_switch() {
+    let mut mapper = mmc1();
+    // 4KBモード + 水平ミラーリング
+    write_serial(&mut mapper, 0x8000, 0b1_1111);
+    write_serial(&mut mapper, 0xA000, 5);
+    write_serial(&mut mapper, 0xC000, 2);
+    assert_eq!(mapper.read_chr(0x0000), 5);
+    assert_eq!(mapper.read_chr(0x1000), 2);
+    assert_eq!(mapper.mirroring(), Mirroring::HORIZONTAL);
+
+    // 8KBモード
+    write_serial(&mut mapper, 0x8000, 0b0_1100);
+    assert_eq!(mapper.read_chr(0x0000), 4);
+    assert_eq!(mapper.read_chr(0x1000), 5);
+    assert_eq!(mapper.mirroring(), Mirroring::SINGLE_SCREEN_LOWER);
+  }
+
+  #[test]
+  fn test_prg_ram() {
+    let mut mapper = mmc1();
+    mapper.write_prg(0x6000, 0x55);
+    assert_eq!(mapper.read_prg(0x6000), 0x55);
+
+    // bit4でPRG_RAMを無効化
+    write_serial(&mut mapper, 0xE000, 0b1_0000);
+    assert_eq!(mapper.read_prg(0x6000), 0);
+  }
+}
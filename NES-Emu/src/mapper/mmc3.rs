@@ -0,0 +1,397 @@
+use log::{debug, warn};
+
+use crate::mapper::{Mapper, PrgRam};
+use crate::rom::Mirroring;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+// A12がこれだけ(3CPUサイクル分のPPUサイクル)Lowでなければ立ち上がりを数えない
+const A12_LOW_CYCLES: usize = 9;
+
+// Mapper 4
+// see: https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+  prg_rom: Vec<u8>,
+  chr: Vec<u8>,
+  chr_ram: bool,
+  prg_ram: PrgRam,
+  four_screen: bool,
+
+  bank_select: u8,         // $8000
+  bank_registers: [u8; 8], // $8001 R0-R7
+  mirroring: Mirroring,    // $A000
+  prg_ram_protect: u8,     // $A001
+
+  irq_latch: u8, // $C000
+  irq_counter: u8,
+  irq_reload: bool,  // $C001
+  irq_enabled: bool, // $E000, $E001
+  irq_pending: bool,
+
+  // PPUアドレスバスのA12(パターンテーブルの$1000側)
+  last_a12: bool,
+  // A12がLowになったときのPPUのサイクル数
+  a12_low_since: usize,
+}
+
+impl Mmc3 {
+  pub fn new(
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    screen_mirroring: Mirroring,
+    prg_ram_size: usize,
+  ) -> Self {
+    Mmc3 {
+      prg_rom: prg_rom,
+      chr: chr,
+      chr_ram: chr_ram,
+      prg_ram: PrgRam::new(prg_ram_size),
+      four_screen: screen_mirroring == Mirroring::FOUR_SCREEN,
+      bank_select: 0,
+      bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+      mirroring: screen_mirroring,
+      prg_ram_protect: 0x80,
+      irq_latch: 0,
+      irq_counter: 0,
+      irq_reload: false,
+      irq_enabled: false,
+      irq_pending: false,
+      last_a12: false,
+      a12_low_since: 0,
+    }
+  }
+
+  fn write_register(&mut self, addr: u16, value: u8) {
+    debug!("MMC3 REG {:04X} => {:02X}", addr, value);
+    let even = addr & 1 == 0;
+    match (addr, even) {
+      (0x8000..=0x9FFF, true) => self.bank_select = value,
+      (0x8000..=0x9FFF, false) => {
+        let target = (self.bank_select & 0b111) as usize;
+        self.bank_registers[target] = value;
+      }
+      (0xA000..=0xBFFF, true) => {
+        if !self.four_screen {
+          self.mirroring = if value & 1 == 0 {
+            Mirroring::VERTICAL
+          } else {
+            Mirroring::HORIZONTAL
+          };
+        }
+      }
+      (0xA000..=0xBFFF, false) => self.prg_ram_protect = value,
+      (0xC000..=0xDFFF, true) => self.irq_latch = value,
+      (0xC000..=0xDFFF, false) => {
+        self.irq_counter = 0;
+        self.irq_reload = true;
+      }
+      (0xE000..=0xFFFF, true) => {
+        // 無効化と同時に保留中のIRQも取り消す
+        self.irq_enabled = false;
+        self.irq_pending = false;
+      }
+      (0xE000..=0xFFFF, false) => self.irq_enabled = true,
+      _ => panic!("can't be"),
+    }
+  }
+
+  fn clock_irq_counter(&mut self) {
+    if self.irq_counter == 0 || self.irq_reload {
+      self.irq_counter = self.irq_latch;
+      self.irq_reload = false;
+    } else {
+      self.irq_counter -= 1;
+    }
+    if self.irq_counter == 0 && self.irq_enabled {
+      self.irq_pending = true;
+    }
+  }
+
+  fn prg_offset(&self, addr: u16) -> usize {
+    let count = self.prg_rom.len() / PRG_BANK_SIZE;
+    let second_last = count - 2;
+    let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+    let swap = self.bank_select & 0b0100_0000 != 0;
+
+    let bank = match (slot, swap) {
+      (0, false) => self.bank_registers[6] as usize & 0b11_1111,
+      (0, true) => second_last,
+      (1, _) => self.bank_registers[7] as usize & 0b11_1111,
+      (2, false) => second_last,
+      (2, true) => self.bank_registers[6] as usize & 0b11_1111,
+      (3, _) => count - 1,
+      _ => panic!("can't be"),
+    };
+    (bank % count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+  }
+
+  fn chr_offset(&self, addr: u16) -> usize {
+    let inversion = self.bank_select & 0b1000_0000 != 0;
+    // A12反転時は$0000と$1000の役割が入れ替わる
+    let addr = if inversion { addr ^ 0x1000 } else { addr } as usize;
+    let slot = addr / CHR_BANK_SIZE;
+
+    let bank = match slot {
+      // R0, R1は2KB単位(下位bitは無視)
+      0 | 1 => (self.bank_registers[0] as usize & !1) + slot,
+      2 | 3 => (self.bank_registers[1] as usize & !1) + slot - 2,
+      _ => self.bank_registers[slot - 2] as usize,
+    };
+    (bank * CHR_BANK_SIZE + (addr & (CHR_BANK_SIZE - 1))) % self.chr.len()
+  }
+
+  fn prg_ram_enabled(&self) -> bool {
+    self.prg_ram_protect & 0b1000_0000 != 0
+  }
+
+  fn prg_ram_writable(&self) -> bool {
+    self.prg_ram_enabled() && self.prg_ram_protect & 0b0100_0000 == 0
+  }
+}
+
+impl Mapper for Mmc3 {
+  fn read_prg(&self, addr: u16) -> u8 {
+    match addr {
+      0x6000..=0x7FFF => {
+        if self.prg_ram_enabled() {
+          self.prg_ram.read(addr)
+        } else {
+          0
+        }
+      }
+      0x8000..=0xFFFF => self.prg_rom[self.prg_offset(addr)],
+      _ => {
+        warn!("Ignoreing mem access at {:X}", addr);
+        0
+      }
+    }
+  }
+
+  fn write_prg(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x6000..=0x7FFF => {
+        if self.prg_ram_writable() {
+          self.prg_ram.write(addr, data);
+        }
+      }
+      0x8000..=0xFFFF => self.write_register(addr, data),
+      _ => {
+        warn!("Ignoring mem write-access at {:X}", addr);
+      }
+    }
+  }
+
+  fn read_chr(&self, addr: u16) -> u8 {
+    self.chr[self.chr_offset(addr)]
+  }
+
+  fn write_chr(&mut self, addr: u16, data: u8) {
+    if self.chr_ram {
+      let offset = self.chr_offset(addr);
+      self.chr[offset] = data;
+    } else {
+      warn!("Attempt to write to CHR_ROM {:04X}", addr);
+    }
+  }
+
+  fn mirroring(&self) -> Mirroring {
+    self.mirroring
+  }
+
+  fn prg_ram(&self) -> &PrgRam {
+    &self.prg_ram
+  }
+
+  fn prg_ram_mut(&mut self) -> &mut PrgRam {
+    &mut self.prg_ram
+  }
+
+  fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_u8(self.bank_select);
+    for bank in self.bank_registers {
+      writer.write_u8(bank);
+    }
+    writer.write_bool(self.mirroring == Mirroring::HORIZONTAL);
+    writer.write_u8(self.prg_ram_protect);
+    writer.write_u8(self.irq_latch);
+    writer.write_u8(self.irq_counter);
+    writer.write_bool(self.irq_reload);
+    writer.write_bool(self.irq_enabled);
+    writer.write_bool(self.irq_pending);
+    writer.write_bool(self.last_a12);
+    writer.write_usize(self.a12_low_since);
+    self.prg_ram.save_state(writer);
+    if self.chr_ram {
+      writer.write_bytes(&self.chr);
+    }
+  }
+
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.bank_select = reader.read_u8()?;
+    for bank in self.bank_registers.iter_mut() {
+      *bank = reader.read_u8()?;
+    }
+    let horizontal = reader.read_bool()?;
+    // 4画面のカートリッジは$A000で切り替えられない
+    self.mirroring = if self.four_screen {
+      Mirroring::FOUR_SCREEN
+    } else if horizontal {
+      Mirroring::HORIZONTAL
+    } else {
+      Mirroring::VERTICAL
+    };
+    self.prg_ram_protect = reader.read_u8()?;
+    self.irq_latch = reader.read_u8()?;
+    self.irq_counter = reader.read_u8()?;
+    self.irq_reload = reader.read_bool()?;
+    self.irq_enabled = reader.read_bool()?;
+    self.irq_pending = reader.read_bool()?;
+    self.last_a12 = reader.read_bool()?;
+    self.a12_low_since = reader.read_usize()?;
+    self.prg_ram.load_state(reader)?;
+    if self.chr_ram {
+      reader.read_into(&mut self.chr)?;
+    }
+    Ok(())
+  }
+
+  fn on_ppu_fetch(&mut self, addr: u16, cycles: usize) {
+    // A12の立ち上がりでスキャンラインカウンタが進む
+    // 8x16スプライトのフェッチの合間のような短いLowは無視する
+    let a12 = addr & 0x1000 != 0;
+    if !self.last_a12 && a12 && cycles.saturating_sub(self.a12_low_since) >= A12_LOW_CYCLES {
+      self.clock_irq_counter();
+    }
+    if self.last_a12 && !a12 {
+      self.a12_low_since = cycles;
+    }
+    self.last_a12 = a12;
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_pending
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn mmc3() -> Mmc3 {
+    mmc3_with_mirroring(Mirroring::VERTICAL)
+  }
+
+  // 8 x 8KBのPRG_ROM, 各バンクの先頭にバンク番号を入れておく
+  fn mmc3_with_mirroring(mirroring: Mirroring) -> Mmc3 {
+    let mut prg_rom = vec![0; 8 * PRG_BANK_SIZE];
+    for bank in 0..8 {
+      prg_rom[bank * PRG_BANK_SIZE] = bank as u8;
+    }
+    let mut chr = vec![0; 16 * CHR_BANK_SIZE];
+    for bank in 0..16 {
+      chr[bank * CHR_BANK_SIZE] = bank as u8;
+    }
+    Mmc3::new(prg_rom, chr, false, mirroring, 0x2000)
+  }
+
+  fn scanline(mapper: &mut Mmc3, line: usize) {
+    // BG=$0000, スプライト=$1000 の一般的な構成
+    let cycles = line * 341;
+    mapper.on_ppu_fetch(0x0000, cycles + 5);
+    mapper.on_ppu_fetch(0x1000, cycles + 261);
+  }
+
+  #[test]
+  fn test_prg_bank_modes() {
+    let mut mapper = mmc3();
+    mapper.write_prg(0x8000, 6);
+    mapper.write_prg(0x8001, 2);
+    mapper.write_prg(0x8000, 7);
+    mapper.write_prg(0x8001, 3);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    assert_eq!(mapper.read_prg(0xA000), 3);
+    assert_eq!(mapper.read_prg(0xC000), 6);
+    assert_eq!(mapper.read_prg(0xE000), 7);
+
+    mapper.write_prg(0x8000, 0b0100_0000);
+    assert_eq!(mapper.read_prg(0x8000), 6);
+    assert_eq!(mapper.read_prg(0xC000), 2);
+  }
+
+  #[test]
+  fn test_chr_bank_inversion() {
+    let mut mapper = mmc3();
+    mapper.write_prg(0x8000, 0);
+    mapper.write_prg(0x8001, 4);
+    mapper.write_prg(0x8000, 2);
+    mapper.write_prg(0x8001, 9);
+    assert_eq!(mapper.read_chr(0x0000), 4);
+    assert_eq!(mapper.read_chr(0x0400), 5);
+    assert_eq!(mapper.read_chr(0x1000), 9);
+
+    mapper.write_prg(0x8000, 0b1000_0000);
+    assert_eq!(mapper.read_chr(0x0000), 9);
+    assert_eq!(mapper.read_chr(0x1000), 4);
+    assert_eq!(mapper.read_chr(0x1400), 5);
+  }
+
+  #[test]
+  fn test_scanline_irq() {
+    let mut mapper = mmc3();
+    mapper.write_prg(0xC000, 2);
+    mapper.write_prg(0xC001, 0);
+    mapper.write_prg(0xE001, 0);
+
+    scanline(&mut mapper, 0); // reload => 2
+    scanline(&mut mapper, 1); // 1
+    assert!(!mapper.irq_pending());
+    scanline(&mut mapper, 2); // 0
+    assert!(mapper.irq_pending());
+
+    mapper.write_prg(0xE000, 0);
+    assert!(!mapper.irq_pending());
+  }
+
+  #[test]
+  fn test_a12_filter() {
+    let mut mapper = mmc3();
+    mapper.write_prg(0xC000, 5);
+    mapper.write_prg(0xC001, 0);
+    scanline(&mut mapper, 0); // reload => 5
+
+    // 8x16スプライトで$0000と$1000が交互になっても、Lowが短ければ1回しか数えない
+    let cycles = 341;
+    mapper.on_ppu_fetch(0x0000, cycles + 5);
+    for slot in 0..8 {
+      let addr = if slot % 2 == 0 { 0x1000 } else { 0x0000 };
+      mapper.on_ppu_fetch(addr, cycles + 261 + slot * 8);
+      mapper.on_ppu_fetch(addr + 8, cycles + 263 + slot * 8);
+    }
+    assert_eq!(mapper.irq_counter, 4);
+  }
+
+  #[test]
+  fn test_mirroring() {
+    let mut mapper = mmc3();
+    mapper.write_prg(0xA000, 1);
+    assert_eq!(mapper.mirroring(), Mirroring::HORIZONTAL);
+    mapper.write_prg(0xA000, 0);
+    assert_eq!(mapper.mirroring(), Mirroring::VERTICAL);
+  }
+
+  #[test]
+  fn test_four_screen_state() {
+    let mut mapper = mmc3_with_mirroring(Mirroring::FOUR_SCREEN);
+    mapper.write_prg(0xA000, 1);
+    assert_eq!(mapper.mirroring(), Mirroring::FOUR_SCREEN);
+
+    let mut writer = StateWriter::new();
+    mapper.save_state(&mut writer);
+    let data = writer.into_inner();
+    let mut loaded = mmc3_with_mirroring(Mirroring::FOUR_SCREEN);
+    loaded.load_state(&mut StateReader::new(&data)).unwrap();
+    assert_eq!(loaded.mirroring(), Mirroring::FOUR_SCREEN);
+  }
+}
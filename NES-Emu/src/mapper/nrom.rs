@@ -0,0 +1,102 @@
+use log::warn;
+
+use crate::mapper::{Mapper, PrgRam};
+use crate::rom::Mirroring;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Mapper 0
+// see: https://www.nesdev.org/wiki/NROM
+pub struct Nrom {
+  prg_rom: Vec<u8>,
+  chr: Vec<u8>,
+  chr_ram: bool,
+  prg_ram: PrgRam,
+  mirroring: Mirroring,
+}
+
+impl Nrom {
+  pub fn new(
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    prg_ram_size: usize,
+  ) -> Self {
+    Nrom {
+      prg_rom: prg_rom,
+      chr: chr,
+      chr_ram: chr_ram,
+      prg_ram: PrgRam::new(prg_ram_size),
+      mirroring: mirroring,
+    }
+  }
+}
+
+impl Mapper for Nrom {
+  fn read_prg(&self, addr: u16) -> u8 {
+    match addr {
+      // Family BASICなどはPRG_RAMを持っている
+      0x6000..=0x7FFF => self.prg_ram.read(addr),
+      0x8000..=0xFFFF => {
+        let mut addr = addr - 0x8000;
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+          // mirror if needed
+          addr = addr % 0x4000;
+        }
+        self.prg_rom[addr as usize]
+      }
+      _ => {
+        warn!("Ignoreing mem access at {:X}", addr);
+        0
+      }
+    }
+  }
+
+  fn write_prg(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.write(addr, data),
+      _ => {
+        warn!("Attempt to write to Cartridge ROM space {:X}", addr);
+      }
+    }
+  }
+
+  fn read_chr(&self, addr: u16) -> u8 {
+    self.chr[addr as usize]
+  }
+
+  fn write_chr(&mut self, addr: u16, data: u8) {
+    if self.chr_ram {
+      self.chr[addr as usize] = data;
+    } else {
+      warn!("Attempt to write to CHR_ROM {:04X}", addr);
+    }
+  }
+
+  fn mirroring(&self) -> Mirroring {
+    self.mirroring
+  }
+
+  fn prg_ram(&self) -> &PrgRam {
+    &self.prg_ram
+  }
+
+  fn prg_ram_mut(&mut self) -> &mut PrgRam {
+    &mut self.prg_ram
+  }
+
+  fn save_state(&self, writer: &mut StateWriter) {
+    self.prg_ram.save_state(writer);
+    if self.chr_ram {
+      writer.write_bytes(&self.chr);
+    }
+  }
+
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.prg_ram.load_state(reader)?;
+    if self.chr_ram {
+      reader.read_into(&mut self.chr)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,163 @@
+use log::warn;
+
+use crate::mapper::{Mapper, PrgRam};
+use crate::rom::Mirroring;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+// Mapper 2
+// see: https://www.nesdev.org/wiki/UxROM
+pub struct Uxrom {
+  prg_rom: Vec<u8>,
+  chr: Vec<u8>,
+  chr_ram: bool,
+  prg_ram: PrgRam,
+  mirroring: Mirroring,
+  bus_conflicts: bool,
+
+  prg_bank: u8,
+}
+
+impl Uxrom {
+  pub fn new(
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    prg_ram_size: usize,
+  ) -> Self {
+    Uxrom {
+      prg_rom: prg_rom,
+      chr: chr,
+      chr_ram: chr_ram,
+      prg_ram: PrgRam::new(prg_ram_size),
+      mirroring: mirroring,
+      bus_conflicts: bus_conflicts,
+      prg_bank: 0,
+    }
+  }
+}
+
+impl Mapper for Uxrom {
+  fn read_prg(&self, addr: u16) -> u8 {
+    let count = self.prg_rom.len() / PRG_BANK_SIZE;
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.read(addr),
+      // $8000は切り替え、$C000は最後のバンクに固定
+      0x8000..=0xBFFF => {
+        let bank = self.prg_bank as usize % count;
+        self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0x8000) as usize]
+      }
+      0xC000..=0xFFFF => self.prg_rom[(count - 1) * PRG_BANK_SIZE + (addr - 0xC000) as usize],
+      _ => {
+        warn!("Ignoreing mem access at {:X}", addr);
+        0
+      }
+    }
+  }
+
+  fn write_prg(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram.write(addr, data),
+      0x8000..=0xFFFF => {
+        self.prg_bank = if self.bus_conflicts {
+          data & self.read_prg(addr)
+        } else {
+          data
+        };
+      }
+      _ => {
+        warn!("Ignoring mem write-access at {:X}", addr);
+      }
+    }
+  }
+
+  fn read_chr(&self, addr: u16) -> u8 {
+    self.chr[addr as usize]
+  }
+
+  fn write_chr(&mut self, addr: u16, data: u8) {
+    if self.chr_ram {
+      self.chr[addr as usize] = data;
+    } else {
+      warn!("Attempt to write to CHR_ROM {:04X}", addr);
+    }
+  }
+
+  fn mirroring(&self) -> Mirroring {
+    self.mirroring
+  }
+
+  fn prg_ram(&self) -> &PrgRam {
+    &self.prg_ram
+  }
+
+  fn prg_ram_mut(&mut self) -> &mut PrgRam {
+    &mut self.prg_ram
+  }
+
+  fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_u8(self.prg_bank);
+    self.prg_ram.save_state(writer);
+    if self.chr_ram {
+      writer.write_bytes(&self.chr);
+    }
+  }
+
+  fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.prg_bank = reader.read_u8()?;
+    self.prg_ram.load_state(reader)?;
+    if self.chr_ram {
+      reader.read_into(&mut self.chr)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn uxrom(bus_conflicts: bool) -> Uxrom {
+    // 4 x 16KBのPRG_ROM, 各バンクの先頭にバンク番号を入れておく
+    let mut prg_rom = vec![0xFF; 4 * PRG_BANK_SIZE];
+    for bank in 0..4 {
+      prg_rom[bank * PRG_BANK_SIZE] = bank as u8;
+    }
+    Uxrom::new(
+      prg_rom,
+      vec![0; 0x2000],
+      true,
+      Mirroring::VERTICAL,
+      bus_conflicts,
+      0,
+    )
+  }
+
+  #[test]
+  fn test_prg_bank_switch() {
+    let mut mapper = uxrom(false);
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    assert_eq!(mapper.read_prg(0xC000), 3);
+
+    mapper.write_prg(0x8000, 2);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    assert_eq!(mapper.read_prg(0xC000), 3);
+  }
+
+  #[test]
+  fn test_bus_conflicts() {
+    let mut mapper = uxrom(true);
+    // $C000には3が入っているので 2 & 3 = 2
+    mapper.write_prg(0xC000, 2);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    // $C001は$FFなのでそのまま
+    mapper.write_prg(0xC001, 1);
+    assert_eq!(mapper.read_prg(0x8000), 1);
+    // $8000(バンク1の先頭=1)に2を書くと 2 & 1 = 0
+    mapper.write_prg(0x8000, 2);
+    assert_eq!(mapper.read_prg(0x8000), 0);
+  }
+}
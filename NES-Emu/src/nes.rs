@@ -0,0 +1,311 @@
+use crate::apu::{ApuOutput, NesAPU};
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use crate::frame::Frame;
+use crate::joypad::{JoypadButton, Player};
+use crate::palette::Palette;
+use crate::region::Region;
+use crate::render;
+use crate::rom::Rom;
+use crate::savestate::{self, StateError};
+
+pub const SAMPLE_RATE: f32 = 44100.0;
+
+// 呼び出し側に制御を返しながら進めるためのファサード
+// CPU::run_with_callbackと違ってSDLのイベントループなどを前提にしない
+pub struct Nes {
+  cpu: CPU,
+  frame: Frame,
+  // 画面の色なのでステートには含めない
+  palette: Palette,
+  audio: ApuOutput,
+  audio_samples: Vec<f32>,
+  // 1サンプルに満たない端数のサイクル
+  audio_cycles: f32,
+  rom_hash: u64,
+}
+
+impl Nes {
+  // 地域はROMヘッダーに従う (set_regionで変えられる)
+  pub fn new(rom: Rom) -> Self {
+    let rom_hash = rom.hash;
+    let region = Region::from(rom.timing);
+    let (apu, audio) = NesAPU::new(SAMPLE_RATE);
+    let mut bus = Bus::new(rom, apu, |_, _| {});
+    bus.set_region(region);
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    Nes {
+      cpu: cpu,
+      frame: Frame::new(),
+      palette: Palette::default(),
+      audio: audio,
+      audio_samples: vec![],
+      audio_cycles: 0.0,
+      rom_hash: rom_hash,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    self.cpu.reset();
+  }
+
+  // 1命令実行して、かかったCPUサイクル数を返す
+  pub fn step_instruction(&mut self) -> usize {
+    self.step_instruction_with_callback(|_| {})
+  }
+
+  // callbackは命令を実行する直前に呼ばれる(トレース用)
+  pub fn step_instruction_with_callback<F>(&mut self, callback: F) -> usize
+  where
+    F: FnOnce(&mut CPU),
+  {
+    let before = self.cpu.bus.cycles();
+    let frame_count = self.cpu.bus.frame_count();
+    self.cpu.step_with_callback(callback);
+    let cycles = self.cpu.bus.cycles() - before;
+
+    self.generate_audio(cycles);
+    if self.cpu.bus.frame_count() != frame_count {
+      render::render(self.cpu.bus.ppu(), &self.palette, &mut self.frame);
+    }
+    cycles
+  }
+
+  // 次のvblankまで進めて、描画したフレームを返す
+  pub fn step_frame(&mut self) -> &Frame {
+    self.step_frame_with_callback(|_| {})
+  }
+
+  pub fn step_frame_with_callback<F>(&mut self, mut callback: F) -> &Frame
+  where
+    F: FnMut(&mut CPU),
+  {
+    let frame_count = self.cpu.bus.frame_count();
+    while self.cpu.bus.frame_count() == frame_count {
+      self.step_instruction_with_callback(&mut callback);
+    }
+    &self.frame
+  }
+
+  // 命令の途中では止められないので、実際に進んだサイクル数(>= cycles)を返す
+  pub fn run_cycles(&mut self, cycles: usize) -> usize {
+    let mut elapsed = 0;
+    while elapsed < cycles {
+      elapsed += self.step_instruction();
+    }
+    elapsed
+  }
+
+  pub fn frame(&self) -> &Frame {
+    &self.frame
+  }
+
+  // 今のフレームもすぐに新しいパレットで描き直す
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.palette = palette;
+    render::render(self.cpu.bus.ppu(), &self.palette, &mut self.frame);
+  }
+
+  pub fn palette(&self) -> &Palette {
+    &self.palette
+  }
+
+  pub fn frame_count(&self) -> usize {
+    self.cpu.bus.frame_count()
+  }
+
+  pub fn region(&self) -> Region {
+    self.cpu.bus.region()
+  }
+
+  pub fn set_region(&mut self, region: Region) {
+    self.cpu.bus.set_region(region);
+  }
+
+  // 次に書き換えるまで押されたままになる
+  pub fn set_buttons(&mut self, player: Player, buttons: JoypadButton) {
+    match player {
+      Player::ONE => self.cpu.bus.joypad1().set_buttons(buttons),
+      Player::TWO => self.cpu.bus.joypad2().set_buttons(buttons),
+    }
+  }
+
+  // falseにすると1ラインに9個以上のスプライトを表示する(ちらつきが減る)
+  pub fn set_sprite_limit(&mut self, enabled: bool) {
+    self.cpu.bus.ppu_mut().set_sprite_limit(enabled);
+  }
+
+  // 前回取り出してからのサンプル(SAMPLE_RATE, モノラル)
+  // 取り出さないと溜まり続けるので、毎フレーム呼ぶこと
+  pub fn take_audio_samples(&mut self) -> Vec<f32> {
+    std::mem::take(&mut self.audio_samples)
+  }
+
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut writer = savestate::write_header(self.rom_hash);
+    self.cpu.save_state(&mut writer);
+    writer.into_inner()
+  }
+
+  // 失敗した場合は読み込む前の状態に戻す
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+    let mut reader = savestate::read_header(data, self.rom_hash)?;
+    let backup = self.save_state();
+    let result = self.cpu.load_state(&mut reader).and_then(|_| {
+      if reader.is_empty() {
+        Ok(())
+      } else {
+        Err(StateError::Invalid("trailing data".to_string()))
+      }
+    });
+    if let Err(err) = result {
+      let mut reader = savestate::read_header(&backup, self.rom_hash).unwrap();
+      self.cpu.load_state(&mut reader).unwrap();
+      return Err(err);
+    }
+    render::render(self.cpu.bus.ppu(), &self.palette, &mut self.frame);
+    Ok(())
+  }
+
+  pub fn cpu(&mut self) -> &mut CPU {
+    &mut self.cpu
+  }
+
+  fn generate_audio(&mut self, cycles: usize) {
+    self.audio_cycles += cycles as f32 * SAMPLE_RATE / self.region().cpu_clock();
+    let count = self.audio_cycles as usize;
+    if count == 0 {
+      return;
+    }
+    self.audio_cycles -= count as f32;
+    let start = self.audio_samples.len();
+    self.audio_samples.resize(start + count, 0.0);
+    self.audio.fill(&mut self.audio_samples[start..]);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bus::Mem;
+  use crate::rom;
+
+  // $8000: JMP $8000 を繰り返すだけのNROM
+  fn nes() -> Nes {
+    nes_with_rom_byte(0)
+  }
+
+  // ROMのハッシュを変えるために使っていない場所に値を入れられるようにしておく
+  fn nes_with_rom_byte(value: u8) -> Nes {
+    let mut prg = vec![0; 0x3001];
+    prg[0..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+    prg[0x3000] = value;
+    Nes::new(rom::nrom(&prg, 0x8000, 0))
+  }
+
+  #[test]
+  fn test_step_instruction() {
+    let mut nes = nes();
+    assert_eq!(nes.cpu().program_counter, 0x8000);
+    assert_eq!(nes.step_instruction(), 3);
+    assert_eq!(nes.cpu().program_counter, 0x8000);
+  }
+
+  #[test]
+  fn test_run_cycles() {
+    let mut nes = nes();
+    assert_eq!(nes.run_cycles(100), 102);
+  }
+
+  #[test]
+  fn test_step_frame() {
+    let mut nes = nes();
+    nes.step_frame();
+    assert_eq!(nes.frame_count(), 1);
+    nes.take_audio_samples();
+
+    nes.step_frame();
+    assert_eq!(nes.frame_count(), 2);
+    // 1フレームは約29780サイクル = 約734サンプル
+    let samples = nes.take_audio_samples().len();
+    assert!((730..=740).contains(&samples), "{}", samples);
+  }
+
+  #[test]
+  fn test_pal_region() {
+    let mut nes = nes();
+    nes.set_region(Region::PAL);
+    nes.step_frame();
+    nes.take_audio_samples();
+
+    // 312ライン x 341ドット / 3.2 = 約33248サイクル = 約882サンプル
+    let before = nes.cpu().bus.cycles();
+    nes.step_frame();
+    let cycles = nes.cpu().bus.cycles() - before;
+    assert!((33245..=33251).contains(&cycles), "{}", cycles);
+    let samples = nes.take_audio_samples().len();
+    assert!((878..=886).contains(&samples), "{}", samples);
+  }
+
+  #[test]
+  fn test_set_buttons() {
+    let mut nes = nes();
+    nes.set_buttons(Player::TWO, JoypadButton::BUTTON_A);
+    nes.cpu().mem_write(0x4016, 1);
+    nes.cpu().mem_write(0x4016, 0);
+    assert_eq!(nes.cpu().mem_read(0x4016) & 1, 0);
+    assert_eq!(nes.cpu().mem_read(0x4017) & 1, 1);
+  }
+
+  #[test]
+  fn test_save_and_load_state() {
+    let mut nes = nes();
+    nes.step_frame();
+    nes.cpu().register_a = 0x42;
+    nes.cpu().mem_write(0x0010, 0x99);
+    let state = nes.save_state();
+
+    nes.step_frame();
+    nes.cpu().register_a = 0;
+    nes.cpu().mem_write(0x0010, 0);
+
+    nes.load_state(&state).unwrap();
+    assert_eq!(nes.frame_count(), 1);
+    assert_eq!(nes.cpu().register_a, 0x42);
+    assert_eq!(nes.cpu().mem_read(0x0010), 0x99);
+    assert_eq!(nes.save_state(), state);
+  }
+
+  #[test]
+  fn test_load_state_errors() {
+    let mut nes = nes();
+    let state = nes_with_rom_byte(1).save_state();
+    assert!(matches!(
+      nes.load_state(&state),
+      Err(StateError::RomMismatch { .. })
+    ));
+
+    // 途中で切れていたら元の状態のまま
+    nes.cpu().register_a = 0x42;
+    let state = nes.save_state();
+    nes.cpu().register_a = 0;
+    assert!(matches!(
+      nes.load_state(&state[..state.len() - 1]),
+      Err(StateError::Truncated)
+    ));
+    assert_eq!(nes.cpu().register_a, 0);
+  }
+
+  #[test]
+  fn test_set_palette() {
+    let mut nes = nes();
+    nes.step_frame();
+    let before = nes.frame().data.clone();
+
+    // 全部白のパレット
+    nes.set_palette(Palette::from_pal(&[0xFF; 192]).unwrap());
+    assert!(nes.frame().data.iter().all(|value| *value == 0xFF));
+    assert_ne!(nes.frame().data, before);
+  }
+}
@@ -1,5 +1,7 @@
+use crate::bus::Mem;
 use crate::cpu::AddressingMode;
 use crate::cpu::CycleCalcMode;
+use crate::cpu::Instruction;
 use crate::cpu::OpCode;
 use crate::cpu::CPU;
 
@@ -2054,392 +2056,98 @@ lazy_static! {
       AddressingMode::Absolute_Y
     ),
   ];
-}
-
-pub fn call(cpu: &mut CPU, op: &OpCode) {
-  match op.name.replace("*", "").as_str() {
-    "ADC" => {
-      cpu.adc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "AND" => {
-      cpu.and(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ASL" => {
-      cpu.asl(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BCC" => {
-      cpu.bcc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BCS" => {
-      cpu.bcs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BEQ" => {
-      cpu.beq(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BIT" => {
-      cpu.bit(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BMI" => {
-      cpu.bmi(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BNE" => {
-      cpu.bne(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BPL" => {
-      cpu.bpl(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BRK" => {
-      cpu.brk(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BVC" => {
-      cpu.bvc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "BVS" => {
-      cpu.bvs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CLC" => {
-      cpu.clc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CLD" => {
-      cpu.cld(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CLI" => {
-      cpu.cli(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CLV" => {
-      cpu.clv(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CMP" => {
-      cpu.cmp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CPX" => {
-      cpu.cpx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "CPY" => {
-      cpu.cpy(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "DEC" => {
-      cpu.dec(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "DEX" => {
-      cpu.dex(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "DEY" => {
-      cpu.dey(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "EOR" => {
-      cpu.eor(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "INC" => {
-      cpu.inc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "INX" => {
-      cpu.inx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "INY" => {
-      cpu.iny(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "JMP" => {
-      cpu.jmp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "JSR" => {
-      cpu.jsr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LDA" => {
-      cpu.lda(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LDX" => {
-      cpu.ldx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LDY" => {
-      cpu.ldy(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LSR" => {
-      cpu.lsr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "NOP" => {
-      cpu.nop(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ORA" => {
-      cpu.ora(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "PHA" => {
-      cpu.pha(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "PHP" => {
-      cpu.php(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "PLA" => {
-      cpu.pla(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
 
-    "PLP" => {
-      cpu.plp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ROL" => {
-      cpu.rol(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ROR" => {
-      cpu.ror(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "RTI" => {
-      cpu.rti(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "RTS" => {
-      cpu.rts(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SBC" => {
-      cpu.sbc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SEC" => {
-      cpu.sec(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SED" => {
-      cpu.sed(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SEI" => {
-      cpu.sei(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "STA" => {
-      cpu.sta(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "STX" => {
-      cpu.stx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "STY" => {
-      cpu.sty(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "TAX" => {
-      cpu.tax(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "TAY" => {
-      cpu.tay(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "TSX" => {
-      cpu.tsx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "TXA" => {
-      cpu.txa(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "TXS" => {
-      cpu.txs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "TYA" => {
-      cpu.tya(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ANC" => {
-      cpu.anc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SAX" => {
-      cpu.sax(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ARR" => {
-      cpu.arr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ASR" => {
-      cpu.asr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LXA" => {
-      cpu.lxa(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SHA" => {
-      cpu.sha(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SBX" => {
-      cpu.sbx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "DCP" => {
-      cpu.dcp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ISB" => {
-      cpu.isb(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "JAM" => {
-      cpu.jam(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LAE" => {
-      cpu.lae(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "LAX" => {
-      cpu.lax(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "RLA" => {
-      cpu.rla(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "RRA" => {
-      cpu.rra(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SLO" => {
-      cpu.slo(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SRE" => {
-      cpu.sre(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SHX" => {
-      cpu.shx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SHY" => {
-      cpu.shy(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "ANE" => {
-      cpu.ane(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
-
-    "SHS" => {
-      cpu.shs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1;
-    }
+  // オペコードをそのまま添字にして引く表
+  pub static ref CPU_OPS_TABLE: [OpCode; 256] = std::array::from_fn(|code| {
+    CPU_OPS_CODES
+      .iter()
+      .find(|op| op.code as usize == code)
+      .unwrap_or_else(|| panic!("no opcode {:02X}", code))
+      .clone()
+  });
+}
 
-    _ => {
-      todo!()
-    }
+// 命令を実行する
+// 分岐やジャンプは実行後にbytes-1だけ進む分を見越してprogram_counterを設定している
+pub fn call<M: Mem>(cpu: &mut CPU<M>, op: &OpCode) {
+  let mode = &op.addressing_mode;
+  match op.instruction {
+    Instruction::ADC => cpu.adc(mode),
+    Instruction::AND => cpu.and(mode),
+    Instruction::ASL => cpu.asl(mode),
+    Instruction::BCC => cpu.bcc(mode),
+    Instruction::BCS => cpu.bcs(mode),
+    Instruction::BEQ => cpu.beq(mode),
+    Instruction::BIT => cpu.bit(mode),
+    Instruction::BMI => cpu.bmi(mode),
+    Instruction::BNE => cpu.bne(mode),
+    Instruction::BPL => cpu.bpl(mode),
+    Instruction::BRK => cpu.brk(mode),
+    Instruction::BVC => cpu.bvc(mode),
+    Instruction::BVS => cpu.bvs(mode),
+    Instruction::CLC => cpu.clc(mode),
+    Instruction::CLD => cpu.cld(mode),
+    Instruction::CLI => cpu.cli(mode),
+    Instruction::CLV => cpu.clv(mode),
+    Instruction::CMP => cpu.cmp(mode),
+    Instruction::CPX => cpu.cpx(mode),
+    Instruction::CPY => cpu.cpy(mode),
+    Instruction::DEC => cpu.dec(mode),
+    Instruction::DEX => cpu.dex(mode),
+    Instruction::DEY => cpu.dey(mode),
+    Instruction::EOR => cpu.eor(mode),
+    Instruction::INC => cpu.inc(mode),
+    Instruction::INX => cpu.inx(mode),
+    Instruction::INY => cpu.iny(mode),
+    Instruction::JMP => cpu.jmp(mode),
+    Instruction::JSR => cpu.jsr(mode),
+    Instruction::LDA => cpu.lda(mode),
+    Instruction::LDX => cpu.ldx(mode),
+    Instruction::LDY => cpu.ldy(mode),
+    Instruction::LSR => cpu.lsr(mode),
+    Instruction::NOP => cpu.nop(mode),
+    Instruction::ORA => cpu.ora(mode),
+    Instruction::PHA => cpu.pha(mode),
+    Instruction::PHP => cpu.php(mode),
+    Instruction::PLA => cpu.pla(mode),
+    Instruction::PLP => cpu.plp(mode),
+    Instruction::ROL => cpu.rol(mode),
+    Instruction::ROR => cpu.ror(mode),
+    Instruction::RTI => cpu.rti(mode),
+    Instruction::RTS => cpu.rts(mode),
+    Instruction::SBC => cpu.sbc(mode),
+    Instruction::SEC => cpu.sec(mode),
+    Instruction::SED => cpu.sed(mode),
+    Instruction::SEI => cpu.sei(mode),
+    Instruction::STA => cpu.sta(mode),
+    Instruction::STX => cpu.stx(mode),
+    Instruction::STY => cpu.sty(mode),
+    Instruction::TAX => cpu.tax(mode),
+    Instruction::TAY => cpu.tay(mode),
+    Instruction::TSX => cpu.tsx(mode),
+    Instruction::TXA => cpu.txa(mode),
+    Instruction::TXS => cpu.txs(mode),
+    Instruction::TYA => cpu.tya(mode),
+    Instruction::ANC => cpu.anc(mode),
+    Instruction::SAX => cpu.sax(mode),
+    Instruction::ARR => cpu.arr(mode),
+    Instruction::ASR => cpu.asr(mode),
+    Instruction::LXA => cpu.lxa(mode),
+    Instruction::SHA => cpu.sha(mode),
+    Instruction::SBX => cpu.sbx(mode),
+    Instruction::DCP => cpu.dcp(mode),
+    Instruction::ISB => cpu.isb(mode),
+    Instruction::JAM => cpu.jam(mode),
+    Instruction::LAE => cpu.lae(mode),
+    Instruction::LAX => cpu.lax(mode),
+    Instruction::RLA => cpu.rla(mode),
+    Instruction::RRA => cpu.rra(mode),
+    Instruction::SLO => cpu.slo(mode),
+    Instruction::SRE => cpu.sre(mode),
+    Instruction::SHX => cpu.shx(mode),
+    Instruction::SHY => cpu.shy(mode),
+    Instruction::ANE => cpu.ane(mode),
+    Instruction::SHS => cpu.shs(mode),
   }
+  cpu.program_counter = cpu.program_counter.wrapping_add(op.bytes - 1);
 }
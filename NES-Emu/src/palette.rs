@@ -1,3 +1,7 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::path::Path;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +19,276 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// PPUMASKの強調ビット8通り x 64色
+pub const EMPHASIS_PALETTE_SIZE: usize = 64 * 8;
+
+// 強調した色以外の成分を暗くする割合
+// see: https://www.nesdev.org/wiki/NTSC_video#Color_Tint_Bits
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+// 64色のパレットから、PPUの出力(強調ビット << 6 | 色)で引ける512色のパレットを作る
+// $xE, $xFの列は黒なので強調の影響を受けない
+pub fn with_emphasis(colors: &[(u8, u8, u8); 64]) -> Vec<(u8, u8, u8)> {
+  let mut palette = Vec::with_capacity(EMPHASIS_PALETTE_SIZE);
+  for emphasis in 0..8 {
+    for (i, &(r, g, b)) in colors.iter().enumerate() {
+      if emphasis == 0 || i & 0x0F >= 0x0E {
+        palette.push((r, g, b));
+        continue;
+      }
+      // bit0: 赤, bit1: 緑, bit2: 青
+      // 強調ビット1つごとに他の2つの成分を暗くする(全部立てると全体が暗くなる)
+      let attenuate = |value: u8, bit: u8| {
+        let count = (emphasis & !bit).count_ones() as i32;
+        (value as f32 * EMPHASIS_ATTENUATION.powi(count)).round() as u8
+      };
+      palette.push((
+        attenuate(r, 0b001),
+        attenuate(g, 0b010),
+        attenuate(b, 0b100),
+      ));
+    }
+  }
+  palette
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+  // 64色(192バイト)か512色(1536バイト)以外
+  InvalidSize(usize),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for PaletteError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PaletteError::InvalidSize(len) => {
+        write!(f, "Palette must be 192 or 1536 bytes, got {} bytes", len)
+      }
+      PaletteError::Io(err) => write!(f, "IO error: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<std::io::Error> for PaletteError {
+  fn from(err: std::io::Error) -> Self {
+    PaletteError::Io(err)
+  }
+}
+
+// PPUの出力(強調ビット << 6 | 色)からRGBを引く512色のパレット
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+  colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+  // 強調なしの64色から作る
+  pub fn new(colors: &[(u8, u8, u8); 64]) -> Self {
+    Palette {
+      colors: with_emphasis(colors),
+    }
+  }
+
+  // .palファイルの中身(RGBの順に1色3バイト)
+  // 192バイトなら強調の分を計算し、1536バイトなら強調の分もファイルのものを使う
+  pub fn from_pal(data: &[u8]) -> Result<Self, PaletteError> {
+    let colors: Vec<(u8, u8, u8)> = data
+      .chunks_exact(3)
+      .map(|rgb| (rgb[0], rgb[1], rgb[2]))
+      .collect();
+    match data.len() {
+      192 => Ok(Palette::new(colors.as_slice().try_into().unwrap())),
+      1536 => Ok(Palette { colors: colors }),
+      len => Err(PaletteError::InvalidSize(len)),
+    }
+  }
+
+  pub fn load(path: &Path) -> Result<Self, PaletteError> {
+    let data = std::fs::read(path)?;
+    Palette::from_pal(&data)
+  }
+
+  // NTSCのコンポジット信号をデコードしてパレットを作る
+  // see: https://www.nesdev.org/wiki/NTSC_video#Emulating_in_software
+  pub fn ntsc(params: &NtscParams) -> Self {
+    let colors = (0..EMPHASIS_PALETTE_SIZE)
+      .map(|index| ntsc_color(index, params))
+      .collect();
+    Palette { colors: colors }
+  }
+
+  // index = 強調ビット << 6 | 色 (NesPPU::pixelsの値)
+  pub fn color(&self, index: u16) -> (u8, u8, u8) {
+    self.colors[index as usize]
+  }
+
+  // 1536バイトの.palファイルの中身
+  pub fn to_pal(&self) -> Vec<u8> {
+    self
+      .colors
+      .iter()
+      .flat_map(|&(r, g, b)| [r, g, b])
+      .collect()
+  }
+}
+
+impl Default for Palette {
+  fn default() -> Self {
+    Palette::new(&SYSTEM_PALLETE)
+  }
+}
+
+// NTSCパレットの調整値
+// デフォルトは補正なし(gammaはモニターのガンマで、2.2なら信号をそのまま使う)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NtscParams {
+  // 色相を回す角度(度)
+  pub hue: f32,
+  pub saturation: f32,
+  pub contrast: f32,
+  pub brightness: f32,
+  pub gamma: f32,
+}
+
+impl Default for NtscParams {
+  fn default() -> Self {
+    NtscParams {
+      hue: 0.0,
+      saturation: 1.0,
+      contrast: 1.0,
+      brightness: 1.0,
+      gamma: 2.2,
+    }
+  }
+}
+
+// 信号の電圧(V) 輝度ごとの低い方と高い方
+const NTSC_LEVELS_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const NTSC_LEVELS_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const NTSC_BLACK: f32 = 0.518;
+const NTSC_WHITE: f32 = 1.962;
+
+fn ntsc_color(index: usize, params: &NtscParams) -> (u8, u8, u8) {
+  let color = index & 0x0F;
+  let emphasis = index >> 6;
+  // $xE, $xFは黒
+  let level = if color >= 0x0E {
+    1
+  } else {
+    (index >> 4) & 0b11
+  };
+  // $x0は高い方だけ、$xD~は低い方だけの矩形波になる
+  let low = if color == 0x00 {
+    NTSC_LEVELS_HIGH[level]
+  } else {
+    NTSC_LEVELS_LOW[level]
+  };
+  let high = if color < 0x0D {
+    NTSC_LEVELS_HIGH[level]
+  } else {
+    NTSC_LEVELS_LOW[level]
+  };
+
+  // 色副搬送波の1周期を12位相に分けてYIQを求める
+  let in_phase = |phase: usize, color: usize| (color + phase + 8) % 12 < 6;
+  let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+  for phase in 0..12 {
+    let mut signal = if in_phase(phase, color) { high } else { low };
+    // 強調ビットはそれぞれ赤(色$C)、緑(色$4)、青(色$8)の位相で信号を弱める
+    if (emphasis & 0b001 != 0 && in_phase(phase, 0x0C))
+      || (emphasis & 0b010 != 0 && in_phase(phase, 0x04))
+      || (emphasis & 0b100 != 0 && in_phase(phase, 0x08))
+    {
+      signal *= EMPHASIS_ATTENUATION;
+    }
+    let mut value = (signal - NTSC_BLACK) / (NTSC_WHITE - NTSC_BLACK);
+    value = ((value - 0.5) * params.contrast + 0.5) * params.brightness / 12.0;
+    let angle = PI / 6.0 * phase as f32 + params.hue.to_radians();
+    y += value;
+    i += value * angle.cos();
+    q += value * angle.sin();
+  }
+  i *= params.saturation;
+  q *= params.saturation;
+
+  let to_u8 = |value: f32| {
+    let value = if value <= 0.0 {
+      0.0
+    } else {
+      value.powf(2.2 / params.gamma)
+    };
+    (value * 255.95).clamp(0.0, 255.0) as u8
+  };
+  (
+    to_u8(y + 0.946882 * i + 0.623557 * q),
+    to_u8(y - 0.274788 * i - 0.635691 * q),
+    to_u8(y - 1.108545 * i + 1.709007 * q),
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_with_emphasis() {
+    let palette = with_emphasis(&SYSTEM_PALLETE);
+    assert_eq!(palette.len(), EMPHASIS_PALETTE_SIZE);
+    assert_eq!(palette[0x20], (0xFF, 0xFF, 0xFF));
+    // 赤を強調すると緑と青が暗くなる
+    assert_eq!(palette[1 << 6 | 0x20], (0xFF, 0xBE, 0xBE));
+    // 全部強調すると全体が暗くなる
+    assert_eq!(palette[7 << 6 | 0x20], (0x8E, 0x8E, 0x8E));
+    assert_eq!(palette[7 << 6 | 0x0F], SYSTEM_PALLETE[0x0F]);
+  }
+
+  #[test]
+  fn test_from_pal() {
+    let mut data = vec![0; 192];
+    data[0x20 * 3..0x20 * 3 + 3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    let palette = Palette::from_pal(&data).unwrap();
+    assert_eq!(palette.color(0x20), (0xFF, 0xFF, 0xFF));
+    assert_eq!(palette.color(1 << 6 | 0x20), (0xFF, 0xBE, 0xBE));
+
+    // 1536バイトなら強調の分もそのまま使う
+    let mut data = palette.to_pal();
+    assert_eq!(data.len(), 1536);
+    data[(1 << 6 | 0x20) * 3..(1 << 6 | 0x20) * 3 + 3].copy_from_slice(&[1, 2, 3]);
+    let palette = Palette::from_pal(&data).unwrap();
+    assert_eq!(palette.color(1 << 6 | 0x20), (1, 2, 3));
+
+    assert!(matches!(
+      Palette::from_pal(&[0; 100]),
+      Err(PaletteError::InvalidSize(100))
+    ));
+  }
+
+  #[test]
+  fn test_ntsc() {
+    let palette = Palette::ntsc(&NtscParams::default());
+    assert_eq!(palette.color(0x0F), (0, 0, 0));
+    assert_eq!(palette.color(0x1D), (0, 0, 0));
+    assert_eq!(palette.color(0x30), (255, 255, 255));
+    // $16は赤っぽく、$1Aは緑っぽく、$12は青っぽい
+    let (r, g, b) = palette.color(0x16);
+    assert!(r > g && r > b, "{:?}", (r, g, b));
+    let (r, g, b) = palette.color(0x1A);
+    assert!(g > r && g > b, "{:?}", (r, g, b));
+    let (r, g, b) = palette.color(0x12);
+    assert!(b > r && b > g, "{:?}", (r, g, b));
+
+    // 赤を強調すると白から緑と青が減る
+    let (r, g, b) = palette.color(1 << 6 | 0x30);
+    assert!(r > g && r > b, "{:?}", (r, g, b));
+
+    let dark = Palette::ntsc(&NtscParams {
+      brightness: 0.5,
+      ..NtscParams::default()
+    });
+    assert!(dark.color(0x30).0 < 200);
+  }
+}
@@ -1,74 +1,164 @@
-use crate::{cpu::IN_TRACE, rom::Mirroring};
-use bitflags::{bitflags, Flags};
-use log::{debug, info, trace};
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::{mapper::Cartridge, rom::Mirroring};
+use bitflags::bitflags;
+use log::{debug, trace};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// loopyのv/tの各部分 (yyy NN YYYYY XXXXX)
+// see: https://www.nesdev.org/wiki/PPU_scrolling
+const COARSE_X: u16 = 0b000_00_00000_11111;
+const COARSE_Y: u16 = 0b000_00_11111_00000;
+const NAMETABLE_X: u16 = 0b000_01_00000_00000;
+const NAMETABLE_Y: u16 = 0b000_10_00000_00000;
+const FINE_Y: u16 = 0b111_00_00000_00000;
 
 pub struct NesPPU {
-  pub chr_rom: Vec<u8>,
+  cartridge: Cartridge,
   pub palette_table: [u8; 32],
-  pub vram: [u8; 2048],
+  // 4画面のカートリッジは追加の2KBを含めて4KB
+  pub vram: Vec<u8>,
 
   pub oam_addr: u8,
   pub oam_data: [u8; 256],
 
-  pub mirroring: Mirroring,
-  addr: AddrResister,        // 0x2006 (0x2007)
   pub ctrl: ControlRegister, // 0x2000
   internal_data_buf: u8,
 
-  // TODO mask 0x2001
   mask: MaskRegister,
-  // TODO status 0x2002
   status: StatusRegister,
-  // TODO OAM Address 0x2003, 0x2004
-  // TODO scroll 0x2005
-  pub scroll: ScrollRegister,
-  // TODO OAM DMA 0x4015
+
+  // v: 現在のVRAMアドレス, t: 次のフレーム(ライン)の開始アドレス
+  // fine_x: タイル内のXスクロール, w: $2005/$2006の1回目と2回目の切り替え
+  v: u16,
+  t: u16,
+  fine_x: u8,
+  w: bool,
+
+  // 次のタイルのフェッチ結果
+  next_tile_id: u8,
+  next_tile_attr: u8,
+  next_tile_lo: u8,
+  next_tile_hi: u8,
+  // 上位8ビットが今のタイル、下位8ビットが次のタイル
+  bg_shifter_lo: u16,
+  bg_shifter_hi: u16,
+  bg_shifter_attr_lo: u16,
+  bg_shifter_attr_hi: u16,
+
+  // 次のラインに表示するスプライト
+  line_sprites: Vec<LineSprite>,
+  // falseなら1ラインに9個以上のスプライトも表示する(ちらつき防止)
+  // オーバーフローフラグは実機と同じように立てる
+  sprite_limit: bool,
+
+  // フレームのライン数やvblankに入るライン
+  region: Region,
   scanline: usize,
   cycles: usize,
+  // 電源を入れてからのPPUのサイクル数(マッパーのA12検出に渡す)
+  total_cycles: usize,
+  // 奇数フレームは描画中ならプリレンダーラインが1ドット短い
+  odd_frame: bool,
   pub nmi_interrupt: Option<i32>,
   pub clear_nmi_interrupt: bool,
-  // 同期的にパレットの色を変えるためのもの
-  pub scanline_palette_indexes: Vec<usize>,
-  pub scanline_palette_tables: Vec<[u8; 32]>,
+
+  // 出力した画素の色 (RGBへの変換はrender)
+  // 下位6ビットがパレットの値、上位3ビットがPPUMASKの強調ビット
+  pixels: Vec<u16>,
+}
+
+// ラインバッファに入れたスプライト1つ分
+// パターンは左端のドットが最上位ビットになるように左右反転を済ませておく
+#[derive(Clone, Copy)]
+struct LineSprite {
+  x: u8,
+  attr: u8,
+  pattern_lo: u8,
+  pattern_hi: u8,
+  // OAMの0番目のスプライト(スプライト0ヒットの判定に使う)
+  sprite_zero: bool,
+  // このラインで読むパターンのアドレス(下位プレーン)
+  addr: u16,
+}
+
+impl LineSprite {
+  // 画面のxでの色 (範囲外は透明の0)
+  fn pixel(&self, x: usize) -> u8 {
+    let offset = x.wrapping_sub(self.x as usize);
+    if offset >= 8 {
+      return 0;
+    }
+    let bit = 7 - offset;
+    ((self.pattern_hi >> bit) & 1) << 1 | ((self.pattern_lo >> bit) & 1)
+  }
 }
 
 impl NesPPU {
-  pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+  pub fn new(cartridge: Cartridge) -> Self {
+    let vram_size = if cartridge.borrow().mirroring() == Mirroring::FOUR_SCREEN {
+      0x1000
+    } else {
+      0x800
+    };
     NesPPU {
-      chr_rom: chr_rom,
-      mirroring: mirroring,
-      vram: [0; 2048],
+      cartridge: cartridge,
+      vram: vec![0; vram_size],
       oam_addr: 0,
       oam_data: [0; 64 * 4],
       palette_table: [0; 32],
       ctrl: ControlRegister::new(),
-      addr: AddrResister::new(),
       status: StatusRegister::new(),
       mask: MaskRegister::new(),
-      scroll: ScrollRegister::new(),
       internal_data_buf: 0,
+      v: 0,
+      t: 0,
+      fine_x: 0,
+      w: false,
+      next_tile_id: 0,
+      next_tile_attr: 0,
+      next_tile_lo: 0,
+      next_tile_hi: 0,
+      bg_shifter_lo: 0,
+      bg_shifter_hi: 0,
+      bg_shifter_attr_lo: 0,
+      bg_shifter_attr_hi: 0,
+      line_sprites: Vec::with_capacity(64),
+      sprite_limit: true,
+      region: Region::NTSC,
       scanline: 0,
       cycles: 0,
+      total_cycles: 0,
+      odd_frame: false,
       nmi_interrupt: None,
       clear_nmi_interrupt: false,
-      scanline_palette_indexes: vec![],
-      scanline_palette_tables: vec![],
+      pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
     }
   }
 
   pub fn write_to_ppu_addr(&mut self, value: u8) {
-    self.addr.update(value);
+    if !self.w {
+      // 上位6ビット (最上位ビットは0になる)
+      self.t = (self.t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+    } else {
+      self.t = (self.t & 0xFF00) | value as u16;
+      self.v = self.t;
+      self.notify_ppu_bus(self.v & 0x3FFF);
+    }
+    self.w = !self.w;
   }
 
   pub fn write_to_data(&mut self, value: u8) {
-    let addr = self.addr.get();
-    if !unsafe { IN_TRACE } {
-      self.increment_vram_addr();
-    }
+    let addr = self.v & 0x3FFF;
+    self.notify_ppu_bus(addr);
+    self.increment_vram_addr();
 
     match addr {
       0..=0x1FFF => {
-        debug!("write CHR_ROM {:04X} => {:02X}", addr, value);
+        debug!("write CHR {:04X} => {:02X}", addr, value);
+        self.cartridge.borrow_mut().write_chr(addr, value);
       }
       0x2000..=0x2FFF => {
         trace!(
@@ -77,7 +167,8 @@ impl NesPPU {
           self.mirror_vram_addr(addr) as usize,
           value
         );
-        self.vram[self.mirror_vram_addr(addr) as usize] = value;
+        let index = self.mirror_vram_addr(addr) as usize;
+        self.vram[index] = value;
       }
       0x3000..=0x3EFF => {
         trace!(
@@ -86,9 +177,10 @@ impl NesPPU {
           self.mirror_vram_addr(addr) as usize,
           value
         );
-        self.vram[self.mirror_vram_addr(addr) as usize] = value;
+        let index = self.mirror_vram_addr(addr) as usize;
+        self.vram[index] = value;
       }
-      0x3F00..=0x3F1F => {
+      0x3F00..=0x3FFF => {
         debug!(
           "WRITE PALATTE {:04X} {:02X} => ({:02X}) SL={}",
           addr,
@@ -96,66 +188,12 @@ impl NesPPU {
           value,
           self.scanline
         );
-        self.write_palette_table(addr, value);
-      }
-      0x3F20..=0x3FFF => {
-        debug!(
-          "WRITE PALATTE MIRROR {:04X} {:02X} => ({:02X}) SL={}",
-          addr,
-          self.mirror_palette_addr(addr),
-          value,
-          self.scanline
-        );
-        self.write_palette_table(addr, value);
+        self.palette_table[self.mirror_palette_addr(addr) as usize] = value;
       }
       _ => panic!("unexpected access to mittord space {}", addr),
     }
   }
 
-  fn write_palette_table(&mut self, addr: u16, value: u8) {
-    let addr = self.mirror_palette_addr(addr) as usize;
-
-    // palette_tableには最新情報を入れておく
-    self.palette_table[addr] = value;
-
-    let scanline = self.scanline;
-    let last_scanline = self.scanline_palette_indexes.last().unwrap_or(&0);
-    if *last_scanline != scanline {
-      self.scanline_palette_indexes.push(scanline);
-      self
-        .scanline_palette_tables
-        .push(self.palette_table.clone());
-    } else {
-      self.scanline_palette_indexes.pop();
-      self
-        .scanline_palette_tables
-        .push(self.palette_table.clone());
-    }
-  }
-
-  fn clear_palette_table_histories(&mut self) {
-    self.scanline_palette_indexes = vec![];
-    self.scanline_palette_tables = vec![];
-  }
-
-  pub fn read_palette_table(&self, scanline: usize) -> &[u8; 32] {
-    if self.scanline_palette_indexes.is_empty() {
-      return &self.palette_table;
-    }
-
-    let mut index = 0;
-    for (i, s) in self.scanline_palette_indexes.iter().enumerate() {
-      if *s > scanline {
-        break;
-      }
-      index = i
-    }
-    info!("RPT SL={}, INDEX={}", scanline, index);
-    let table = &self.scanline_palette_tables[index];
-    info!("  => {:?}", table);
-    table
-  }
-
   fn mirror_palette_addr(&self, addr: u16) -> u16 {
     // see: https://taotao54321.hatenablog.com/entry/2017/04/11/115205
     let addr = addr & 0b0001_1111;
@@ -169,25 +207,28 @@ impl NesPPU {
   }
 
   pub fn write_to_ctrl(&mut self, value: u8) {
-    let before_nmi_status = self.ctrl.generate_vblank_nmi();
+    let before_nmi_status = self.ctrl.generate_nmi();
 
     self.ctrl.update(value);
-    if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
+    // ネームテーブルの選択はtに入る
+    self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y)) | ((value as u16 & 0b11) << 10);
+    if !before_nmi_status && self.ctrl.generate_nmi() && self.status.is_in_vblank() {
       self.nmi_interrupt = Some(1);
     }
   }
 
   pub fn read_status(&mut self) -> u8 {
-    // スクロール($2005)PPU_STATUSを読み取ってアドレスラッチをリセットしたあと
-    if unsafe { IN_TRACE } {
-      return self.status.bits();
-    } else {
-      self.scroll.reset();
-      let bits = self.status.bits();
-      self.status.reset_vblank_status();
-      self.clear_nmi_interrupt = true;
-      return bits;
-    }
+    // $2005/$2006の書き込みラッチをリセットする
+    self.w = false;
+    let bits = self.status.bits();
+    self.status.reset_vblank_status();
+    self.clear_nmi_interrupt = true;
+    return bits;
+  }
+
+  // VBlankフラグやラッチに触れずに$2002の値を返す
+  pub fn peek_status(&self) -> u8 {
+    self.status.bits()
   }
 
   pub fn write_to_status(&mut self, value: u8) {
@@ -219,55 +260,52 @@ impl NesPPU {
   }
 
   pub fn write_to_scroll(&mut self, value: u8) {
-    self.scroll.set(value);
+    if !self.w {
+      self.t = (self.t & !COARSE_X) | (value as u16 >> 3);
+      self.fine_x = value & 0b111;
+    } else {
+      self.t = (self.t & !(COARSE_Y | FINE_Y))
+        | ((value as u16 >> 3) << 5)
+        | ((value as u16 & 0b111) << 12);
+    }
+    self.w = !self.w;
   }
 
   fn increment_vram_addr(&mut self) {
-    self.addr.increment(self.ctrl.vram_addr_increment());
+    if self.is_rendering() {
+      // 描画中はフェッチ用のインクリメントが両方かかる
+      self.increment_scroll_x();
+      self.increment_scroll_y();
+    } else {
+      self.v = (self.v + self.ctrl.vram_addr_increment() as u16) & 0x7FFF;
+    }
   }
 
   pub fn read_data(&mut self) -> u8 {
-    let addr = self.addr.get();
-    if !unsafe { IN_TRACE } {
-      self.increment_vram_addr();
-    }
+    let addr = self.v & 0x3FFF;
+    self.notify_ppu_bus(addr);
+    self.increment_vram_addr();
     debug!("READ PPU: {:04X}", addr);
 
     match addr {
       0..=0x1FFF => {
-        if unsafe { IN_TRACE } {
-          self.internal_data_buf
-        } else {
-          let result = self.internal_data_buf;
-          self.internal_data_buf = self.chr_rom[addr as usize];
-          result
-        }
+        let result = self.internal_data_buf;
+        self.internal_data_buf = self.read_chr(addr);
+        result
       }
       0x2000..=0x2FFF => {
-        if unsafe { IN_TRACE } {
-          self.internal_data_buf
-        } else {
-          let result = self.internal_data_buf;
-          self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
-          result
-        }
+        let result = self.internal_data_buf;
+        self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+        result
       }
       0x3000..=0x3EFF => {
-        if unsafe { IN_TRACE } {
-          self.internal_data_buf
-        } else {
-          let result = self.internal_data_buf;
-          self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
-          result
-        }
+        let result = self.internal_data_buf;
+        self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+        result
       }
       0x3F00..=0x3F1F => {
-        if unsafe { IN_TRACE } {
-          self.internal_data_buf
-        } else {
-          self.internal_data_buf = self.palette_table[self.mirror_palette_addr(addr) as usize];
-          self.internal_data_buf
-        }
+        self.internal_data_buf = self.palette_table[self.mirror_palette_addr(addr) as usize];
+        self.internal_data_buf
       }
       0x3F20..=0x3FFF => {
         // TODO
@@ -277,108 +315,560 @@ impl NesPPU {
     }
   }
 
+  // アドレスを進めずバッファも更新せずに$2007の値を返す
+  pub fn peek_data(&self) -> u8 {
+    let addr = self.v & 0x3FFF;
+    match addr {
+      0x3F00..=0x3F1F => self.palette_table[self.mirror_palette_addr(addr) as usize],
+      0x3F20..=0x3FFF => 0,
+      _ => self.internal_data_buf,
+    }
+  }
+
+  pub fn read_chr(&self, addr: u16) -> u8 {
+    self.cartridge.borrow().read_chr(addr)
+  }
+
+  pub fn mirroring(&self) -> Mirroring {
+    self.cartridge.borrow().mirroring()
+  }
+
   pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
     let mirrored_vram = addr & 0b10_1111_1111_1111;
     let vram_index = mirrored_vram - 0x2000;
     let name_table = vram_index / 0x400;
 
-    match (&self.mirroring, name_table) {
+    match (self.mirroring(), name_table) {
       (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
       (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
       (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
       (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+      (Mirroring::SINGLE_SCREEN_LOWER, _) => vram_index & 0x3FF,
+      (Mirroring::SINGLE_SCREEN_UPPER, _) => 0x400 | (vram_index & 0x3FF),
+      // カートリッジ側のVRAMで4枚とも別々
+      (Mirroring::FOUR_SCREEN, _) => vram_index,
       _ => vram_index,
     }
   }
 
+  // 1フレーム終わったらtrue
   pub fn tick(&mut self, cycles: u8) -> bool {
-    self.cycles += cycles as usize;
-    if self.cycles >= 341 {
-      if self.is_sprite_zero_hit(self.cycles) {
-        self.status.set_sprite_zero_hit(true);
+    let mut frame_end = false;
+    for _ in 0..cycles {
+      frame_end |= self.step_dot();
+    }
+    frame_end
+  }
+
+  // 1ドット進める
+  // see: https://www.nesdev.org/wiki/PPU_rendering
+  fn step_dot(&mut self) -> bool {
+    let dot = self.cycles;
+    let visible = self.scanline < 240;
+    let pre_render = self.scanline == self.pre_render_scanline();
+
+    if pre_render && dot == 1 {
+      self.status.set_sprite_zero_hit(false);
+      self.status.set_sprite_overflow(false);
+      self.status.reset_vblank_status();
+      self.nmi_interrupt = None;
+    }
+    if (visible || pre_render) && self.is_rendering_enabled() {
+      self.fetch_background(dot, pre_render);
+      if dot == 257 {
+        // OAMADDRは257~320の間0になる
+        self.oam_addr = 0;
+        if visible {
+          self.evaluate_sprites();
+        } else {
+          self.line_sprites.clear();
+        }
+      }
+      if (257..=320).contains(&dot) {
+        self.fetch_sprite_patterns(dot);
+      }
+    }
+    if visible && (1..=256).contains(&dot) {
+      self.output_pixel(dot - 1);
+    }
+    if self.scanline == self.region.vblank_scanline() && dot == 1 {
+      self.status.set_vblank_status(true);
+      if self.ctrl.generate_nmi() {
+        self.nmi_interrupt = Some(1);
       }
-      self.cycles = self.cycles - 341;
-      self.scanline += 1;
-
-      if self.scanline == 241 {
-        self.status.set_vblank_status(true);
-        self.status.set_sprite_zero_hit(false);
-        if self.ctrl.generate_vblank_nmi() {
-          self.nmi_interrupt = Some(1);
+    }
+
+    self.cycles += 1;
+    self.total_cycles += 1;
+    if pre_render
+      && dot == 339
+      && self.odd_frame
+      && self.region.skips_odd_frame_dot()
+      && self.is_rendering_enabled()
+    {
+      self.cycles += 1;
+    }
+    if self.cycles < 341 {
+      return false;
+    }
+    self.cycles = 0;
+    self.scanline += 1;
+    if self.scanline >= self.region.scanlines() {
+      self.scanline = 0;
+      self.odd_frame = !self.odd_frame;
+      return true;
+    }
+    false
+  }
+
+  fn is_rendering_enabled(&self) -> bool {
+    self.mask.show_background() || self.mask.show_sprites()
+  }
+
+  // 描画中のライン(プリレンダーラインを含む)でVRAMのフェッチが動いているか
+  fn is_rendering(&self) -> bool {
+    self.is_rendering_enabled()
+      && (self.scanline < 240 || self.scanline == self.pre_render_scanline())
+  }
+
+  fn pre_render_scanline(&self) -> usize {
+    self.region.scanlines() - 1
+  }
+
+  // BGのタイルを8ドットごとに、ネームテーブル、属性、パターン下位、パターン上位の順に読む
+  fn fetch_background(&mut self, dot: usize, pre_render: bool) {
+    if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+      self.shift_background();
+    }
+    if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+      match (dot - 1) % 8 {
+        0 => {
+          self.load_background_shifters();
+          self.next_tile_id = self.read_vram(0x2000 | (self.v & 0x0FFF));
+        }
+        2 => {
+          let v = self.v;
+          let attr = self.read_vram(0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+          // 属性は4x4タイルを2x2タイルずつに分けた4つ分
+          let shift = ((v >> 4) & 0b100) | (v & 0b10);
+          self.next_tile_attr = (attr >> shift) & 0b11;
+        }
+        4 => {
+          let addr = self.background_pattern_row();
+          self.next_tile_lo = self.fetch_pattern(addr);
         }
+        6 => {
+          let addr = self.background_pattern_row() + 8;
+          self.next_tile_hi = self.fetch_pattern(addr);
+        }
+        7 => self.increment_scroll_x(),
+        _ => {}
       }
+    }
+    if dot == 256 {
+      self.increment_scroll_y();
+    }
+    if dot == 257 {
+      self.load_background_shifters();
+      // 水平方向をtから戻す
+      let mask = COARSE_X | NAMETABLE_X;
+      self.v = (self.v & !mask) | (self.t & mask);
+    }
+    if pre_render && (280..=304).contains(&dot) {
+      // 垂直方向をtから戻す
+      let mask = COARSE_Y | NAMETABLE_Y | FINE_Y;
+      self.v = (self.v & !mask) | (self.t & mask);
+    }
+  }
+
+  fn background_pattern_row(&self) -> u16 {
+    self.ctrl.background_pattern_addr() + self.next_tile_id as u16 * 16 + (self.v >> 12)
+  }
 
-      if self.scanline >= 262 {
-        self.scanline = 0;
-        self.status.set_sprite_zero_hit(false);
-        self.status.reset_vblank_status();
-        self.nmi_interrupt = None;
-        self.clear_palette_table_histories();
-        return true;
+  fn shift_background(&mut self) {
+    self.bg_shifter_lo <<= 1;
+    self.bg_shifter_hi <<= 1;
+    self.bg_shifter_attr_lo <<= 1;
+    self.bg_shifter_attr_hi <<= 1;
+  }
+
+  fn load_background_shifters(&mut self) {
+    self.bg_shifter_lo = (self.bg_shifter_lo & 0xFF00) | self.next_tile_lo as u16;
+    self.bg_shifter_hi = (self.bg_shifter_hi & 0xFF00) | self.next_tile_hi as u16;
+    let attr_lo = if self.next_tile_attr & 0b01 != 0 {
+      0xFF
+    } else {
+      0x00
+    };
+    let attr_hi = if self.next_tile_attr & 0b10 != 0 {
+      0xFF
+    } else {
+      0x00
+    };
+    self.bg_shifter_attr_lo = (self.bg_shifter_attr_lo & 0xFF00) | attr_lo;
+    self.bg_shifter_attr_hi = (self.bg_shifter_attr_hi & 0xFF00) | attr_hi;
+  }
+
+  fn increment_scroll_x(&mut self) {
+    if self.v & COARSE_X == 31 {
+      // 隣のネームテーブルへ
+      self.v &= !COARSE_X;
+      self.v ^= NAMETABLE_X;
+    } else {
+      self.v += 1;
+    }
+  }
+
+  fn increment_scroll_y(&mut self) {
+    if self.v & FINE_Y != FINE_Y {
+      self.v += 0x1000;
+      return;
+    }
+    self.v &= !FINE_Y;
+    let mut coarse_y = (self.v & COARSE_Y) >> 5;
+    if coarse_y == 29 {
+      // 30行目は属性テーブルなので下のネームテーブルへ
+      coarse_y = 0;
+      self.v ^= NAMETABLE_Y;
+    } else if coarse_y == 31 {
+      // 属性テーブルを指していた場合はネームテーブルを切り替えずに折り返す
+      coarse_y = 0;
+    } else {
+      coarse_y += 1;
+    }
+    self.v = (self.v & !COARSE_Y) | (coarse_y << 5);
+  }
+
+  // ネームテーブルと属性テーブルを読む
+  fn read_vram(&self, addr: u16) -> u8 {
+    self.vram[self.mirror_vram_addr(addr) as usize]
+  }
+
+  fn sprite_height(&self) -> usize {
+    if self.ctrl.is_sprite_8x16_mode() {
+      16
+    } else {
+      8
+    }
+  }
+
+  fn is_sprite_in_range(&self, y: u8) -> bool {
+    let y = y as usize;
+    y <= self.scanline && self.scanline < y + self.sprite_height()
+  }
+
+  // 次のラインに表示するスプライトを集める
+  // OAMのYは表示されるラインの1つ上
+  // see: https://www.nesdev.org/wiki/PPU_sprite_evaluation
+  fn evaluate_sprites(&mut self) {
+    self.line_sprites.clear();
+    let mut n = 0;
+    while n < 64 && self.line_sprites.len() < 8 {
+      if self.is_sprite_in_range(self.oam_data[n * 4]) {
+        self.push_sprite(n);
       }
+      n += 1;
+    }
+    let rest = n;
 
-      if self.scanline == 257 {
-        // OAMDDRは、プリレンダリング及び表示可能のスキャンラインのティック257~320(スプライトタイルの読み込み間隔)のそれぞれの間に0に設定されます。
-        self.oam_addr = 0;
+    // 8個見つかった次のスプライトから9個目を探す
+    // このときnと一緒にスプライト内のバイト位置mも進めてしまう(実機のバグ)
+    // そのためYではないバイトで判定して、見逃したり誤って立てたりする
+    let mut m = 0;
+    while n < 64 {
+      if self.is_sprite_in_range(self.oam_data[n * 4 + m]) {
+        self.status.set_sprite_overflow(true);
+        break;
+      }
+      n += 1;
+      m = (m + 1) & 0b11;
+    }
+
+    if !self.sprite_limit {
+      for n in rest..64 {
+        if self.is_sprite_in_range(self.oam_data[n * 4]) {
+          self.push_sprite(n);
+        }
       }
     }
-    return false;
   }
 
-  fn is_sprite_zero_hit(&self, cycle: usize) -> bool {
-    let y = self.oam_data[0] as usize;
-    let x = self.oam_data[3] as usize;
-    (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
+  // スプライトnをラインバッファに入れる
+  // パターンは257~320のフェッチで読む
+  fn push_sprite(&mut self, n: usize) {
+    let y = self.oam_data[n * 4] as usize;
+    let tile_idx = self.oam_data[n * 4 + 1];
+    let attr = self.oam_data[n * 4 + 2];
+    let mut row = (self.scanline - y) as u16;
+    if attr & 0b1000_0000 != 0 {
+      row = self.sprite_height() as u16 - 1 - row;
+    }
+    self.line_sprites.push(LineSprite {
+      x: self.oam_data[n * 4 + 3],
+      attr: attr,
+      pattern_lo: 0,
+      pattern_hi: 0,
+      sprite_zero: n == 0,
+      addr: self.sprite_pattern_row(tile_idx, row),
+    });
   }
-}
 
-pub struct AddrResister {
-  value: (u8, u8),
-  hi_ptr: bool,
-}
-impl AddrResister {
-  pub fn new() -> Self {
-    AddrResister {
-      value: (0, 0), // high byte first,lobyte second
-      hi_ptr: true,
+  fn sprite_pattern_row(&self, tile_idx: u8, row: u16) -> u16 {
+    let tile_idx = tile_idx as u16;
+    // 8x16ではタイル番号の最下位ビットでパターンテーブルを選び、上下に2枚並べる
+    if self.ctrl.is_sprite_8x16_mode() {
+      let bank = (tile_idx & 1) * 0x1000;
+      let tile_idx = (tile_idx & 0xFE) + row / 8;
+      bank + tile_idx * 16 + row % 8
+    } else {
+      self.ctrl.sprite_pattern_addr() + tile_idx * 16 + row
     }
   }
 
-  pub fn set(&mut self, data: u16) {
-    self.value.0 = (data >> 8) as u8;
-    self.value.1 = (data & 0xFF) as u8;
+  // 257~320: 8スロット分のスプライトのパターンを1スロット8ドットで読む
+  // 空きスロットはタイル$FFを読んで捨てる
+  fn fetch_sprite_patterns(&mut self, dot: usize) {
+    let slot = (dot - 257) / 8;
+    match (dot - 257) % 8 {
+      4 => self.fetch_sprite_pattern(slot, false),
+      6 => self.fetch_sprite_pattern(slot, true),
+      _ => {}
+    }
+    // 制限を外して9個以上並んでいるときは、残りを最後にまとめて読む
+    if dot == 320 {
+      for slot in 8..self.line_sprites.len() {
+        self.fetch_sprite_pattern(slot, false);
+        self.fetch_sprite_pattern(slot, true);
+      }
+    }
   }
 
-  pub fn update(&mut self, data: u8) {
-    if self.hi_ptr {
-      self.value.0 = data;
+  fn fetch_sprite_pattern(&mut self, slot: usize, hi: bool) {
+    let plane = if hi { 8 } else { 0 };
+    let sprite = match self.line_sprites.get(slot) {
+      Some(sprite) => *sprite,
+      None => {
+        self.fetch_pattern(self.sprite_pattern_row(0xFF, 0) + plane);
+        return;
+      }
+    };
+    let mut pattern = self.fetch_pattern(sprite.addr + plane);
+    // 左右反転はここで済ませておく
+    if sprite.attr & 0b0100_0000 != 0 {
+      pattern = pattern.reverse_bits();
+    }
+    if hi {
+      self.line_sprites[slot].pattern_hi = pattern;
+    } else {
+      self.line_sprites[slot].pattern_lo = pattern;
+    }
+  }
+
+  // BGとスプライトを合成して1ドット出力する
+  fn output_pixel(&mut self, x: usize) {
+    // 左端8ドットはそれぞれのビットが立っていなければ隠す(スクロールの継ぎ目隠し)
+    let left = x < 8;
+    let mut bg_pixel = 0;
+    let mut bg_palette = 0;
+    if self.mask.show_background() && !(left && !self.mask.show_background_in_left()) {
+      let bit = 0x8000 >> self.fine_x;
+      bg_pixel =
+        (((self.bg_shifter_hi & bit) != 0) as u8) << 1 | ((self.bg_shifter_lo & bit) != 0) as u8;
+      bg_palette = (((self.bg_shifter_attr_hi & bit) != 0) as u8) << 1
+        | ((self.bg_shifter_attr_lo & bit) != 0) as u8;
+    }
+
+    let mut palette_addr = if bg_pixel == 0 {
+      0
     } else {
-      self.value.1 = data;
+      bg_palette * 4 + bg_pixel
+    };
+    if bg_pixel != 0 && self.is_sprite_zero_hit(x) {
+      self.status.set_sprite_zero_hit(true);
+    }
+    if self.mask.show_sprites() && !(left && !self.mask.show_sprites_in_left()) {
+      if let Some((sprite_attr, sprite_pixel)) = self.sprite_pixel(x) {
+        // 優先度のビットが立っていたらBGの不透明なドットの後ろに隠れる
+        let behind_background = sprite_attr & 0b0010_0000 != 0;
+        if bg_pixel == 0 || !behind_background {
+          palette_addr = 0x10 + (sprite_attr & 0b11) * 4 + sprite_pixel;
+        }
+      }
     }
 
-    if self.get() > 0x3fff {
-      self.set(self.get() & 0b11_1111_1111_1111);
+    // 描画が止まっている間にvがパレットを指していると、その色がそのまま出る
+    let palette_addr = if !self.is_rendering_enabled() && self.v & 0x3F00 == 0x3F00 {
+      self.v & 0x1F
+    } else {
+      palette_addr as u16
+    };
+    let mut color = self.palette_table[self.mirror_palette_addr(palette_addr) as usize] & 0x3F;
+    if self.mask.is_greyscale() {
+      color &= 0x30;
     }
-    self.hi_ptr = !self.hi_ptr;
+    self.pixels[self.scanline * SCREEN_WIDTH + x] = self.mask.emphasis() << 6 | color as u16;
+  }
+
+  // xで最初に見つかった不透明なスプライトの属性と色
+  // 後ろのスプライトはBGの後ろに隠れるスプライトの下でも表示されない
+  fn sprite_pixel(&self, x: usize) -> Option<(u8, u8)> {
+    for sprite in &self.line_sprites {
+      let pixel = sprite.pixel(x);
+      if pixel != 0 {
+        return Some((sprite.attr, pixel));
+      }
+    }
+    None
+  }
+
+  // パターンテーブルを読む
+  fn fetch_pattern(&self, addr: u16) -> u8 {
+    self.notify_ppu_bus(addr);
+    self.read_chr(addr)
+  }
+
+  // アドレスバスに出したアドレスをマッパーに伝える(MMC3のA12検出)
+  fn notify_ppu_bus(&self, addr: u16) {
+    self
+      .cartridge
+      .borrow_mut()
+      .on_ppu_fetch(addr, self.total_cycles);
+  }
+
+  pub fn scanline(&self) -> usize {
+    self.scanline
+  }
+
+  pub fn set_sprite_limit(&mut self, enabled: bool) {
+    self.sprite_limit = enabled;
+  }
+
+  pub fn set_region(&mut self, region: Region) {
+    self.region = region;
+  }
+
+  // スキャンライン内の位置(ドット)
+  pub fn dot(&self) -> usize {
+    self.cycles
+  }
+
+  // 256x240の色 (0x000~0x1FF、palette::with_emphasisの添字)
+  pub fn pixels(&self) -> &[u16] {
+    &self.pixels
   }
 
-  pub fn increment(&mut self, inc: u8) {
-    let lo = self.value.1;
-    self.value.1 = self.value.1.wrapping_add(inc);
-    if lo > self.value.1 {
-      self.value.0 = self.value.0.wrapping_add(1);
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    writer.write_bytes(&self.palette_table);
+    writer.write_bytes(&self.vram);
+    writer.write_u8(self.oam_addr);
+    writer.write_bytes(&self.oam_data);
+    writer.write_u8(self.ctrl.bits());
+    writer.write_u8(self.internal_data_buf);
+    writer.write_u8(self.mask.bits());
+    writer.write_u8(self.status.bits());
+    writer.write_u16(self.v);
+    writer.write_u16(self.t);
+    writer.write_u8(self.fine_x);
+    writer.write_bool(self.w);
+    writer.write_u8(self.next_tile_id);
+    writer.write_u8(self.next_tile_attr);
+    writer.write_u8(self.next_tile_lo);
+    writer.write_u8(self.next_tile_hi);
+    writer.write_u16(self.bg_shifter_lo);
+    writer.write_u16(self.bg_shifter_hi);
+    writer.write_u16(self.bg_shifter_attr_lo);
+    writer.write_u16(self.bg_shifter_attr_hi);
+    writer.write_usize(self.line_sprites.len());
+    for sprite in &self.line_sprites {
+      writer.write_u8(sprite.x);
+      writer.write_u8(sprite.attr);
+      writer.write_u8(sprite.pattern_lo);
+      writer.write_u8(sprite.pattern_hi);
+      writer.write_bool(sprite.sprite_zero);
+      writer.write_u16(sprite.addr);
     }
-    if self.get() > 0x3FFF {
-      self.set(self.get() & 0b11_1111_1111_1111);
+    writer.write_usize(self.scanline);
+    writer.write_usize(self.cycles);
+    writer.write_usize(self.total_cycles);
+    writer.write_bool(self.odd_frame);
+    writer.write_bool(self.nmi_interrupt.is_some());
+    writer.write_bool(self.clear_nmi_interrupt);
+    // 読み込んだ直後も同じ画面を出せるように
+    for pixel in &self.pixels {
+      writer.write_u16(*pixel);
     }
   }
 
-  pub fn reset_latch(&mut self) {
-    self.hi_ptr = true;
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    reader.read_into(&mut self.palette_table)?;
+    reader.read_into(&mut self.vram)?;
+    self.oam_addr = reader.read_u8()?;
+    reader.read_into(&mut self.oam_data)?;
+    self.ctrl = ControlRegister::from_bits_retain(reader.read_u8()?);
+    self.internal_data_buf = reader.read_u8()?;
+    self.mask = MaskRegister::from_bits_retain(reader.read_u8()?);
+    self.status = StatusRegister::from_bits_retain(reader.read_u8()?);
+    self.v = reader.read_u16()?;
+    self.t = reader.read_u16()?;
+    self.fine_x = reader.read_u8()?;
+    self.w = reader.read_bool()?;
+    self.next_tile_id = reader.read_u8()?;
+    self.next_tile_attr = reader.read_u8()?;
+    self.next_tile_lo = reader.read_u8()?;
+    self.next_tile_hi = reader.read_u8()?;
+    self.bg_shifter_lo = reader.read_u16()?;
+    self.bg_shifter_hi = reader.read_u16()?;
+    self.bg_shifter_attr_lo = reader.read_u16()?;
+    self.bg_shifter_attr_hi = reader.read_u16()?;
+    let len = reader.read_usize()?;
+    if len > 64 {
+      return Err(StateError::Invalid(format!("{} sprites on a line", len)));
+    }
+    self.line_sprites.clear();
+    for _ in 0..len {
+      self.line_sprites.push(LineSprite {
+        x: reader.read_u8()?,
+        attr: reader.read_u8()?,
+        pattern_lo: reader.read_u8()?,
+        pattern_hi: reader.read_u8()?,
+        sprite_zero: reader.read_bool()?,
+        addr: reader.read_u16()?,
+      });
+    }
+    self.scanline = reader.read_usize()?;
+    self.cycles = reader.read_usize()?;
+    self.total_cycles = reader.read_usize()?;
+    self.odd_frame = reader.read_bool()?;
+    self.nmi_interrupt = if reader.read_bool()? { Some(1) } else { None };
+    self.clear_nmi_interrupt = reader.read_bool()?;
+    for pixel in self.pixels.iter_mut() {
+      *pixel = reader.read_u16()?;
+    }
+    Ok(())
+  }
+
+  pub fn irq_pending(&self) -> bool {
+    self.cartridge.borrow().irq_pending()
   }
 
-  pub fn get(&self) -> u16 {
-    ((self.value.0 as u16) << 8) | (self.value.1 as u16)
+  // BGの不透明なドットにスプライト0の不透明なドットが重なったか
+  // 優先度や前にある他のスプライトには関係なく判定する
+  // see: https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+  fn is_sprite_zero_hit(&self, x: usize) -> bool {
+    if !self.mask.show_background() || !self.mask.show_sprites() {
+      return false;
+    }
+    // 左端8ドットが隠されている間とx=255では起きない
+    if x < 8 && (!self.mask.show_background_in_left() || !self.mask.show_sprites_in_left()) {
+      return false;
+    }
+    if x == 255 {
+      return false;
+    }
+    match self.line_sprites.first() {
+      Some(sprite) if sprite.sprite_zero => sprite.pixel(x) != 0,
+      _ => false,
+    }
   }
 }
 
@@ -412,10 +902,8 @@ impl ControlRegister {
     *self.0.bits_mut() = data;
   }
 
-  pub fn generate_vblank_nmi(&mut self) -> bool {
-    let last_status = self.contains(ControlRegister::GENERATE_NMI);
-    self.set(ControlRegister::GENERATE_NMI, true);
-    last_status
+  pub fn generate_nmi(&self) -> bool {
+    self.contains(ControlRegister::GENERATE_NMI)
   }
 
   pub fn background_pattern_addr(&self) -> u16 {
@@ -430,12 +918,8 @@ impl ControlRegister {
     self.contains(ControlRegister::SPRITE_SIZE)
   }
 
+  // 8x16モードではタイル番号で決まるので使わない
   pub fn sprite_pattern_addr(&self) -> u16 {
-    // ignore in 8x16 mode
-    // FIXME
-    // if self.is_sprite_8x16_mode() {
-    //   return 0x0000;
-    // }
     if !self.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
       0x0000
     } else {
@@ -488,6 +972,9 @@ impl StatusRegister {
   pub fn set_sprite_zero_hit(&mut self, value: bool) {
     self.set(StatusRegister::SPRITE_ZERO_HIT, value)
   }
+  pub fn set_sprite_overflow(&mut self, value: bool) {
+    self.set(StatusRegister::SPRITE_OVERFLOW, value)
+  }
 }
 
 bitflags! {
@@ -514,33 +1001,469 @@ impl MaskRegister {
   pub fn show_sprites(&self) -> bool {
     self.contains(MaskRegister::SHOW_SPRITES)
   }
-}
 
-pub struct ScrollRegister {
-  pub scroll_x: u8,
-  pub scroll_y: u8,
-  writte_x: bool,
+  pub fn show_background(&self) -> bool {
+    self.contains(MaskRegister::SHOW_BACKGROUND)
+  }
+
+  pub fn is_greyscale(&self) -> bool {
+    self.contains(MaskRegister::GREYSCALE)
+  }
+
+  // 強調ビット (bit0: 赤, bit1: 緑, bit2: 青)
+  pub fn emphasis(&self) -> u16 {
+    (self.bits() >> 5) as u16
+  }
+
+  pub fn show_sprites_in_left(&self) -> bool {
+    self.contains(MaskRegister::SHOW_SPRITES_IN_LEFT)
+  }
+
+  pub fn show_background_in_left(&self) -> bool {
+    self.contains(MaskRegister::SHOW_BACKGROUND_IN_LEFT)
+  }
 }
 
-impl ScrollRegister {
-  pub fn new() -> Self {
-    ScrollRegister {
-      scroll_x: 0,
-      scroll_y: 0,
-      writte_x: true,
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::rom;
+
+  // CHR_RAMのNROM(水平ミラー)
+  // タイル1は全ドットが色1、ネームテーブル$2000の列1に縦一列並べる
+  // 描画中に$2007を使うとvが崩れるので、VRAMは描画を有効にする前に書く
+  fn ppu() -> NesPPU {
+    let mut ppu = NesPPU::new(rom::nrom(&[], 0x8000, 0).cartridge);
+
+    write_vram(&mut ppu, 0x0010, &[0xFF; 8]);
+    for row in 0..30 {
+      write_vram(&mut ppu, 0x2000 + row * 32 + 1, &[0x01]);
     }
+    write_vram(&mut ppu, 0x3F00, &[0x0F, 0x30]);
+    write_vram(&mut ppu, 0x3F11, &[0x16]);
+    ppu.write_to_mask(0b0000_1010);
+    ppu
   }
 
-  fn set(&mut self, data: u8) {
-    if self.writte_x {
-      self.scroll_x = data;
-    } else {
-      self.scroll_y = data;
+  fn write_vram(ppu: &mut NesPPU, addr: u16, data: &[u8]) {
+    ppu.write_to_ppu_addr((addr >> 8) as u8);
+    ppu.write_to_ppu_addr(addr as u8);
+    for value in data {
+      ppu.write_to_data(*value);
+    }
+  }
+
+  // $2006で書き換わったネームテーブルの選択も$2000で戻す
+  fn set_scroll(ppu: &mut NesPPU, x: u8, y: u8) {
+    ppu.write_to_ctrl(0);
+    ppu.read_status();
+    ppu.write_to_scroll(x);
+    ppu.write_to_scroll(y);
+  }
+
+  fn run_until(ppu: &mut NesPPU, scanline: usize, dot: usize) {
+    while ppu.scanline() != scanline || ppu.dot() != dot {
+      ppu.tick(1);
+    }
+  }
+
+  fn run_frame(ppu: &mut NesPPU) {
+    while !ppu.tick(1) {}
+  }
+
+  // スプライトは色0x16、BGとスプライトを両方表示する
+  fn set_sprite(ppu: &mut NesPPU, n: usize, y: u8, tile: u8, attr: u8, x: u8) {
+    ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[y, tile, attr, x]);
+    ppu.write_to_mask(0b0001_1110);
+  }
+
+  fn pixel(ppu: &NesPPU, x: usize, y: usize) -> u16 {
+    ppu.pixels()[y * SCREEN_WIDTH + x]
+  }
+
+  #[test]
+  fn test_background() {
+    let mut ppu = ppu();
+    set_scroll(&mut ppu, 0, 0);
+    // 最初のフレームはプリレンダーラインでvが設定されるまで崩れている
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 7, 0), 0x0F);
+    assert_eq!(pixel(&ppu, 8, 0), 0x30);
+    assert_eq!(pixel(&ppu, 15, 239), 0x30);
+    assert_eq!(pixel(&ppu, 16, 239), 0x0F);
+  }
+
+  #[test]
+  fn test_fine_scroll_x() {
+    let mut ppu = ppu();
+    set_scroll(&mut ppu, 3, 0);
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 4, 100), 0x0F);
+    assert_eq!(pixel(&ppu, 5, 100), 0x30);
+    assert_eq!(pixel(&ppu, 12, 100), 0x30);
+    assert_eq!(pixel(&ppu, 13, 100), 0x0F);
+  }
+
+  #[test]
+  fn test_mid_frame_scroll_split() {
+    let mut ppu = ppu();
+    set_scroll(&mut ppu, 0, 0);
+    run_frame(&mut ppu);
+    // ラインの途中で書き換えると、次のラインから水平スクロールが変わる
+    run_until(&mut ppu, 100, 100);
+    set_scroll(&mut ppu, 8, 0);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 8, 100), 0x30);
+    assert_eq!(pixel(&ppu, 0, 100), 0x0F);
+    assert_eq!(pixel(&ppu, 0, 101), 0x30);
+    assert_eq!(pixel(&ppu, 8, 101), 0x0F);
+  }
+
+  // MMC3、スキャンラインカウンタを10にしてIRQを有効にしておく
+  fn mmc3_ppu() -> NesPPU {
+    let ppu = NesPPU::new(rom::nrom(&[], 0x8000, 0x40).cartridge);
+    {
+      let mut cartridge = ppu.cartridge.borrow_mut();
+      cartridge.write_prg(0xC000, 10);
+      cartridge.write_prg(0xC001, 0);
+      cartridge.write_prg(0xE001, 0);
     }
-    self.writte_x = !self.writte_x;
+    ppu
+  }
+
+  #[test]
+  fn test_mmc3_scanline_counter() {
+    let mut ppu = mmc3_ppu();
+    // BG=$0000, スプライト=$1000
+    // 空きスロットのタイル$FFのフェッチで毎ライン1回だけA12が立ち上がる
+    run_until(&mut ppu, 261, 0);
+    ppu.write_to_ctrl(0b0000_1000);
+    ppu.write_to_mask(0b0001_1000);
+    // プリレンダーラインで10を読み込み、ライン9で0になる
+    run_until(&mut ppu, 9, 261);
+    assert!(!ppu.irq_pending());
+    run_until(&mut ppu, 9, 262);
+    assert!(ppu.irq_pending());
   }
 
-  pub fn reset(&mut self) {
-    self.writte_x = true;
+  #[test]
+  fn test_mmc3_8x16_sprite_fetches() {
+    let mut ppu = mmc3_ppu();
+    // 8x16で偶数タイルのスプライトが8個並ぶラインは$0000しか読まないので数えない
+    for n in 0..8 {
+      ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[0, 0x00, 0, 0]);
+    }
+    for n in 8..64 {
+      ppu.oam_data[n * 4] = 0xF0;
+    }
+    run_until(&mut ppu, 261, 0);
+    ppu.write_to_ctrl(0b0010_0000);
+    ppu.write_to_mask(0b0001_1000);
+    run_until(&mut ppu, 25, 261);
+    assert!(!ppu.irq_pending());
+    run_until(&mut ppu, 25, 262);
+    assert!(ppu.irq_pending());
+  }
+
+  #[test]
+  fn test_mmc3_ppu_addr_a12() {
+    let mut ppu = mmc3_ppu();
+    // 描画を止めていても$2006で出したアドレスのA12で数える
+    ppu.tick(9);
+    ppu.write_to_ppu_addr(0x10);
+    ppu.write_to_ppu_addr(0x00); // reload => 10
+    for _ in 0..10 {
+      ppu.write_to_ppu_addr(0x00);
+      ppu.write_to_ppu_addr(0x00);
+      // すぐに戻すと短いLowとして無視される
+      ppu.write_to_ppu_addr(0x10);
+      ppu.write_to_ppu_addr(0x00);
+      assert!(!ppu.irq_pending());
+      ppu.write_to_ppu_addr(0x00);
+      ppu.write_to_ppu_addr(0x00);
+      ppu.tick(9);
+      ppu.write_to_ppu_addr(0x10);
+      ppu.write_to_ppu_addr(0x00);
+    }
+    assert!(ppu.irq_pending());
+  }
+
+  #[test]
+  fn test_dendy_vblank() {
+    let mut ppu = ppu();
+    ppu.set_region(Region::DENDY);
+    // 312ラインで、vblankはポストレンダーラインの後の291から
+    run_until(&mut ppu, 241, 2);
+    assert_eq!(ppu.peek_status() & 0x80, 0);
+    run_until(&mut ppu, 291, 2);
+    assert_eq!(ppu.peek_status() & 0x80, 0x80);
+    run_until(&mut ppu, 311, 2);
+    assert_eq!(ppu.peek_status() & 0x80, 0);
+    run_until(&mut ppu, 311, 340);
+    assert!(ppu.tick(1));
+  }
+
+  #[test]
+  fn test_nmi_disabled() {
+    let mut ppu = ppu();
+    ppu.write_to_ctrl(0b1000_0000);
+    run_until(&mut ppu, 241, 2);
+    assert_eq!(ppu.nmi_interrupt, Some(1));
+    ppu.nmi_interrupt = None;
+
+    // bit7を落としたら、vblank中に$00を書いても次のvblankでもNMIは出ない
+    ppu.write_to_ctrl(0);
+    ppu.write_to_ctrl(0);
+    assert_eq!(ppu.nmi_interrupt, None);
+    for _ in 0..2 {
+      run_until(&mut ppu, 0, 0);
+      run_until(&mut ppu, 241, 2);
+      assert_eq!(ppu.nmi_interrupt, None);
+    }
+  }
+
+  #[test]
+  fn test_scroll_registers_share_latch() {
+    let mut ppu = ppu();
+    ppu.read_status();
+    ppu.write_to_scroll(0x7D);
+    // $2005の1回目の後に$2006を書くと2回目の書き込みになる
+    ppu.write_to_ppu_addr(0xEF);
+    assert_eq!(ppu.fine_x, 0b101);
+    assert_eq!(ppu.t & 0x00FF, 0xEF);
+    assert_eq!(ppu.v, ppu.t);
+  }
+
+  #[test]
+  fn test_sprite_limit_and_overflow() {
+    let mut ppu = ppu();
+    for n in 0..9 {
+      set_sprite(&mut ppu, n, 100, 0x01, 0, 32 + n as u8 * 16);
+    }
+    set_scroll(&mut ppu, 0, 0);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert_eq!(pixel(&ppu, 32 + 7 * 16, 101), 0x16);
+    // 9個目は表示されない
+    assert_eq!(pixel(&ppu, 32 + 8 * 16, 101), 0x0F);
+    assert_eq!(ppu.peek_status() & 0b0010_0000, 0b0010_0000);
+
+    ppu.set_sprite_limit(false);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert_eq!(pixel(&ppu, 32 + 8 * 16, 101), 0x16);
+    assert_eq!(ppu.peek_status() & 0b0010_0000, 0b0010_0000);
+
+    // プリレンダーラインでクリアされる
+    ppu.oam_data[8 * 4] = 0xF0;
+    run_until(&mut ppu, 261, 2);
+    assert_eq!(ppu.peek_status() & 0b0010_0000, 0);
+  }
+
+  #[test]
+  fn test_sprite_overflow_bug() {
+    let mut ppu = ppu();
+    // 使わないスプライトはどのラインにも掛からないY=$F0に置いておく
+    for n in 0..64 {
+      ppu.oam_data[n * 4] = 0xF0;
+    }
+    for n in 0..8 {
+      set_sprite(&mut ppu, n, 100, 0x01, 0, 0);
+    }
+    // 9個目以降はYではなくタイル番号などを見てしまう
+    // スプライト9のタイル番号(m=1)がラインの範囲に入ると誤ってフラグが立つ
+    set_sprite(&mut ppu, 8, 0xF0, 0x01, 0, 0);
+    set_sprite(&mut ppu, 9, 0xF0, 100, 0, 0);
+    set_scroll(&mut ppu, 0, 0);
+    run_until(&mut ppu, 100, 258);
+    assert_eq!(ppu.peek_status() & 0b0010_0000, 0b0010_0000);
+
+    // 逆に9個目のYがラインに掛かっていても、m=1でタイル番号を見るので見逃す
+    run_until(&mut ppu, 0, 0);
+    set_sprite(&mut ppu, 8, 0xF0, 0x00, 0, 0);
+    set_sprite(&mut ppu, 9, 100, 0x00, 0, 0);
+    run_until(&mut ppu, 200, 0);
+    assert_eq!(ppu.peek_status() & 0b0010_0000, 0);
+  }
+
+  #[test]
+  fn test_sprite_8x16() {
+    let mut ppu = ppu();
+    // タイル3 = $1000のタイル2(上半分)と3(下半分)
+    ppu.write_to_mask(0);
+    write_vram(&mut ppu, 0x1030, &[0xFF; 8]);
+    ppu.ctrl.update(0b0010_0000);
+    set_sprite(&mut ppu, 0, 100, 0x03, 0, 64);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 64, 101), 0x0F);
+    assert_eq!(pixel(&ppu, 64, 109), 0x16);
+    assert_eq!(pixel(&ppu, 64, 116), 0x16);
+    assert_eq!(pixel(&ppu, 64, 117), 0x0F);
+
+    // 上下反転すると2枚の順番も入れ替わる
+    set_sprite(&mut ppu, 0, 100, 0x03, 0b1000_0000, 64);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 64, 101), 0x16);
+    assert_eq!(pixel(&ppu, 64, 109), 0x0F);
+  }
+
+  #[test]
+  fn test_sprite_behind_background() {
+    let mut ppu = ppu();
+    // BGのタイル1(x = 8~15)にまたがるように置く
+    set_sprite(&mut ppu, 0, 100, 0x01, 0b0010_0000, 4);
+    set_scroll(&mut ppu, 0, 0);
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 4, 101), 0x16);
+    assert_eq!(pixel(&ppu, 8, 101), 0x30);
+
+    // 前のスプライトが後ろに隠れる場合は後ろのスプライトも見えない
+    set_sprite(&mut ppu, 1, 100, 0x01, 0, 4);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 8, 101), 0x30);
+  }
+
+  fn sprite_zero_hit(ppu: &NesPPU) -> bool {
+    ppu.peek_status() & 0b0100_0000 != 0
+  }
+
+  #[test]
+  fn test_sprite_zero_hit() {
+    let mut ppu = ppu();
+    // BGのタイル1(x = 8~15)と重なるのはx = 8から
+    set_sprite(&mut ppu, 0, 100, 0x01, 0, 4);
+    set_scroll(&mut ppu, 0, 0);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 101, 9);
+    assert!(!sprite_zero_hit(&ppu));
+    ppu.tick(1);
+    assert!(sprite_zero_hit(&ppu));
+
+    // VBlankでは消えず、プリレンダーラインで消える
+    run_until(&mut ppu, 261, 1);
+    assert!(sprite_zero_hit(&ppu));
+    ppu.tick(1);
+    assert!(!sprite_zero_hit(&ppu));
+  }
+
+  #[test]
+  fn test_sprite_zero_hit_needs_opaque_pixels() {
+    let mut ppu = ppu();
+    // BGが透明な場所
+    set_sprite(&mut ppu, 0, 100, 0x01, 0, 100);
+    // スプライト1はヒットしない
+    set_sprite(&mut ppu, 1, 100, 0x01, 0, 8);
+    set_scroll(&mut ppu, 0, 0);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert!(!sprite_zero_hit(&ppu));
+
+    // BGの後ろに隠れていてもヒットする
+    set_sprite(&mut ppu, 0, 100, 0x01, 0b0010_0000, 8);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert!(sprite_zero_hit(&ppu));
+  }
+
+  #[test]
+  fn test_sprite_zero_hit_left_clip() {
+    let mut ppu = ppu();
+    // BGのタイル1を画面の左端(x = 0~7)に出す
+    set_sprite(&mut ppu, 0, 100, 0x01, 0, 0);
+    ppu.write_to_mask(0b0001_1000);
+    set_scroll(&mut ppu, 8, 0);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert!(!sprite_zero_hit(&ppu));
+
+    ppu.write_to_mask(0b0001_1110);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert!(sprite_zero_hit(&ppu));
+  }
+
+  #[test]
+  fn test_sprite_zero_hit_right_edge() {
+    let mut ppu = ppu();
+    // BGのタイル1を画面の右端(x = 248~255)に出す
+    set_sprite(&mut ppu, 0, 100, 0x01, 0, 255);
+    set_scroll(&mut ppu, 16, 0);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert!(!sprite_zero_hit(&ppu));
+
+    set_sprite(&mut ppu, 0, 100, 0x01, 0, 254);
+    run_frame(&mut ppu);
+    run_until(&mut ppu, 200, 0);
+    assert!(sprite_zero_hit(&ppu));
+  }
+
+  #[test]
+  fn test_mask_greyscale_and_emphasis() {
+    let mut ppu = ppu();
+    write_vram(&mut ppu, 0x3F01, &[0x16]);
+    ppu.write_to_mask(0b1010_1001);
+    set_scroll(&mut ppu, 0, 0);
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 8, 0), 0b101 << 6 | 0x10);
+    assert_eq!(pixel(&ppu, 0, 0), 0b101 << 6 | 0x00);
+  }
+
+  #[test]
+  fn test_mask_left_clip() {
+    let mut ppu = ppu();
+    // BGのタイル1とスプライトを画面の左端(x = 0~7)に出す
+    set_sprite(&mut ppu, 0, 100, 0x01, 0, 0);
+    ppu.write_to_mask(0b0001_1000);
+    set_scroll(&mut ppu, 8, 0);
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 0, 50), 0x0F);
+    assert_eq!(pixel(&ppu, 0, 101), 0x0F);
+
+    ppu.write_to_mask(0b0001_1010);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 0, 50), 0x30);
+    assert_eq!(pixel(&ppu, 0, 101), 0x30);
+
+    ppu.write_to_mask(0b0001_1100);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 0, 50), 0x0F);
+    assert_eq!(pixel(&ppu, 0, 101), 0x16);
+  }
+
+  #[test]
+  fn test_rendering_disabled_shows_palette_at_v() {
+    let mut ppu = ppu();
+    ppu.write_to_mask(0);
+    write_vram(&mut ppu, 0x2000, &[]);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 0, 0), 0x0F);
+
+    // vがパレットを指したまま
+    write_vram(&mut ppu, 0x3F11, &[]);
+    run_frame(&mut ppu);
+    assert_eq!(pixel(&ppu, 0, 0), 0x16);
+  }
+
+  #[test]
+  fn test_four_screen_nametables() {
+    // ヘッダーのbit3で4画面
+    let mut ppu = NesPPU::new(rom::nrom(&[], 0x8000, 0b0000_1000).cartridge);
+    assert_eq!(ppu.mirroring(), Mirroring::FOUR_SCREEN);
+
+    for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+      write_vram(&mut ppu, *addr, &[i as u8 + 1]);
+    }
+    for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+      write_vram(&mut ppu, *addr, &[]);
+      // 最初の読み込みはバッファの値
+      ppu.read_data();
+      assert_eq!(ppu.read_data(), i as u8 + 1, "{:04X}", addr);
+    }
   }
 }
@@ -0,0 +1,91 @@
+use crate::rom::Timing;
+
+// 本体の地域ごとのCPU/PPU/APUのタイミング
+// see: https://www.nesdev.org/wiki/Cycle_reference_chart
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+  NTSC,
+  PAL,
+  // PALのテレビ向けのファミコン互換機
+  DENDY,
+}
+
+impl Region {
+  // CPUのクロック(Hz)
+  pub fn cpu_clock(&self) -> f32 {
+    match self {
+      Region::NTSC => 1_789_773.0,
+      Region::PAL => 1_662_607.0,
+      Region::DENDY => 1_773_448.0,
+    }
+  }
+
+  // 1フレームのスキャンライン数 (最後のラインがプリレンダーライン)
+  pub fn scanlines(&self) -> usize {
+    match self {
+      Region::NTSC => 262,
+      Region::PAL | Region::DENDY => 312,
+    }
+  }
+
+  // vblankに入ってNMIを出すライン
+  // Dendyはポストレンダーラインを伸ばして、vblankの長さをNTSCと揃えている
+  pub fn vblank_scanline(&self) -> usize {
+    match self {
+      Region::NTSC | Region::PAL => 241,
+      Region::DENDY => 291,
+    }
+  }
+
+  // CPUの1サイクルで進むPPUのドット数 (分子, 分母)
+  // PALは3.2ドット
+  pub fn ppu_dots_per_cpu_cycle(&self) -> (usize, usize) {
+    match self {
+      Region::NTSC | Region::DENDY => (3, 1),
+      Region::PAL => (16, 5),
+    }
+  }
+
+  // 描画中は奇数フレームのプリレンダーラインを1ドット短くする(NTSCだけ)
+  pub fn skips_odd_frame_dot(&self) -> bool {
+    *self == Region::NTSC
+  }
+
+  // 1秒あたりのフレーム数
+  pub fn frame_rate(&self) -> f64 {
+    let (dots, cycles) = self.ppu_dots_per_cpu_cycle();
+    let frame_dots = (341 * self.scanlines()) as f64;
+    self.cpu_clock() as f64 * dots as f64 / cycles as f64 / frame_dots
+  }
+}
+
+// ROMヘッダーのタイミングから選ぶ (どちらでも動くROMはNTSCで動かす)
+impl From<Timing> for Region {
+  fn from(timing: Timing) -> Self {
+    match timing {
+      Timing::NTSC | Timing::MULTI_REGION => Region::NTSC,
+      Timing::PAL => Region::PAL,
+      Timing::DENDY => Region::DENDY,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_frame_rate() {
+    assert!((Region::NTSC.frame_rate() - 60.10).abs() < 0.01);
+    assert!((Region::PAL.frame_rate() - 50.01).abs() < 0.01);
+    assert!((Region::DENDY.frame_rate() - 50.01).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_from_timing() {
+    assert_eq!(Region::from(Timing::NTSC), Region::NTSC);
+    assert_eq!(Region::from(Timing::MULTI_REGION), Region::NTSC);
+    assert_eq!(Region::from(Timing::PAL), Region::PAL);
+    assert_eq!(Region::from(Timing::DENDY), Region::DENDY);
+  }
+}
@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+
+use crate::nes::Nes;
+
+// 巻き戻し用のステートのリングバッファ
+// 最新のステートだけそのまま持ち、それより古いものは
+// 「1つ新しいステートとのXORをランレングス圧縮したもの」として持つ。
+// 古い側から捨てても残りは復元できる。
+pub struct Rewind {
+  // 何フレームごとにステートを取るか
+  interval: usize,
+  // latestとdeltasの合計がこれを超えたら古いものから捨てる
+  max_bytes: usize,
+  frames: usize,
+  latest: Option<Vec<u8>>,
+  // 後ろほど新しい
+  deltas: VecDeque<Vec<u8>>,
+  delta_bytes: usize,
+}
+
+impl Rewind {
+  pub fn new(interval: usize, max_bytes: usize) -> Self {
+    assert!(interval > 0);
+    Rewind {
+      interval: interval,
+      max_bytes: max_bytes,
+      frames: 0,
+      latest: None,
+      deltas: VecDeque::new(),
+      delta_bytes: 0,
+    }
+  }
+
+  // 毎フレーム呼ぶ
+  pub fn on_frame(&mut self, nes: &Nes) {
+    self.frames += 1;
+    if self.frames < self.interval {
+      return;
+    }
+    self.frames = 0;
+    self.push(nes.save_state());
+  }
+
+  pub fn push(&mut self, state: Vec<u8>) {
+    if let Some(latest) = self.latest.take() {
+      let delta = encode_delta(&state, &latest);
+      self.delta_bytes += delta.len();
+      self.deltas.push_back(delta);
+    }
+    self.latest = Some(state);
+
+    while self.memory_usage() > self.max_bytes {
+      match self.deltas.pop_front() {
+        Some(delta) => self.delta_bytes -= delta.len(),
+        None => break,
+      }
+    }
+  }
+
+  // 一番新しいステートを取り出す
+  pub fn pop(&mut self) -> Option<Vec<u8>> {
+    let latest = self.latest.take()?;
+    if let Some(delta) = self.deltas.pop_back() {
+      self.delta_bytes -= delta.len();
+      self.latest = Some(decode_delta(&latest, &delta));
+    }
+    self.frames = 0;
+    Some(latest)
+  }
+
+  // 1つ前のステートに戻す。戻せなければfalse
+  pub fn rewind(&mut self, nes: &mut Nes) -> bool {
+    match self.pop() {
+      // 自分で取ったステートなので読み込みには失敗しない
+      Some(state) => nes.load_state(&state).is_ok(),
+      None => false,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    match self.latest {
+      Some(_) => self.deltas.len() + 1,
+      None => 0,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.latest.is_none()
+  }
+
+  pub fn memory_usage(&self) -> usize {
+    self.latest.as_ref().map_or(0, |latest| latest.len()) + self.delta_bytes
+  }
+}
+
+// from XOR to をランレングス圧縮する
+// 形式: to.len() の後に (0の連続数, 非0の長さ, 非0のバイト列) の繰り返し
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+  let mut out = vec![];
+  write_varint(&mut out, to.len());
+  let xor = |i: usize| from.get(i).copied().unwrap_or(0) ^ to[i];
+
+  let mut i = 0;
+  while i < to.len() {
+    let start = i;
+    while i < to.len() && xor(i) == 0 {
+      i += 1;
+    }
+    let zeros = i - start;
+    let start = i;
+    while i < to.len() && xor(i) != 0 {
+      i += 1;
+    }
+    write_varint(&mut out, zeros);
+    write_varint(&mut out, i - start);
+    out.extend((start..i).map(xor));
+  }
+  out
+}
+
+fn decode_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+  let mut pos = 0;
+  let len = read_varint(delta, &mut pos);
+  let mut out = from.to_vec();
+  out.resize(len, 0);
+
+  let mut i = 0;
+  while pos < delta.len() {
+    i += read_varint(delta, &mut pos);
+    let literal = read_varint(delta, &mut pos);
+    for value in &delta[pos..pos + literal] {
+      out[i] ^= value;
+      i += 1;
+    }
+    pos += literal;
+  }
+  out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+  while value >= 0x80 {
+    out.push((value as u8 & 0x7F) | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = data[*pos];
+    *pos += 1;
+    value |= ((byte & 0x7F) as usize) << shift;
+    if byte & 0x80 == 0 {
+      return value;
+    }
+    shift += 7;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_delta_round_trip() {
+    let older = vec![1, 2, 3, 4, 5, 0, 0, 0, 9];
+    let newer = vec![1, 2, 7, 4, 5, 0, 8, 0, 9, 10, 11];
+    let delta = encode_delta(&newer, &older);
+    assert_eq!(decode_delta(&newer, &delta), older);
+    let delta = encode_delta(&older, &newer);
+    assert_eq!(decode_delta(&older, &delta), newer);
+
+    // 変化が無ければほとんど空になる
+    let state = vec![0xAA; 10000];
+    assert!(encode_delta(&state, &state).len() < 8);
+  }
+
+  #[test]
+  fn test_push_and_pop() {
+    let mut rewind = Rewind::new(1, usize::MAX);
+    for i in 0..5u8 {
+      rewind.push(vec![i; 100]);
+    }
+    assert_eq!(rewind.len(), 5);
+    for i in (0..5u8).rev() {
+      assert_eq!(rewind.pop(), Some(vec![i; 100]));
+    }
+    assert!(rewind.is_empty());
+    assert_eq!(rewind.pop(), None);
+  }
+
+  #[test]
+  fn test_memory_limit() {
+    let mut rewind = Rewind::new(1, 300);
+    for i in 0..10u8 {
+      rewind.push(vec![i; 100]);
+      assert!(rewind.memory_usage() <= 300);
+    }
+    // 古い方は捨てられているが、残っている分は新しい順に戻せる
+    let len = rewind.len();
+    assert!(len > 1 && len < 10);
+    for i in (10 - len as u8..10).rev() {
+      assert_eq!(rewind.pop(), Some(vec![i; 100]));
+    }
+  }
+}
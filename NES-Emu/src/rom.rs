@@ -1,31 +1,196 @@
-#[derive(Debug, PartialEq)]
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::mapper::{self, Cartridge};
+use crate::savestate;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
 pub enum Mirroring {
   VERTICAL,
   HORIZONTAL,
   FOUR_SCREEN,
+  SINGLE_SCREEN_LOWER,
+  SINGLE_SCREEN_UPPER,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum RomFormat {
+  // byte7~15にゴミが入っている古いダンプ
+  ARCHAIC_INES,
+  INES,
+  NES2,
+}
+
+// CPU/PPUのタイミング
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum Timing {
+  NTSC,
+  PAL,
+  MULTI_REGION,
+  DENDY,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum ConsoleType {
+  NES,
+  VS_SYSTEM,
+  PLAYCHOICE_10,
+  // NES2.0のbyte13に入っている拡張コンソールタイプ
+  EXTENDED(u8),
+}
+
+#[derive(Debug)]
+pub enum RomError {
+  BadMagic,
+  TruncatedHeader {
+    len: usize,
+  },
+  TruncatedPrg {
+    offset: usize,
+    expected: usize,
+    actual: usize,
+  },
+  TruncatedChr {
+    offset: usize,
+    expected: usize,
+    actual: usize,
+  },
+  // 0か、マッパーのバンクサイズの倍数でない
+  InvalidPrgSize {
+    size: usize,
+    bank_size: usize,
+  },
+  InvalidChrSize {
+    size: usize,
+    bank_size: usize,
+  },
+  UnsupportedMapper {
+    mapper: u16,
+    submapper: u8,
+  },
+  UnsupportedConsoleType(ConsoleType),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for RomError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RomError::BadMagic => write!(
+        f,
+        "File is not in iNES file format (bad magic at offset 0x0)"
+      ),
+      RomError::TruncatedHeader { len } => {
+        write!(f, "Header is truncated: expected 16 bytes, got {}", len)
+      }
+      RomError::TruncatedPrg {
+        offset,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "PRG ROM is truncated: expected {} bytes at offset 0x{:X}, got {}",
+        expected, offset, actual
+      ),
+      RomError::TruncatedChr {
+        offset,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "CHR ROM is truncated: expected {} bytes at offset 0x{:X}, got {}",
+        expected, offset, actual
+      ),
+      RomError::InvalidPrgSize { size, bank_size } => write!(
+        f,
+        "PRG ROM size must be a non-zero multiple of {} bytes, got {}",
+        bank_size, size
+      ),
+      RomError::InvalidChrSize { size, bank_size } => write!(
+        f,
+        "CHR ROM size must be a multiple of {} bytes, got {}",
+        bank_size, size
+      ),
+      RomError::UnsupportedMapper { mapper, submapper } => {
+        write!(
+          f,
+          "Mapper {} (submapper {}) is not supported",
+          mapper, submapper
+        )
+      }
+      RomError::UnsupportedConsoleType(console_type) => {
+        write!(f, "Console type {:?} is not supported", console_type)
+      }
+      RomError::Io(err) => write!(f, "IO error: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<std::io::Error> for RomError {
+  fn from(err: std::io::Error) -> Self {
+    RomError::Io(err)
+  }
 }
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16 * 1024;
 const CHR_ROM_PAGE_SIZE: usize = 8 * 1024;
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_RAM_PAGE_SIZE: usize = 8 * 1024;
 
 pub struct Rom {
-  pub prg_rom: Vec<u8>,
-  pub chr_rom: Vec<u8>,
-  pub mapper: u8,
+  pub format: RomFormat,
+  pub mapper: u16,
+  pub submapper: u8,
   pub screen_mirroring: Mirroring,
+  pub prg_ram_size: usize,
+  pub prg_nvram_size: usize,
+  pub chr_ram_size: usize,
+  pub chr_nvram_size: usize,
+  // PRG_RAMがバッテリーバックアップされている(.savに保存する)
+  pub battery: bool,
+  pub timing: Timing,
+  pub console_type: ConsoleType,
+  pub expansion_device: u8,
+  // ステートが別のROMのものでないか確かめるためのハッシュ
+  pub hash: u64,
+  // PRG_ROM, CHR_ROMはマッパーが持つ
+  pub cartridge: Cartridge,
 }
 
 impl Rom {
-  pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-    if &raw[0..4] != NES_TAG {
-      return Err("File is not in iNES file format".to_string());
+  // see: https://www.nesdev.org/wiki/NES_2.0
+  pub fn new(raw: &Vec<u8>) -> Result<Rom, RomError> {
+    if raw.len() < NES_TAG.len() || raw[0..4] != NES_TAG {
+      return Err(RomError::BadMagic);
     }
-    let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-    let ines_ver = (raw[7] >> 2) & 0b11;
-    if ines_ver != 0 {
-      return Err("NES2.0 format is not supported".to_string());
+    if raw.len() < HEADER_SIZE {
+      return Err(RomError::TruncatedHeader { len: raw.len() });
     }
+    let format = match (raw[7] >> 2) & 0b11 {
+      0b10 => RomFormat::NES2,
+      0b00 if raw[12..16].iter().all(|v| *v == 0) => RomFormat::INES,
+      _ => RomFormat::ARCHAIC_INES,
+    };
+    let nes2 = format == RomFormat::NES2;
+
+    let mapper = match format {
+      RomFormat::NES2 => {
+        ((raw[8] as u16 & 0b1111) << 8) | (raw[7] & 0b1111_0000) as u16 | (raw[6] >> 4) as u16
+      }
+      RomFormat::INES => ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16,
+      // 上位ニブルは信用できない
+      RomFormat::ARCHAIC_INES => (raw[6] >> 4) as u16,
+    };
+    let submapper = if nes2 { raw[8] >> 4 } else { 0 };
+
     let four_screen = raw[6] & 0b1000 != 0;
     let vertical_mirroring = raw[6] & 0b1 != 0;
     let screen_mirroring = match (four_screen, vertical_mirroring) {
@@ -33,36 +198,407 @@ impl Rom {
       (false, true) => Mirroring::VERTICAL,
       (false, false) => Mirroring::HORIZONTAL,
     };
-    let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-    let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
 
+    let (prg_rom_size, chr_rom_size) = if nes2 {
+      (
+        nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE),
+        nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE),
+      )
+    } else {
+      (
+        raw[4] as usize * PRG_ROM_PAGE_SIZE,
+        raw[5] as usize * CHR_ROM_PAGE_SIZE,
+      )
+    };
+
+    let (prg_ram_size, prg_nvram_size, chr_ram_size, chr_nvram_size) = if nes2 {
+      (
+        nes2_ram_size(raw[10] & 0b1111),
+        nes2_ram_size(raw[10] >> 4),
+        nes2_ram_size(raw[11] & 0b1111),
+        nes2_ram_size(raw[11] >> 4),
+      )
+    } else {
+      // iNESではbyte8が8KB単位のPRG_RAMサイズ(0でも8KBとみなす)
+      let prg_ram_size = if format == RomFormat::INES && raw[8] != 0 {
+        raw[8] as usize * PRG_RAM_PAGE_SIZE
+      } else {
+        PRG_RAM_PAGE_SIZE
+      };
+      // char_rom_size=0の場合、8KBのCHR_RAMが存在する
+      let chr_ram_size = if chr_rom_size == 0 {
+        CHR_ROM_PAGE_SIZE
+      } else {
+        0
+      };
+      (prg_ram_size, 0, chr_ram_size, 0)
+    };
+
+    let timing = if nes2 {
+      match raw[12] & 0b11 {
+        0 => Timing::NTSC,
+        1 => Timing::PAL,
+        2 => Timing::MULTI_REGION,
+        _ => Timing::DENDY,
+      }
+    } else if format == RomFormat::INES && raw[9] & 1 != 0 {
+      Timing::PAL
+    } else {
+      Timing::NTSC
+    };
+
+    let console_type = match (format, raw[7] & 0b11) {
+      (RomFormat::ARCHAIC_INES, _) | (_, 0) => ConsoleType::NES,
+      (_, 1) => ConsoleType::VS_SYSTEM,
+      (_, 2) => ConsoleType::PLAYCHOICE_10,
+      (_, _) => ConsoleType::EXTENDED(if nes2 { raw[13] & 0b1111 } else { 0 }),
+    };
+    match console_type {
+      // PlayChoice-10のROMはNESと同じように動く
+      ConsoleType::NES | ConsoleType::PLAYCHOICE_10 => {}
+      _ => return Err(RomError::UnsupportedConsoleType(console_type)),
+    }
+    let expansion_device = if nes2 { raw[15] & 0b11_1111 } else { 0 };
+
+    let battery = raw[6] & 0b10 != 0;
     let skip_trainer = raw[6] & 0b100 != 0;
 
-    let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-    let chr_rom_start = prg_rom_start + prg_rom_size;
+    let prg_rom_start = HEADER_SIZE + if skip_trainer { TRAINER_SIZE } else { 0 };
+    let chr_rom_start = prg_rom_start.saturating_add(prg_rom_size);
+    if raw.len() < chr_rom_start {
+      return Err(RomError::TruncatedPrg {
+        offset: prg_rom_start,
+        expected: prg_rom_size,
+        actual: raw.len().saturating_sub(prg_rom_start),
+      });
+    }
+    if raw.len() - chr_rom_start < chr_rom_size {
+      return Err(RomError::TruncatedChr {
+        offset: chr_rom_start,
+        expected: chr_rom_size,
+        actual: raw.len() - chr_rom_start,
+      });
+    }
 
-    let chr_rom = if chr_rom_size == 0 {
-      // char_rom_size=0の場合、8KBのCHR_RAMが存在する
-      let bank_chr_ram: Vec<u8> = vec![0; CHR_ROM_PAGE_SIZE];
-      bank_chr_ram
+    // 半端なサイズだとマッパーがバンクを計算できない
+    if let Some((prg_bank_size, chr_bank_size)) = mapper::bank_sizes(mapper) {
+      if prg_rom_size == 0 || prg_rom_size % prg_bank_size != 0 {
+        return Err(RomError::InvalidPrgSize {
+          size: prg_rom_size,
+          bank_size: prg_bank_size,
+        });
+      }
+      if chr_rom_size % chr_bank_size != 0 {
+        return Err(RomError::InvalidChrSize {
+          size: chr_rom_size,
+          bank_size: chr_bank_size,
+        });
+      }
+    }
+
+    let chr_ram = chr_rom_size == 0;
+    let chr_rom = if chr_ram {
+      // CHR_RAMが無いことになっていても8KBは用意しておく
+      vec![0; (chr_ram_size + chr_nvram_size).max(CHR_ROM_PAGE_SIZE)]
     } else {
       raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec()
     };
+    let prg_rom = raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec();
+
+    let cartridge = mapper::new_mapper(
+      mapper,
+      submapper,
+      prg_rom,
+      chr_rom,
+      chr_ram,
+      screen_mirroring,
+      prg_ram_size + prg_nvram_size,
+    )?;
 
     Ok(Rom {
-      prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-      chr_rom: chr_rom,
+      format: format,
       mapper: mapper,
+      submapper: submapper,
       screen_mirroring: screen_mirroring,
+      prg_ram_size: prg_ram_size,
+      prg_nvram_size: prg_nvram_size,
+      chr_ram_size: chr_ram_size,
+      chr_nvram_size: chr_nvram_size,
+      battery: battery,
+      timing: timing,
+      console_type: console_type,
+      expansion_device: expansion_device,
+      hash: savestate::hash(raw),
+      cartridge: Rc::new(RefCell::new(cartridge)),
     })
   }
 
   pub fn empty() -> Self {
     return Rom {
-      prg_rom: vec![],
-      chr_rom: vec![],
+      format: RomFormat::INES,
       mapper: 0,
+      submapper: 0,
       screen_mirroring: Mirroring::VERTICAL,
+      prg_ram_size: 0,
+      prg_nvram_size: 0,
+      chr_ram_size: CHR_ROM_PAGE_SIZE,
+      chr_nvram_size: 0,
+      battery: false,
+      timing: Timing::NTSC,
+      console_type: ConsoleType::NES,
+      expansion_device: 0,
+      hash: 0,
+      cartridge: Rc::new(RefCell::new(
+        mapper::new_mapper(
+          0,
+          0,
+          vec![],
+          vec![0; CHR_ROM_PAGE_SIZE],
+          true,
+          Mirroring::VERTICAL,
+          0,
+        )
+        .unwrap(),
+      )),
     };
   }
 }
+
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+  if msb == 0b1111 {
+    // 指数-乗数表記: 2^E * (MM * 2 + 1)
+    let exponent = (lsb >> 2) as u32;
+    let multiplier = (lsb & 0b11) as usize * 2 + 1;
+    1usize
+      .checked_shl(exponent)
+      .unwrap_or(usize::MAX)
+      .saturating_mul(multiplier)
+  } else {
+    ((msb as usize) << 8 | lsb as usize) * page_size
+  }
+}
+
+fn nes2_ram_size(shift: u8) -> usize {
+  // 0ならRAM無し、それ以外は 64 << shift バイト
+  if shift == 0 {
+    0
+  } else {
+    64 << shift
+  }
+}
+
+// テストやベンチマーク用の、16KBのPRG_ROMと8KBのCHR_RAMだけのROM
+// prgを$8000(と$C000)から置いて、リセットベクタをresetにする
+// flags6はヘッダーのbyte6 (ミラーリング、バッテリー、マッパーの下位4ビット)
+// ベンチマークからも使うので#[cfg(test)]にはしていない
+pub fn nrom(prg: &[u8], reset: u16, flags6: u8) -> Rom {
+  let mut raw = NES_TAG.to_vec();
+  raw.extend_from_slice(&[0x01, 0x00, flags6, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0]);
+  let mut prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+  prg_rom[..prg.len()].copy_from_slice(prg);
+  prg_rom[0x3FFC] = reset as u8;
+  prg_rom[0x3FFD] = (reset >> 8) as u8;
+  raw.extend(prg_rom);
+  Rom::new(&raw).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn raw_rom(header: [u8; 16], prg_rom_size: usize, chr_rom_size: usize) -> Vec<u8> {
+    let mut raw = header.to_vec();
+    raw.resize(16 + prg_rom_size + chr_rom_size, 0);
+    raw
+  }
+
+  #[test]
+  fn test_ines() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x11, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+    ];
+    let rom = Rom::new(&raw_rom(header, 0x8000, 0x2000)).unwrap();
+    assert_eq!(rom.format, RomFormat::INES);
+    assert_eq!(rom.mapper, 1);
+    assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+    assert_eq!(rom.prg_ram_size, 0x2000);
+    assert_eq!(rom.chr_ram_size, 0);
+    assert!(!rom.battery);
+    assert_eq!(rom.timing, Timing::NTSC);
+  }
+
+  #[test]
+  fn test_archaic_ines_ignores_upper_mapper_nibble() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x44, 0x69, 0x73, 0x6B, 0x44, 0x75, 0x64, 0x65,
+      0x21,
+    ];
+    let rom = Rom::new(&raw_rom(header, 0x4000, 0x2000)).unwrap();
+    assert_eq!(rom.format, RomFormat::ARCHAIC_INES);
+    assert_eq!(rom.mapper, 0);
+  }
+
+  #[test]
+  fn test_nes2() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x08, 0x00, 0x42, 0x08, 0x10, 0x00, 0x70, 0x07, 0x01, 0x00, 0x00,
+      0x01,
+    ];
+    let rom = Rom::new(&raw_rom(header, 8 * 0x4000, 0)).unwrap();
+    assert_eq!(rom.format, RomFormat::NES2);
+    assert_eq!(rom.mapper, 4);
+    assert_eq!(rom.submapper, 1);
+    assert_eq!(rom.prg_ram_size, 0);
+    assert_eq!(rom.prg_nvram_size, 0x2000);
+    assert!(rom.battery);
+    assert_eq!(rom.chr_ram_size, 0x2000);
+    assert_eq!(rom.timing, Timing::PAL);
+    assert_eq!(rom.console_type, ConsoleType::NES);
+    assert_eq!(rom.expansion_device, 1);
+  }
+
+  #[test]
+  fn test_bad_magic() {
+    let err = Rom::new(&vec![0x4E, 0x45, 0x53]).err().unwrap();
+    assert!(matches!(err, RomError::BadMagic));
+  }
+
+  #[test]
+  fn test_truncated_prg() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+    ];
+    let err = Rom::new(&raw_rom(header, 0x4000, 0)).err().unwrap();
+    assert!(matches!(
+      err,
+      RomError::TruncatedPrg {
+        offset: 0x10,
+        expected: 0x8000,
+        actual: 0x4000
+      }
+    ));
+  }
+
+  #[test]
+  fn test_truncated_chr() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x04, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+    ];
+    // トレーナー付き
+    let err = Rom::new(&raw_rom(header, 0x200 + 0x4000, 0x100))
+      .err()
+      .unwrap();
+    assert_eq!(
+      err.to_string(),
+      "CHR ROM is truncated: expected 8192 bytes at offset 0x4210, got 256"
+    );
+  }
+
+  #[test]
+  fn test_empty_prg() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+    ];
+    let err = Rom::new(&raw_rom(header, 0, 0x2000)).err().unwrap();
+    assert!(matches!(
+      err,
+      RomError::InvalidPrgSize {
+        size: 0,
+        bank_size: 0x4000
+      }
+    ));
+  }
+
+  #[test]
+  fn test_partial_bank() {
+    // NES2.0の指数表記で96バイトのPRG_ROM
+    let header = [
+      0x4E,
+      0x45,
+      0x53,
+      0x1A,
+      0b0001_0101,
+      0x01,
+      0x00,
+      0x08,
+      0x00,
+      0x0F,
+      0,
+      0,
+      0,
+      0,
+      0,
+      0,
+    ];
+    let err = Rom::new(&raw_rom(header, 96, 0x2000)).err().unwrap();
+    assert_eq!(
+      err.to_string(),
+      "PRG ROM size must be a non-zero multiple of 16384 bytes, got 96"
+    );
+
+    // MMC1のCHRは4KB単位 (96バイト)
+    let header = [
+      0x4E,
+      0x45,
+      0x53,
+      0x1A,
+      0x01,
+      0b0001_0101,
+      0x10,
+      0x08,
+      0x00,
+      0xF0,
+      0,
+      0,
+      0,
+      0,
+      0,
+      0,
+    ];
+    let err = Rom::new(&raw_rom(header, 0x4000, 96)).err().unwrap();
+    assert!(matches!(
+      err,
+      RomError::InvalidChrSize {
+        size: 96,
+        bank_size: 0x1000
+      }
+    ));
+  }
+
+  #[test]
+  fn test_unsupported_mapper() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x50, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+    ];
+    let err = Rom::new(&raw_rom(header, 0x4000, 0x2000)).err().unwrap();
+    assert!(matches!(
+      err,
+      RomError::UnsupportedMapper {
+        mapper: 5,
+        submapper: 0
+      }
+    ));
+  }
+
+  #[test]
+  fn test_unsupported_console_type() {
+    let header = [
+      0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+    ];
+    let err = Rom::new(&raw_rom(header, 0x4000, 0x2000)).err().unwrap();
+    assert!(matches!(
+      err,
+      RomError::UnsupportedConsoleType(ConsoleType::VS_SYSTEM)
+    ));
+  }
+
+  #[test]
+  fn test_nes2_exponent_multiplier_size() {
+    assert_eq!(
+      nes2_rom_size(0x02, 0x01, PRG_ROM_PAGE_SIZE),
+      0x102 * PRG_ROM_PAGE_SIZE
+    );
+    // 2^5 * (1 * 2 + 1) = 96
+    assert_eq!(nes2_rom_size(0b0001_0101, 0b1111, PRG_ROM_PAGE_SIZE), 96);
+  }
+}
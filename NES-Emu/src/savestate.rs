@@ -0,0 +1,247 @@
+use std::fmt;
+
+// ステートファイルのフォーマット
+// "NESS" | version(u32) | ROMのハッシュ(u64) | 本体
+// 数値は全てリトルエンディアン
+pub const MAGIC: [u8; 4] = *b"NESS";
+// 本体の中身を変えたら上げること
+pub const VERSION: u32 = 8;
+
+#[derive(Debug)]
+pub enum StateError {
+  BadMagic,
+  UnsupportedVersion(u32),
+  RomMismatch { expected: u64, actual: u64 },
+  Truncated,
+  Invalid(String),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for StateError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StateError::BadMagic => write!(f, "not a save state file"),
+      StateError::UnsupportedVersion(version) => write!(
+        f,
+        "unsupported save state version {} (expected {})",
+        version, VERSION
+      ),
+      StateError::RomMismatch { expected, actual } => write!(
+        f,
+        "save state is for a different ROM (hash {:016X}, loaded ROM is {:016X})",
+        actual, expected
+      ),
+      StateError::Truncated => write!(f, "save state is truncated"),
+      StateError::Invalid(reason) => write!(f, "invalid save state: {}", reason),
+      StateError::Io(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<std::io::Error> for StateError {
+  fn from(err: std::io::Error) -> Self {
+    StateError::Io(err)
+  }
+}
+
+pub struct StateWriter {
+  data: Vec<u8>,
+}
+
+impl StateWriter {
+  pub fn new() -> Self {
+    StateWriter { data: vec![] }
+  }
+
+  pub fn write_u8(&mut self, value: u8) {
+    self.data.push(value);
+  }
+
+  pub fn write_bool(&mut self, value: bool) {
+    self.write_u8(value as u8);
+  }
+
+  pub fn write_u16(&mut self, value: u16) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn write_u32(&mut self, value: u32) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn write_u64(&mut self, value: u64) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn write_usize(&mut self, value: usize) {
+    self.write_u64(value as u64);
+  }
+
+  // 長さ付きで書き込む
+  pub fn write_bytes(&mut self, value: &[u8]) {
+    self.write_u32(value.len() as u32);
+    self.data.extend_from_slice(value);
+  }
+
+  pub fn into_inner(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+pub struct StateReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    StateReader { data: data, pos: 0 }
+  }
+
+  fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+    if self.data.len() - self.pos < len {
+      return Err(StateError::Truncated);
+    }
+    let value = &self.data[self.pos..self.pos + len];
+    self.pos += len;
+    Ok(value)
+  }
+
+  pub fn read_u8(&mut self) -> Result<u8, StateError> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, StateError> {
+    Ok(self.read_u8()? != 0)
+  }
+
+  pub fn read_u16(&mut self) -> Result<u16, StateError> {
+    Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+  }
+
+  pub fn read_u32(&mut self) -> Result<u32, StateError> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+  }
+
+  pub fn read_u64(&mut self) -> Result<u64, StateError> {
+    Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+  }
+
+  pub fn read_usize(&mut self) -> Result<usize, StateError> {
+    Ok(self.read_u64()? as usize)
+  }
+
+  pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+    let len = self.read_u32()? as usize;
+    self.take(len)
+  }
+
+  // 固定長のバッファに読み込む (長さが違えばエラー)
+  pub fn read_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+    let value = self.read_bytes()?;
+    if value.len() != out.len() {
+      return Err(StateError::Invalid(format!(
+        "expected {} bytes, got {}",
+        out.len(),
+        value.len()
+      )));
+    }
+    out.copy_from_slice(value);
+    Ok(())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pos == self.data.len()
+  }
+}
+
+// ヘッダーを書いて本体用のWriterを返す
+pub fn write_header(rom_hash: u64) -> StateWriter {
+  let mut writer = StateWriter::new();
+  writer.data.extend_from_slice(&MAGIC);
+  writer.write_u32(VERSION);
+  writer.write_u64(rom_hash);
+  writer
+}
+
+// ヘッダーを検証して本体用のReaderを返す
+pub fn read_header(data: &[u8], rom_hash: u64) -> Result<StateReader<'_>, StateError> {
+  let mut reader = StateReader::new(data);
+  if reader.take(MAGIC.len()).map_err(|_| StateError::BadMagic)? != MAGIC {
+    return Err(StateError::BadMagic);
+  }
+  let version = reader.read_u32()?;
+  if version != VERSION {
+    return Err(StateError::UnsupportedVersion(version));
+  }
+  let hash = reader.read_u64()?;
+  if hash != rom_hash {
+    return Err(StateError::RomMismatch {
+      expected: rom_hash,
+      actual: hash,
+    });
+  }
+  Ok(reader)
+}
+
+// FNV-1a (ファイルに残すのでstdのHasherは使わない)
+pub fn hash(data: &[u8]) -> u64 {
+  let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+  for byte in data {
+    hash ^= *byte as u64;
+    hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+  }
+  hash
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+    let mut writer = write_header(0x1234);
+    writer.write_u8(0xAB);
+    writer.write_bool(true);
+    writer.write_u16(0xBEEF);
+    writer.write_usize(123456);
+    writer.write_bytes(&[1, 2, 3]);
+    let data = writer.into_inner();
+
+    let mut reader = read_header(&data, 0x1234).unwrap();
+    assert_eq!(reader.read_u8().unwrap(), 0xAB);
+    assert!(reader.read_bool().unwrap());
+    assert_eq!(reader.read_u16().unwrap(), 0xBEEF);
+    assert_eq!(reader.read_usize().unwrap(), 123456);
+    let mut bytes = [0; 3];
+    reader.read_into(&mut bytes).unwrap();
+    assert_eq!(bytes, [1, 2, 3]);
+    assert!(reader.is_empty());
+    assert!(matches!(reader.read_u8(), Err(StateError::Truncated)));
+  }
+
+  #[test]
+  fn test_header_errors() {
+    let data = write_header(1).into_inner();
+    assert!(matches!(
+      read_header(&data, 2),
+      Err(StateError::RomMismatch {
+        expected: 2,
+        actual: 1
+      })
+    ));
+    assert!(matches!(
+      read_header(b"NES\x1A", 1),
+      Err(StateError::BadMagic)
+    ));
+
+    let mut data = data;
+    data[4] = 0xFF;
+    assert!(matches!(
+      read_header(&data, 1),
+      Err(StateError::UnsupportedVersion(_))
+    ));
+  }
+}
@@ -0,0 +1,199 @@
+// blarggのテストROMなど、結果を$6000に書き込むテストROMをヘッドレスで実行する
+// https://github.com/christopherpow/nes-test-roms/blob/master/instr_test-v5/readme.txt
+//
+// $6000: 状態 ($80: 実行中, $81: リセットが必要, $00: 成功, それ以外: 失敗)
+// $6001-$6003: DE B0 61 (書き込まれるまでは$6000の値は信用できない)
+// $6004-: 結果のメッセージ(ASCIIZ)
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::bus::Mem;
+use crate::cartridge::load_rom;
+use crate::nes::Nes;
+use crate::rom::{Rom, RomError};
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const MESSAGE: u16 = 0x6004;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+// メッセージが壊れていても読み続けないように
+const MESSAGE_MAX_LEN: u16 = 0x1000;
+// リセットを要求されてから押すまでに100ms以上待つ
+const RESET_DELAY_FRAMES: usize = 10;
+
+// 60fpsで1分(エミュレーション上の時間)
+pub const DEFAULT_TIMEOUT_FRAMES: usize = 60 * 60;
+
+#[derive(Debug)]
+pub enum TestRomError {
+  Rom(RomError),
+  // 0以外の結果コード
+  Failed { code: u8, message: String },
+  // 終わらなかった(途中までのメッセージ)
+  Timeout { frames: usize, message: String },
+}
+
+impl fmt::Display for TestRomError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TestRomError::Rom(err) => write!(f, "{}", err),
+      TestRomError::Failed { code, message } => {
+        write!(f, "failed with code {}: {}", code, message.trim_end())
+      }
+      TestRomError::Timeout { frames, message } => write!(
+        f,
+        "did not finish within {} frames: {}",
+        frames,
+        message.trim_end()
+      ),
+    }
+  }
+}
+
+impl std::error::Error for TestRomError {}
+
+impl From<RomError> for TestRomError {
+  fn from(err: RomError) -> Self {
+    TestRomError::Rom(err)
+  }
+}
+
+// 成功したら結果のメッセージを返す
+pub fn run_test_rom(rom: Rom, timeout_frames: usize) -> Result<String, TestRomError> {
+  let mut nes = Nes::new(rom);
+  let mut reset_frame = None;
+
+  for frame in 0..timeout_frames {
+    nes.step_frame();
+    if !has_signature(&mut nes) {
+      continue;
+    }
+    match nes.cpu().mem_peek(STATUS) {
+      STATUS_RUNNING => {}
+      STATUS_RESET => {
+        let requested = *reset_frame.get_or_insert(frame);
+        if frame - requested >= RESET_DELAY_FRAMES {
+          nes.reset();
+          reset_frame = None;
+        }
+      }
+      0 => return Ok(read_message(&mut nes)),
+      code => {
+        return Err(TestRomError::Failed {
+          code: code,
+          message: read_message(&mut nes),
+        })
+      }
+    }
+  }
+
+  Err(TestRomError::Timeout {
+    frames: timeout_frames,
+    message: read_message(&mut nes),
+  })
+}
+
+pub fn run_test_rom_file(path: &Path, timeout_frames: usize) -> Result<String, TestRomError> {
+  let rom = load_rom(&path.to_string_lossy())?;
+  run_test_rom(rom, timeout_frames)
+}
+
+// ディレクトリ以下の.nesを再帰的に集める(順番を固定するためにソートする)
+pub fn find_test_roms(dir: &Path) -> Vec<PathBuf> {
+  let mut roms = vec![];
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return roms,
+  };
+  for entry in entries.flatten() {
+    let path = entry.path();
+    if path.is_dir() {
+      roms.extend(find_test_roms(&path));
+    } else if path
+      .extension()
+      .map_or(false, |ext| ext.eq_ignore_ascii_case("nes"))
+    {
+      roms.push(path);
+    }
+  }
+  roms.sort();
+  roms
+}
+
+fn has_signature(nes: &mut Nes) -> bool {
+  let cpu = nes.cpu();
+  cpu.mem_peek(SIGNATURE) == 0xDE
+    && cpu.mem_peek(SIGNATURE + 1) == 0xB0
+    && cpu.mem_peek(SIGNATURE + 2) == 0x61
+}
+
+fn read_message(nes: &mut Nes) -> String {
+  let cpu = nes.cpu();
+  let mut bytes = vec![];
+  for addr in MESSAGE..MESSAGE + MESSAGE_MAX_LEN {
+    let value = cpu.mem_peek(addr);
+    if value == 0 {
+      break;
+    }
+    bytes.push(value);
+  }
+  String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::rom;
+
+  // $6000-に結果を書き込んで止まるだけのROM
+  fn result_rom(status: u8, message: &str) -> Rom {
+    let mut program = vec![];
+    let mut store = |addr: u16, value: u8| {
+      // LDA #value / STA addr
+      program.extend_from_slice(&[0xA9, value, 0x8D, addr as u8, (addr >> 8) as u8]);
+    };
+    store(STATUS, STATUS_RUNNING);
+    store(SIGNATURE, 0xDE);
+    store(SIGNATURE + 1, 0xB0);
+    store(SIGNATURE + 2, 0x61);
+    for (i, value) in message.bytes().chain(std::iter::once(0)).enumerate() {
+      store(MESSAGE + i as u16, value);
+    }
+    store(STATUS, status);
+    // JMP *
+    let pc = 0x8000 + program.len() as u16;
+    program.extend_from_slice(&[0x4C, pc as u8, (pc >> 8) as u8]);
+
+    rom::nrom(&program, 0x8000, 0)
+  }
+
+  #[test]
+  fn test_passed() {
+    let result = run_test_rom(result_rom(0, "Passed\n"), 10);
+    assert_eq!(result.unwrap(), "Passed\n");
+  }
+
+  #[test]
+  fn test_failed() {
+    match run_test_rom(result_rom(3, "BIT failed\n"), 10) {
+      Err(TestRomError::Failed { code, message }) => {
+        assert_eq!(code, 3);
+        assert_eq!(message, "BIT failed\n");
+      }
+      result => panic!("unexpected result {:?}", result),
+    }
+  }
+
+  #[test]
+  fn test_timeout() {
+    match run_test_rom(result_rom(STATUS_RUNNING, "running"), 10) {
+      Err(TestRomError::Timeout { frames, message }) => {
+        assert_eq!(frames, 10);
+        assert_eq!(message, "running");
+      }
+      result => panic!("unexpected result {:?}", result),
+    }
+  }
+}
@@ -0,0 +1,39 @@
+// tests/roms以下(またはNES_TEST_ROMSで指定したディレクトリ)のテストROMを全て実行する
+// instr_test-v5, ppu_vbl_nmi, apu_testなど$6000に結果を書き込むROMをそのまま置けばいい
+// ROMはリポジトリに含めていないので、置いてから cargo test -- --ignored で実行する
+use std::path::PathBuf;
+
+use nes_emu::testrom::{find_test_roms, run_test_rom_file, DEFAULT_TIMEOUT_FRAMES};
+
+fn test_rom_dir() -> PathBuf {
+  match std::env::var_os("NES_TEST_ROMS") {
+    Some(dir) => PathBuf::from(dir),
+    None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"),
+  }
+}
+
+#[test]
+#[ignore = "requires test ROMs in tests/roms or NES_TEST_ROMS"]
+fn test_roms() {
+  let dir = test_rom_dir();
+  let roms = find_test_roms(&dir);
+  assert!(!roms.is_empty(), "no test ROMs in {}", dir.display());
+
+  let mut failures = vec![];
+  for path in &roms {
+    match run_test_rom_file(path, DEFAULT_TIMEOUT_FRAMES) {
+      Ok(_) => eprintln!("ok   {}", path.display()),
+      Err(err) => {
+        eprintln!("FAIL {}", path.display());
+        failures.push(format!("{}: {}", path.display(), err));
+      }
+    }
+  }
+  assert!(
+    failures.is_empty(),
+    "{} of {} test ROMs failed\n{}",
+    failures.len(),
+    roms.len(),
+    failures.join("\n")
+  );
+}